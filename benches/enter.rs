@@ -0,0 +1,45 @@
+//! Time entering and leaving a directory many times with each constructor.
+//! Run with `cargo bench`.
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use with_dir::WithDir;
+
+const ITERATIONS: u32 = 10_000;
+
+fn bench(name: &str, mut f: impl FnMut()) {
+    // warm up
+    for _ in 0..ITERATIONS / 10 {
+        f();
+    }
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let per_iter: Duration = start.elapsed() / ITERATIONS;
+    println!("{:<24} {:>10.2?} per iteration", name, per_iter);
+}
+
+fn main() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path().join("dir");
+    std::fs::create_dir(&dir).unwrap();
+    let _origin = WithDir::new(tmp.path()).unwrap();
+    let origin = std::env::current_dir().unwrap();
+
+    bench("new", || {
+        black_box(WithDir::new(&dir).unwrap());
+    });
+    bench("new_with_origin", || {
+        black_box(WithDir::new_with_origin(&dir, &origin).unwrap());
+    });
+    bench("new_unchecked", || {
+        // SAFETY: this is the only thread
+        black_box(unsafe { WithDir::new_unchecked(&dir) }.unwrap());
+    });
+    bench("new (same directory)", || {
+        black_box(WithDir::new(".").unwrap());
+    });
+}
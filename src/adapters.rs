@@ -0,0 +1,124 @@
+//! Adapters that root other crates' path based APIs at a
+//! [WithDir](crate::WithDir), so they resolve paths against the scope
+//! without relying on the process wide current directory. Each adapter
+//! is behind a feature of the same name as the crate.
+#[cfg(any(
+    feature = "camino",
+    feature = "cap-std",
+    feature = "config",
+    feature = "walkdir"
+))]
+use crate::WithDir;
+
+#[cfg(feature = "camino")]
+impl WithDir<'_> {
+    /// [path](crate::WithDir::path) as a [camino::Utf8Path], or an
+    /// [InvalidData](std::io::ErrorKind::InvalidData) error naming the path
+    /// if it isn't UTF-8. Constructors already accept `Utf8Path` since it
+    /// implements `AsRef<Path>`. Requires the `camino` feature.
+    pub fn utf8_path(&self) -> Result<&camino::Utf8Path, std::io::Error> {
+        camino::Utf8Path::from_path(self.path()).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} is not valid UTF-8", self.path().display()),
+            )
+        })
+    }
+}
+
+#[cfg(feature = "cap-std")]
+impl WithDir<'_> {
+    /// Open this directory as a [cap_std::fs::Dir]. IO through it is
+    /// confined to the directory, and keeps working on the same directory
+    /// whatever happens to the current directory later. Requires the
+    /// `cap-std` feature.
+    pub fn open_dir(&self) -> Result<cap_std::fs::Dir, std::io::Error> {
+        cap_std::fs::Dir::open_ambient_dir(self.absolute_path(), cap_std::ambient_authority())
+    }
+}
+
+#[cfg(feature = "walkdir")]
+impl WithDir<'_> {
+    /// A [walkdir::WalkDir] rooted at this directory. Requires the
+    /// `walkdir` feature.
+    pub fn walkdir(&self) -> walkdir::WalkDir {
+        walkdir::WalkDir::new(self.absolute_path())
+    }
+}
+
+#[cfg(feature = "config")]
+impl WithDir<'_> {
+    /// A [config::File] source for `rel` inside this directory. The format
+    /// is picked from the file extension as with [config::File::from].
+    /// Requires the `config` feature.
+    pub fn config_file(
+        &self,
+        rel: impl AsRef<std::path::Path>,
+    ) -> config::File<config::FileSourceFile, config::FileFormat> {
+        config::File::from(self.absolute_path().join(rel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(any(
+        feature = "camino",
+        feature = "cap-std",
+        feature = "config",
+        feature = "walkdir"
+    ))]
+    use crate::WithDir;
+
+    #[cfg(feature = "camino")]
+    #[test]
+    fn test_utf8_path() {
+        let tmp = WithDir::temp().unwrap();
+        std::fs::create_dir("sub").unwrap();
+        let wd = WithDir::new(camino::Utf8Path::new("sub")).unwrap();
+        assert_eq!(wd.utf8_path().unwrap(), tmp.path().join("sub"));
+        drop(wd);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let name = std::ffi::OsStr::from_bytes(b"\xff");
+            let wd = WithDir::create(name).unwrap();
+            assert!(wd.utf8_path().is_err());
+        }
+    }
+
+    #[cfg(feature = "cap-std")]
+    #[test]
+    fn test_open_dir() {
+        let wd = WithDir::temp().unwrap();
+        let dir = wd.open_dir().unwrap();
+        let _other = WithDir::new(std::env::temp_dir()).unwrap();
+        dir.write("a", "x").unwrap();
+        assert!(dir.open("../escape").is_err());
+        assert_eq!(std::fs::read_to_string(wd.path().join("a")).unwrap(), "x");
+    }
+
+    #[cfg(feature = "walkdir")]
+    #[test]
+    fn test_walkdir() {
+        let wd = WithDir::temp().unwrap();
+        std::fs::write("a", "").unwrap();
+        let entries: Vec<_> = {
+            // leave the directory, walkdir should still find it
+            let _other = WithDir::new(std::env::temp_dir()).unwrap();
+            wd.walkdir().min_depth(1).into_iter().collect()
+        };
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].as_ref().unwrap().file_name(), "a");
+    }
+
+    #[cfg(feature = "config")]
+    #[test]
+    fn test_config_file() {
+        let wd = WithDir::temp().unwrap();
+        let _other = WithDir::new(std::env::temp_dir()).unwrap();
+        let source = wd.config_file("missing").required(false);
+        let config = config::Config::builder().add_source(source).build();
+        assert!(config.is_ok());
+    }
+}
@@ -0,0 +1,298 @@
+//! Constructors for entering well known directories, such as
+//! [WithDir::home](crate::WithDir::home).
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::{current_dir, lock_to_enter, WithDir};
+
+/// Where to start walking up from: the package directory under cargo,
+/// otherwise the current directory. Call with the lock held.
+fn start_dir() -> Result<PathBuf, std::io::Error> {
+    match std::env::var_os("CARGO_MANIFEST_DIR") {
+        Some(dir) => Ok(dir.into()),
+        None => current_dir(),
+    }
+}
+
+/// The nearest of `start` and its ancestors for which `found` is true
+fn find_up(start: &Path, found: impl Fn(&Path) -> bool) -> Option<PathBuf> {
+    start.ancestors().find(|dir| found(dir)).map(Path::to_owned)
+}
+
+/// Whether `dir` has a `Cargo.toml` with a `[workspace]` table
+fn is_workspace_root(dir: &Path) -> bool {
+    let Ok(manifest) = std::fs::read_to_string(dir.join("Cargo.toml")) else {
+        return false;
+    };
+    manifest.lines().any(|line| {
+        let line = line.trim();
+        line == "[workspace]" || line.starts_with("[workspace.")
+    })
+}
+
+impl<'a> WithDir<'a> {
+    /// Enter the current user's home directory, as found by
+    /// [std::env::home_dir]. Fails with [NotFound](ErrorKind::NotFound) if
+    /// it can't be determined.
+    ///
+    /// ```no_run
+    /// use with_dir::WithDir;
+    ///
+    /// let home = WithDir::home().unwrap();
+    /// let has_bashrc = std::path::Path::new(".bashrc").exists();
+    /// ```
+    pub fn home() -> Result<WithDir<'a>, std::io::Error> {
+        let home = std::env::home_dir()
+            .filter(|h| !h.as_os_str().is_empty())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::NotFound,
+                    "the home directory can't be determined",
+                )
+            })?;
+        WithDir::new(home)
+    }
+
+    /// Enter the directory of the package being built or tested, read from
+    /// `CARGO_MANIFEST_DIR` at run time. Cargo sets it for `cargo test` and
+    /// `cargo run`, whichever directory they're started from, so it makes a
+    /// stable anchor for fixture paths. Fails with
+    /// [NotFound](ErrorKind::NotFound) when it isn't set, for instance when
+    /// a binary is run directly. Use [manifest_dir!](crate::manifest_dir)
+    /// to capture it at compile time instead.
+    ///
+    /// ```
+    /// use with_dir::WithDir;
+    ///
+    /// let wd = WithDir::manifest_dir().unwrap();
+    /// assert!(std::path::Path::new("Cargo.toml").exists());
+    /// ```
+    pub fn manifest_dir() -> Result<WithDir<'a>, std::io::Error> {
+        let dir = std::env::var_os("CARGO_MANIFEST_DIR").ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::NotFound,
+                "CARGO_MANIFEST_DIR isn't set, run under cargo or use manifest_dir!",
+            )
+        })?;
+        WithDir::new(dir)
+    }
+
+    /// Enter the root of the cargo workspace, the nearest directory with a
+    /// `Cargo.toml` that has a `[workspace]` table. The search starts from
+    /// `CARGO_MANIFEST_DIR` when it is set, otherwise from the current
+    /// directory. A package that isn't part of a workspace is its own root,
+    /// so failing that the nearest directory with any `Cargo.toml` is
+    /// entered. Fails with [NotFound](ErrorKind::NotFound) if there is none.
+    ///
+    /// ```
+    /// use with_dir::WithDir;
+    ///
+    /// let root = WithDir::workspace_root().unwrap();
+    /// assert!(std::path::Path::new("with_dir_macros").is_dir());
+    /// ```
+    pub fn workspace_root() -> Result<WithDir<'a>, std::io::Error> {
+        let _lock = lock_to_enter()?;
+        let start = start_dir()?;
+        let root = find_up(&start, is_workspace_root)
+            .or_else(|| find_up(&start, |dir| dir.join("Cargo.toml").is_file()))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("no Cargo.toml in {} or above", start.display()),
+                )
+            })?;
+        WithDir::new(root)
+    }
+
+    /// Enter the root of the git repository the current directory is in,
+    /// the nearest directory containing `.git`. That may be a file, as in
+    /// worktrees and submodules. Fails with [NotFound](ErrorKind::NotFound)
+    /// outside a repository.
+    ///
+    /// ```no_run
+    /// use with_dir::WithDir;
+    ///
+    /// let repo = WithDir::git_root().unwrap();
+    /// let status = repo.command("git").arg("status").output().unwrap();
+    /// ```
+    pub fn git_root() -> Result<WithDir<'a>, std::io::Error> {
+        // held until entered, so another thread's WithDir can't move the
+        // start of the search
+        let _lock = lock_to_enter()?;
+        let start = current_dir()?;
+        let root = find_up(&start, |dir| dir.join(".git").exists()).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("{} is not inside a git repository", start.display()),
+            )
+        })?;
+        WithDir::new(root)
+    }
+
+    /// Enter the nearest of the current directory and its ancestors that
+    /// contains `marker`, a file or directory such as `package.json`,
+    /// `.hg` or `flake.nix`. Fails with [NotFound](ErrorKind::NotFound) if
+    /// none does. See [nearest_from](crate::WithDir::nearest_from) to start
+    /// somewhere else.
+    ///
+    /// ```
+    /// use with_dir::WithDir;
+    ///
+    /// let _src = WithDir::new("src").unwrap();
+    /// let package = WithDir::nearest("Cargo.toml").unwrap();
+    /// assert!(std::path::Path::new("src").is_dir());
+    /// ```
+    pub fn nearest(marker: impl AsRef<Path>) -> Result<WithDir<'a>, std::io::Error> {
+        let _lock = lock_to_enter()?;
+        WithDir::nearest_from(current_dir()?, marker)
+    }
+
+    /// Same as [nearest](crate::WithDir::nearest), walking up from `start`
+    /// instead of the current directory. A relative `start` is resolved
+    /// against the current directory.
+    pub fn nearest_from(
+        start: impl AsRef<Path>,
+        marker: impl AsRef<Path>,
+    ) -> Result<WithDir<'a>, std::io::Error> {
+        let _lock = lock_to_enter()?;
+        let start = current_dir()?.join(start);
+        let marker = marker.as_ref();
+        let dir = find_up(&start, |dir| dir.join(marker).exists()).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("no {} in {} or above", marker.display(), start.display()),
+            )
+        })?;
+        WithDir::new(dir)
+    }
+
+    /// Enter the directory containing the current executable, for loading
+    /// assets that ship next to it. Symlinks are resolved, so if the
+    /// executable was started through a link this is the directory of the
+    /// real file. See [exe_link_dir](crate::WithDir::exe_link_dir) to keep
+    /// the link's directory.
+    ///
+    /// ```
+    /// use with_dir::WithDir;
+    ///
+    /// let exe = std::env::current_exe().unwrap();
+    /// let wd = WithDir::exe_dir().unwrap();
+    /// assert!(wd.join(exe.file_name().unwrap()).exists());
+    /// ```
+    pub fn exe_dir() -> Result<WithDir<'a>, std::io::Error> {
+        let exe = std::fs::canonicalize(std::env::current_exe()?)?;
+        WithDir::new(parent_of(&exe)?)
+    }
+
+    /// Same as [exe_dir](crate::WithDir::exe_dir), without resolving
+    /// symlinks, so a program linked into `~/bin` enters `~/bin`. Where the
+    /// platform reports the resolved path anyway, as Linux does, the two
+    /// are the same.
+    pub fn exe_link_dir() -> Result<WithDir<'a>, std::io::Error> {
+        let exe = std::env::current_exe()?;
+        WithDir::new(parent_of(&exe)?)
+    }
+}
+
+/// The directory containing the file `path`
+fn parent_of(path: &Path) -> Result<&Path, std::io::Error> {
+    path.parent().ok_or_else(|| {
+        std::io::Error::new(
+            ErrorKind::NotFound,
+            format!("{} has no parent directory", path.display()),
+        )
+    })
+}
+
+/// Enter the directory of the calling crate, as
+/// [WithDir::manifest_dir](crate::WithDir::manifest_dir) does, but with
+/// `CARGO_MANIFEST_DIR` captured when the caller is compiled, so it works
+/// however the binary is run later on the same machine.
+///
+/// ```
+/// let wd = with_dir::manifest_dir!().unwrap();
+/// assert!(std::path::Path::new("Cargo.toml").exists());
+/// ```
+#[macro_export]
+macro_rules! manifest_dir {
+    () => {
+        $crate::WithDir::new(::core::env!("CARGO_MANIFEST_DIR"))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_home() {
+        let Some(expected) = std::env::home_dir().filter(|h| h.is_dir()) else {
+            return;
+        };
+        let wd = WithDir::home().unwrap();
+        assert_eq!(wd.path(), expected);
+        assert!(wd.is_current());
+    }
+
+    #[test]
+    fn test_manifest_dir() {
+        let wd = WithDir::manifest_dir().unwrap();
+        assert_eq!(wd.path(), Path::new(env!("CARGO_MANIFEST_DIR")));
+        let inner = crate::manifest_dir!().unwrap();
+        assert_eq!(inner.path(), wd.path());
+    }
+
+    #[test]
+    fn test_workspace_root() {
+        let tmp = WithDir::temp().unwrap();
+        std::fs::create_dir_all("crates/a/src").unwrap();
+        std::fs::write("Cargo.toml", "[workspace]\nmembers = [\"crates/a\"]\n").unwrap();
+        std::fs::write("crates/a/Cargo.toml", "[package]\nname = \"a\"\n").unwrap();
+        let start = tmp.path().join("crates/a/src");
+        assert_eq!(find_up(&start, is_workspace_root).unwrap(), tmp.path());
+
+        // found from CARGO_MANIFEST_DIR, wherever the current directory is
+        let _elsewhere = WithDir::new("/").unwrap();
+        let root = WithDir::workspace_root().unwrap();
+        assert_eq!(root.path(), Path::new(env!("CARGO_MANIFEST_DIR")));
+    }
+
+    #[test]
+    fn test_git_root() {
+        let tmp = WithDir::temp().unwrap();
+        std::fs::create_dir_all("sub/dir").unwrap();
+        std::fs::create_dir("sub/.git").unwrap();
+        let _wd = WithDir::new("sub/dir").unwrap();
+        let root = WithDir::git_root().unwrap();
+        assert_eq!(root.path(), tmp.path().join("sub"));
+    }
+
+    #[test]
+    fn test_nearest() {
+        let tmp = WithDir::temp().unwrap();
+        std::fs::create_dir_all("a/b/c").unwrap();
+        std::fs::write("a/flake.nix", "").unwrap();
+        let found = WithDir::nearest_from("a/b/c", "flake.nix").unwrap();
+        assert_eq!(found.path(), tmp.path().join("a"));
+        drop(found);
+
+        let _c = WithDir::new("a/b/c").unwrap();
+        assert_eq!(WithDir::nearest("b").unwrap().path(), tmp.path().join("a"));
+        let err = WithDir::nearest("no-such-marker").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_exe_dir() {
+        let exe = std::fs::canonicalize(std::env::current_exe().unwrap()).unwrap();
+        let wd = WithDir::exe_dir().unwrap();
+        assert_eq!(wd.path(), exe.parent().unwrap());
+        assert!(Path::new(exe.file_name().unwrap()).exists());
+        let link = WithDir::exe_link_dir().unwrap();
+        assert!(link.join(exe.file_name().unwrap()).exists());
+    }
+}
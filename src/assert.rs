@@ -0,0 +1,85 @@
+//! Assertion helpers for tests that use a [WithDir](crate::WithDir). Relative
+//! paths are resolved against the guard's directory, and failure messages
+//! include that directory.
+use std::{fs::read, path::Path};
+
+use crate::WithDir;
+
+impl WithDir<'_> {
+    /// Panics if `rel` does not exist inside this directory.
+    #[track_caller]
+    pub fn assert_exists(&self, rel: impl AsRef<Path>) {
+        let rel = rel.as_ref();
+        if !self.resolve(rel).exists() {
+            panic!(
+                "expected {} to exist in {}",
+                rel.display(),
+                self.path().display()
+            );
+        }
+    }
+
+    /// Panics if `rel` exists inside this directory.
+    #[track_caller]
+    pub fn assert_not_exists(&self, rel: impl AsRef<Path>) {
+        let rel = rel.as_ref();
+        if self.resolve(rel).exists() {
+            panic!(
+                "expected {} not to exist in {}",
+                rel.display(),
+                self.path().display()
+            );
+        }
+    }
+
+    /// Panics if `rel` can't be read or its content is not `expected`.
+    #[track_caller]
+    pub fn assert_content(&self, rel: impl AsRef<Path>, expected: impl AsRef<[u8]>) {
+        let rel = rel.as_ref();
+        let expected = expected.as_ref();
+        let actual = match read(self.resolve(rel)) {
+            Ok(a) => a,
+            Err(e) => panic!(
+                "failed to read {} in {}: {}",
+                rel.display(),
+                self.path().display(),
+                e
+            ),
+        };
+        if actual != expected {
+            panic!(
+                "content of {} in {} did not match\n  expected: {:?}\n    actual: {:?}",
+                rel.display(),
+                self.path().display(),
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(&actual)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::write,
+        panic::{catch_unwind, AssertUnwindSafe},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_asserts() {
+        let wd = WithDir::temp().unwrap();
+        write("report.json", "{}").unwrap();
+
+        wd.assert_exists("report.json");
+        wd.assert_not_exists("missing");
+        wd.assert_content("report.json", "{}");
+
+        let err =
+            catch_unwind(AssertUnwindSafe(|| wd.assert_content("report.json", "[]"))).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(msg.contains(&wd.path().display().to_string()));
+        assert!(catch_unwind(AssertUnwindSafe(|| wd.assert_exists("missing"))).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! Waiting for and restoring the directory without blocking an async
+//! executor, see [WithDir::new_async](crate::WithDir::new_async) and
+//! [WithDir::leave_async](crate::WithDir::leave_async). Requires the
+//! `async` feature.
+use std::{
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use tokio::sync::Mutex;
+
+use crate::WithDir;
+
+/// Held by every `WithDir` made by `new_async`, so async callers queue here
+/// rather than on the directory lock
+static GATE: Mutex<()> = Mutex::const_new(());
+
+impl<'a> WithDir<'a> {
+    /// Same as [new](crate::WithDir::new), but waits for other `new_async`
+    /// guards to be dropped without blocking the executor thread, so many
+    /// tasks wanting the directory don't starve the runtime. Once it's
+    /// their turn the directory lock is taken and the directory changed
+    /// synchronously, which only blocks if a guard made some other way is
+    /// alive on another thread. Requires the `async` feature.
+    ///
+    /// Unlike the other constructors this isn't reentrant, even on the same
+    /// thread. Two tasks on one thread could otherwise both hold the
+    /// directory across an `.await`. Nest with the synchronous
+    /// constructors instead.
+    ///
+    /// ```
+    /// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+    /// use with_dir::WithDir;
+    ///
+    /// let wd = WithDir::new_async("src").await.unwrap();
+    /// assert!(std::path::Path::new("lib.rs").exists());
+    /// # });
+    /// ```
+    pub async fn new_async(path: impl AsRef<Path>) -> Result<WithDir<'a>, std::io::Error> {
+        let gate = GATE.lock().await;
+        let mut wd = WithDir::new(path)?;
+        wd.gate = Some(gate);
+        Ok(wd)
+    }
+}
+
+impl WithDir<'_> {
+    /// Same as [leave](crate::WithDir::leave), but the original directory
+    /// is restored on tokio's blocking pool, since dropping can't be async.
+    /// Call it at the end of async scopes instead of letting the guard
+    /// drop. Requires the `async` feature and a tokio runtime.
+    ///
+    /// ```
+    /// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+    /// use with_dir::WithDir;
+    ///
+    /// let wd = WithDir::new_async("src").await.unwrap();
+    /// wd.leave_async().await.unwrap();
+    /// # });
+    /// ```
+    pub async fn leave_async(self) -> Result<(), std::io::Error> {
+        let clobbered = self.check_restore();
+        let restore = self.restore().into_owned();
+        let ret = tokio::task::spawn_blocking(move || restore.run())
+            .await
+            .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+        self.release(clobbered, ret).map(|_| ())
+    }
+}
+
+/// In debug builds, say once on stderr that a guard for `path` was dropped
+/// on a tokio runtime thread, where it should have been given to
+/// [leave_async](crate::WithDir::leave_async) instead
+pub(crate) fn warn_if_dropped_in_runtime(path: &Path) {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    if !cfg!(debug_assertions) || tokio::runtime::Handle::try_current().is_err() {
+        return;
+    }
+    if !WARNED.swap(true, Ordering::Relaxed) {
+        eprintln!(
+            "with_dir: WithDir for {} dropped inside an async runtime, \
+             use leave_async to restore without blocking the executor",
+            path.display()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_new_async() {
+        let tmp = WithDir::temp().unwrap();
+        std::fs::create_dir("a").unwrap();
+        std::fs::create_dir("b").unwrap();
+        let enter = |name| async move {
+            let wd = WithDir::new_async(name).await.unwrap();
+            // the other task runs here, and mustn't get in
+            tokio::task::yield_now().await;
+            assert!(wd.is_current());
+        };
+        tokio::join!(enter("a"), enter("b"));
+        assert!(tmp.is_current());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_leave_async() {
+        let tmp = WithDir::temp().unwrap();
+        std::fs::create_dir("a").unwrap();
+        let wd = WithDir::new_async("a").await.unwrap();
+        wd.leave_async().await.unwrap();
+        assert!(tmp.is_current());
+        // the gate was released
+        WithDir::new_async("a")
+            .await
+            .unwrap()
+            .leave_async()
+            .await
+            .unwrap();
+        WithDir::new("a").unwrap().leave_async().await.unwrap();
+        assert!(tmp.is_current());
+    }
+}
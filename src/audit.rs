@@ -0,0 +1,203 @@
+//! A log of every directory change made by a [WithDir](crate::WithDir), see
+//! [AuditLog].
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    thread::{self, ThreadId},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+
+use crate::{registry, DirChangeObserver};
+
+/// Whether an [AuditEntry] is for entering or leaving
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// A `WithDir` was created
+    Enter,
+    /// A `WithDir` was dropped or left and the original directory restored
+    Leave,
+}
+
+/// One directory change recorded by an [AuditLog]
+#[derive(Clone, Debug)]
+pub struct AuditEntry {
+    /// Entering or leaving
+    pub event: AuditEvent,
+    /// Directory before the change
+    pub from: PathBuf,
+    /// Directory after the change
+    pub to: PathBuf,
+    /// Thread that made the change
+    pub thread: ThreadId,
+    /// Name of that thread, which under `cargo test` is the test name
+    pub thread_name: Option<String>,
+    /// When the change was made
+    pub time: SystemTime,
+    /// Set with [label](crate::WithDir::label), if it was by the time of
+    /// the change. A label given to [Options](crate::Options) is only
+    /// seen on leaving.
+    pub label: Option<String>,
+}
+
+/// One line, e.g. `1700000000.123 tests::a enter /src -> /tmp/x [setup]`
+impl std::fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(f, "{}.{:03} ", time.as_secs(), time.subsec_millis())?;
+        match &self.thread_name {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "{:?}", self.thread)?,
+        }
+        let event = match self.event {
+            AuditEvent::Enter => "enter",
+            AuditEvent::Leave => "leave",
+        };
+        write!(
+            f,
+            " {} {} -> {}",
+            event,
+            self.from.display(),
+            self.to.display()
+        )?;
+        if let Some(label) = &self.label {
+            write!(f, " [{}]", label)?;
+        }
+        Ok(())
+    }
+}
+
+/// Records every enter and leave of every `WithDir` in the process once
+/// registered with [add_observer](crate::add_observer), to look back on
+/// after a flaky, order dependent test fails. Entries are kept in memory
+/// and, with [to_file](AuditLog::to_file), also appended to a file as they
+/// happen so they survive a crash.
+///
+/// ```
+/// use std::sync::Arc;
+/// use with_dir::{audit::AuditEvent, AuditLog, WithDir};
+///
+/// let log = Arc::new(AuditLog::new());
+/// with_dir::add_observer(log.clone());
+/// let path = WithDir::temp().unwrap().path().to_owned();
+///
+/// // other threads' guards are recorded too
+/// let events: Vec<_> = log.entries().into_iter().filter(|e| e.from == path || e.to == path).collect();
+/// assert_eq!(events[0].event, AuditEvent::Enter);
+/// assert_eq!(events[1].event, AuditEvent::Leave);
+/// log.dump(std::io::stderr()).unwrap();
+/// ```
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    entries: Mutex<Vec<AuditEntry>>,
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    /// An empty log kept in memory
+    pub fn new() -> AuditLog {
+        AuditLog::default()
+    }
+
+    /// An empty log that also appends each entry as a line to `path`,
+    /// which is created if it doesn't exist
+    pub fn to_file(path: impl AsRef<Path>) -> Result<AuditLog, std::io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            entries: Mutex::default(),
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    /// Everything recorded so far, oldest first
+    pub fn entries(&self) -> Vec<AuditEntry> {
+        self.entries.lock().clone()
+    }
+
+    /// Write every entry to `w`, one per line
+    pub fn dump(&self, mut w: impl Write) -> Result<(), std::io::Error> {
+        for e in self.entries.lock().iter() {
+            writeln!(w, "{}", e)?;
+        }
+        Ok(())
+    }
+
+    /// Forget everything recorded so far. A file is left as it is.
+    pub fn clear(&self) {
+        self.entries.lock().clear();
+    }
+
+    fn record(&self, event: AuditEvent, from: &Path, to: &Path) {
+        let current = thread::current();
+        let path = match event {
+            AuditEvent::Enter => to,
+            AuditEvent::Leave => from,
+        };
+        let entry = AuditEntry {
+            event,
+            from: from.to_owned(),
+            to: to.to_owned(),
+            thread: current.id(),
+            thread_name: current.name().map(str::to_owned),
+            time: SystemTime::now(),
+            label: registry::label_of(path),
+        };
+        if let Some(file) = &self.file {
+            // losing a line is better than failing the guard
+            let _ = writeln!(file.lock(), "{}", entry);
+        }
+        self.entries.lock().push(entry);
+    }
+}
+
+impl DirChangeObserver for AuditLog {
+    fn on_enter(&self, from: &Path, to: &Path) {
+        self.record(AuditEvent::Enter, from, to);
+    }
+
+    fn on_exit(&self, from: &Path, to: &Path) {
+        self.record(AuditEvent::Leave, from, to);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{add_observer, remove_observer, WithDir};
+
+    #[test]
+    fn test_audit_log() {
+        let tmp = WithDir::temp().unwrap();
+        let log = Arc::new(AuditLog::to_file(tmp.path().join("audit.log")).unwrap());
+        let observer: Arc<dyn DirChangeObserver> = log.clone();
+        add_observer(observer.clone());
+        std::fs::create_dir("a").unwrap();
+        let mut wd = WithDir::new("a").unwrap();
+        wd.label("inner");
+        drop(wd);
+        remove_observer(&observer);
+
+        // only this thread can change directory while tmp is alive
+        let entries = log.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].event, AuditEvent::Enter);
+        assert_eq!(entries[0].to, tmp.path().join("a"));
+        assert_eq!(entries[0].label, None);
+        assert_eq!(entries[1].event, AuditEvent::Leave);
+        assert_eq!(entries[1].to, tmp.path());
+        assert_eq!(entries[1].label.as_deref(), Some("inner"));
+        assert_eq!(entries[1].thread, thread::current().id());
+
+        let mut dumped = Vec::new();
+        log.dump(&mut dumped).unwrap();
+        let written = std::fs::read("audit.log").unwrap();
+        assert_eq!(dumped, written);
+        let dumped = String::from_utf8(dumped).unwrap();
+        assert!(dumped.contains("audit::tests::test_audit_log leave "));
+        assert!(dumped.ends_with(" [inner]\n"));
+    }
+}
@@ -0,0 +1,227 @@
+//! The operations [WithDir](crate::WithDir) uses to read, change and make
+//! directories, behind a trait so they can be replaced, see [CwdBackend].
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+use tempfile::TempDir;
+
+use crate::{fs::long_path, temp_root};
+
+/// How a [WithDir](crate::WithDir) reads, changes and makes directories.
+/// [OsBackend] is used unless another backend is installed for every guard
+/// with [set_backend], or for one guard with
+/// [Options::backend](crate::Options::backend). On WASI the default is
+/// `WasiBackend` instead, which emulates the current directory. A backend can record or
+/// check directory changes in unit tests of code that uses `WithDir`, or
+/// redirect them somewhere else entirely.
+///
+/// Paths passed in may be relative, they are relative to what
+/// [current_dir](CwdBackend::current_dir) returns.
+pub trait CwdBackend: Send + Sync {
+    /// Same as [std::env::current_dir]
+    fn current_dir(&self) -> Result<PathBuf, std::io::Error>;
+
+    /// Same as [std::env::set_current_dir]
+    fn set_current_dir(&self, path: &Path) -> Result<(), std::io::Error>;
+
+    /// Make the directory `path`, and any missing parents if `recursive`
+    fn create_dir(&self, path: &Path, recursive: bool) -> Result<(), std::io::Error>;
+
+    /// Make a temporary directory whose name starts with `prefix`
+    fn temp_dir(&self, prefix: &str) -> Result<TempDir, std::io::Error>;
+
+    /// Same as [std::fs::metadata]. The default calls it, resolving
+    /// relative paths against the process current directory.
+    fn metadata(&self, path: &Path) -> Result<Metadata, std::io::Error> {
+        std::fs::metadata(path).map(Metadata::from)
+    }
+
+    /// Same as [Path::exists], using [metadata](CwdBackend::metadata)
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    /// Same as [std::fs::canonicalize]. The default calls it, resolving
+    /// relative paths against the process current directory.
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        std::fs::canonicalize(path)
+    }
+
+    /// Same as [std::fs::remove_dir_all], used to clean up directories
+    /// made by [create_ephemeral](crate::WithDir::create_ephemeral). The
+    /// default calls it, resolving relative paths against the process
+    /// current directory.
+    fn remove_dir_all(&self, path: &Path) -> Result<(), std::io::Error> {
+        std::fs::remove_dir_all(path)
+    }
+}
+
+/// What [CwdBackend::metadata] reports about a path, all `WithDir` needs
+/// to know. Backends that don't keep real files can make one directly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    /// Whether it is a directory, rather than a file or anything else
+    pub is_dir: bool,
+}
+
+impl From<std::fs::Metadata> for Metadata {
+    fn from(m: std::fs::Metadata) -> Metadata {
+        Metadata { is_dir: m.is_dir() }
+    }
+}
+
+/// The real process current directory and file system. Paths longer than
+/// `MAX_PATH` are accepted on Windows, which works when long path support
+/// is enabled for the process (Windows 10 1607 and later, with the
+/// `LongPathsEnabled` policy and manifest setting).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsBackend;
+
+impl CwdBackend for OsBackend {
+    fn current_dir(&self) -> Result<PathBuf, std::io::Error> {
+        std::env::current_dir()
+    }
+
+    fn set_current_dir(&self, path: &Path) -> Result<(), std::io::Error> {
+        std::env::set_current_dir(long_path(path))
+    }
+
+    fn create_dir(&self, path: &Path, recursive: bool) -> Result<(), std::io::Error> {
+        std::fs::DirBuilder::new()
+            .recursive(recursive)
+            .create(long_path(path))
+    }
+
+    fn temp_dir(&self, prefix: &str) -> Result<TempDir, std::io::Error> {
+        tempfile::Builder::new()
+            .prefix(prefix)
+            .tempdir_in(temp_root())
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata, std::io::Error> {
+        std::fs::metadata(long_path(path)).map(Metadata::from)
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        std::fs::canonicalize(long_path(path))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<(), std::io::Error> {
+        std::fs::remove_dir_all(long_path(path))
+    }
+}
+
+static BACKEND: Mutex<Option<Arc<dyn CwdBackend>>> = Mutex::new(None);
+
+/// Use `backend` for every `WithDir` created from now on, in any thread,
+/// or go back to the default with `None`. Guards that already exist keep
+/// the backend they were created with.
+pub fn set_backend(backend: Option<Arc<dyn CwdBackend>>) {
+    *BACKEND.lock() = backend;
+}
+
+/// The backend installed with [set_backend]
+pub(crate) fn backend() -> Arc<dyn CwdBackend> {
+    BACKEND.lock().clone().unwrap_or_else(default_backend)
+}
+
+/// [OsBackend], or on WASI the [WasiBackend](crate::WasiBackend) that
+/// emulates its current directory
+pub(crate) fn default_backend() -> Arc<dyn CwdBackend> {
+    #[cfg(target_os = "wasi")]
+    return crate::wasi::WasiBackend::shared();
+    #[cfg(not(target_os = "wasi"))]
+    {
+        // shared, rather than allocating for every guard
+        static OS: std::sync::OnceLock<Arc<dyn CwdBackend>> = std::sync::OnceLock::new();
+        OS.get_or_init(|| Arc::new(OsBackend)).clone()
+    }
+}
+
+/// A backend set on [Options](crate::Options), which has to be `Debug`
+#[derive(Clone)]
+pub(crate) struct BackendRef(pub(crate) Arc<dyn CwdBackend>);
+
+impl std::fmt::Debug for BackendRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CwdBackend")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WithDir;
+
+    /// Passes everything on to the OS, remembering where it changed to
+    #[derive(Default)]
+    struct Record(Mutex<Vec<PathBuf>>);
+
+    impl CwdBackend for Record {
+        fn current_dir(&self) -> Result<PathBuf, std::io::Error> {
+            OsBackend.current_dir()
+        }
+
+        fn set_current_dir(&self, path: &Path) -> Result<(), std::io::Error> {
+            self.0.lock().push(path.to_owned());
+            OsBackend.set_current_dir(path)
+        }
+
+        fn create_dir(&self, path: &Path, recursive: bool) -> Result<(), std::io::Error> {
+            OsBackend.create_dir(path, recursive)
+        }
+
+        fn temp_dir(&self, prefix: &str) -> Result<TempDir, std::io::Error> {
+            OsBackend.temp_dir(prefix)
+        }
+    }
+
+    #[test]
+    fn test_backend() {
+        let tmp = WithDir::temp().unwrap();
+        let record = Arc::new(Record::default());
+
+        let wd = WithDir::options()
+            .backend(record.clone())
+            .create(true)
+            .enter("a")
+            .unwrap();
+        drop(wd);
+        assert_eq!(
+            *record.0.lock(),
+            [PathBuf::from("a"), tmp.path().to_owned()]
+        );
+
+        record.0.lock().clear();
+        set_backend(Some(record.clone()));
+        let wd = WithDir::new("a");
+        set_backend(None);
+        drop(wd);
+        // still used to restore after being uninstalled
+        assert_eq!(record.0.lock().len(), 2);
+    }
+
+    #[test]
+    fn test_same_dir() {
+        let tmp = WithDir::temp().unwrap();
+        std::fs::create_dir("a").unwrap();
+        let record = Arc::new(Record::default());
+        set_backend(Some(record.clone()));
+        let same = WithDir::new(".").unwrap();
+        let current = WithDir::current().unwrap();
+        drop(current);
+        drop(same);
+        assert!(record.0.lock().is_empty());
+
+        // restored if it was moved anyway
+        let same = WithDir::new(tmp.path()).unwrap();
+        std::env::set_current_dir("a").unwrap();
+        drop(same);
+        set_backend(None);
+        assert_eq!(*record.0.lock(), [tmp.path().to_owned()]);
+        assert!(tmp.is_current());
+    }
+}
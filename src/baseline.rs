@@ -0,0 +1,120 @@
+//! Persistent, verified directories for benchmarks. See
+//! [WithDir::baseline](crate::WithDir::baseline).
+use std::{
+    fs::{read_dir, read_to_string, remove_dir_all, write},
+    io::ErrorKind,
+    path::Path,
+};
+
+use crate::{current_dir, lock_to_enter, Snapshot, WithDir};
+
+/// Name of the manifest file written inside a baseline directory.
+pub const MANIFEST_FILE_NAME: &str = ".with_dir.manifest";
+
+fn digest(dir: &Path) -> Result<u64, std::io::Error> {
+    Ok(Snapshot::take(dir, true)?.digest(Path::new(MANIFEST_FILE_NAME)))
+}
+
+fn is_valid(dir: &Path) -> bool {
+    let Ok(manifest) = read_to_string(dir.join(MANIFEST_FILE_NAME)) else {
+        return false;
+    };
+    matches!(digest(dir), Ok(d) if manifest.trim() == d.to_string())
+}
+
+impl<'a> WithDir<'a> {
+    /// Enter a directory that is reused between runs, so benchmarks see the
+    /// same on-disk state every time. The first time, or if the contents no
+    /// longer match the hash recorded in its
+    /// [MANIFEST_FILE_NAME](crate::baseline::MANIFEST_FILE_NAME), the
+    /// directory is wiped, `populate` is called with the current directory
+    /// set to it, and a new manifest is written.
+    ///
+    /// Only directories with a manifest are wiped. An existing directory
+    /// without one is populated if it is empty, otherwise this fails with
+    /// [AlreadyExists](std::io::ErrorKind::AlreadyExists) rather than delete
+    /// files it didn't make.
+    ///
+    /// The benchmark should not modify the directory, or it will be
+    /// repopulated on the next run.
+    ///
+    /// ```no_run
+    /// use with_dir::WithDir;
+    ///
+    /// let wd = WithDir::baseline("target/bench-fixture", |_| {
+    ///     std::fs::write("input.txt", "lots of data")
+    /// }).unwrap();
+    /// // criterion's bench_function(...) here
+    /// ```
+    pub fn baseline<F>(path: impl AsRef<Path>, populate: F) -> Result<WithDir<'a>, std::io::Error>
+    where
+        F: FnOnce(&Path) -> Result<(), std::io::Error>,
+    {
+        // held while checking, so `path` is resolved against, and the
+        // directory entered from, the same current directory
+        let _lock = lock_to_enter()?;
+        let path = current_dir()?.join(path);
+        if path.exists() {
+            if is_valid(&path) {
+                return WithDir::new(&path);
+            }
+            if path.join(MANIFEST_FILE_NAME).exists() {
+                remove_dir_all(&path)?;
+            } else if read_dir(&path)?.next().is_some() {
+                return Err(std::io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!(
+                        "{} isn't empty and has no {}, refusing to replace it with a baseline",
+                        path.display(),
+                        MANIFEST_FILE_NAME
+                    ),
+                ));
+            }
+        }
+        let wd = WithDir::create_all(&path)?;
+        populate(wd.path())?;
+        let root = wd.absolute_path();
+        write(root.join(MANIFEST_FILE_NAME), digest(root)?.to_string())?;
+        Ok(wd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, fs::read_dir};
+
+    use super::*;
+
+    #[test]
+    fn test_baseline() {
+        let tmp = WithDir::temp().unwrap();
+        let calls = Cell::new(0);
+        let populate = |_: &Path| {
+            calls.set(calls.get() + 1);
+            write("data", "abc")
+        };
+
+        drop(WithDir::baseline("fixture", populate).unwrap());
+        drop(WithDir::baseline("fixture", populate).unwrap());
+        assert_eq!(calls.get(), 1);
+
+        // tamper with it, should be rebuilt
+        write(tmp.path().join("fixture/data"), "xyz").unwrap();
+        write(tmp.path().join("fixture/extra"), "").unwrap();
+        let wd = WithDir::baseline("fixture", populate).unwrap();
+        assert_eq!(calls.get(), 2);
+        assert_eq!(read_dir(".").unwrap().count(), 2);
+        wd.assert_content("data", "abc");
+        drop(wd);
+
+        // a directory this didn't make is left alone
+        std::fs::create_dir("user").unwrap();
+        write(tmp.path().join("user/notes"), "").unwrap();
+        let err = WithDir::baseline("user", populate).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert!(tmp.path().join("user/notes").exists());
+        std::fs::create_dir("empty").unwrap();
+        drop(WithDir::baseline("empty", populate).unwrap());
+        assert_eq!(calls.get(), 3);
+    }
+}
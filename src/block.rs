@@ -0,0 +1,88 @@
+//! Declarative macros that run a block in a directory and return its
+//! value, see [with_dir](crate::block::with_dir) and
+//! [with_temp_dir](crate::block::with_temp_dir). The guard is never in
+//! scope, so it can't be bound to `_` and dropped straight away by mistake.
+//!
+//! They live in this module because `with_dir::with_dir` is already the
+//! attribute from the `macros` feature. Import them from here:
+//!
+//! ```
+//! use with_dir::block::{with_dir, with_temp_dir};
+//!
+//! let found = with_dir!("src" => { std::path::Path::new("lib.rs").exists() }).unwrap();
+//! assert!(found);
+//! let written = with_temp_dir! {
+//!     std::fs::write("out.txt", "hi").unwrap();
+//!     std::fs::read_to_string("out.txt").unwrap()
+//! }
+//! .unwrap();
+//! assert_eq!(written, "hi");
+//! ```
+
+/// Run a block with `path` as the current directory, see
+/// [block](crate::block)
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __with_dir_block {
+    ($path:expr => $body:block) => {
+        match $crate::WithDir::new($path) {
+            ::core::result::Result::Ok(guard) => {
+                let value = $body;
+                guard.leave().map(|()| value)
+            }
+            ::core::result::Result::Err(e) => ::core::result::Result::Err(e),
+        }
+    };
+}
+
+/// Run a block in a new temporary directory, see [block](crate::block)
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __with_temp_dir_block {
+    ($($body:tt)*) => {
+        match $crate::WithDir::temp() {
+            ::core::result::Result::Ok(guard) => {
+                let value = { $($body)* };
+                guard.leave().map(|()| value)
+            }
+            ::core::result::Result::Err(e) => ::core::result::Result::Err(e),
+        }
+    };
+}
+
+/// `with_dir!(path => { ... })` enters `path` with
+/// [WithDir::new](crate::WithDir::new), runs the block and restores the
+/// original directory. Evaluates to `Ok` with the block's value, or the
+/// error entering or leaving the directory.
+pub use crate::__with_dir_block as with_dir;
+
+/// `with_temp_dir! { ... }` is the same as [with_dir!] in a directory from
+/// [WithDir::temp](crate::WithDir::temp), deleted afterwards
+pub use crate::__with_temp_dir_block as with_temp_dir;
+
+#[cfg(test)]
+mod tests {
+    use std::{env::current_dir, path::Path};
+
+    use super::*;
+    use crate::WithDir;
+
+    #[test]
+    fn test_block_macros() {
+        let tmp = WithDir::temp().unwrap();
+        std::fs::create_dir("a").unwrap();
+        let cwd = with_dir!("a" => { current_dir().unwrap() }).unwrap();
+        assert_eq!(cwd, tmp.path().join("a"));
+        assert!(tmp.is_current());
+        assert!(with_dir!("missing" => { 1 }).is_err());
+
+        let temp = with_temp_dir! {
+            let cwd = current_dir().unwrap();
+            assert_ne!(cwd, tmp.path());
+            cwd
+        }
+        .unwrap();
+        assert!(tmp.is_current());
+        assert!(!Path::new(&temp).exists());
+    }
+}
@@ -0,0 +1,210 @@
+//! Failure bundles: a single `.tar.zst` holding everything needed to debug a
+//! scope after the fact. Requires the `bundle` feature.
+use std::{
+    fs::{create_dir_all, File},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tar::{Builder, Header};
+
+use crate::{
+    audit::{AuditEntry, AuditEvent},
+    registry, WithDir,
+};
+
+fn append_text<W: std::io::Write>(
+    builder: &mut Builder<W>,
+    name: &str,
+    text: &str,
+) -> Result<(), std::io::Error> {
+    let mut header = Header::new_gnu();
+    header.set_size(text.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    );
+    header.set_cksum();
+    builder.append_data(&mut header, name, text.as_bytes())
+}
+
+impl WithDir<'_> {
+    /// Write a bundle to `dest` if this scope fails: when it is dropped during
+    /// a panic, or when restoring the original directory fails. Relative
+    /// `dest` are resolved against the directory this `WithDir` was created
+    /// from. The bundle's path is printed to stderr.
+    pub fn bundle_on_failure(&mut self, dest: impl AsRef<Path>) {
+        self.bundle_dir = Some(self.original_dir.join(dest));
+    }
+
+    /// Write a `<scope name>-<unix time>.tar.zst` into the directory `dest`
+    /// containing:
+    ///
+    /// * `scope/` - the contents of this directory
+    /// * `env.txt` - the environment of the current process
+    /// * `metadata.txt` - the scope's paths, thread and time of writing
+    /// * `events.txt` - the scope's enter event and, for a failure bundle,
+    ///   its leave event and how that went, in the format of
+    ///   [AuditLog](crate::AuditLog)
+    ///
+    /// Returns the path of the bundle.
+    pub fn write_bundle(&self, dest: impl AsRef<Path>) -> Result<PathBuf, std::io::Error> {
+        self.write_bundle_with(dest.as_ref(), None)
+    }
+
+    fn write_bundle_with(
+        &self,
+        dest: &Path,
+        left: Option<&Result<PathBuf, std::io::Error>>,
+    ) -> Result<PathBuf, std::io::Error> {
+        let dest = self.original_dir.join(dest);
+        create_dir_all(&dest)?;
+        let root = self.absolute_path();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let name = root
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "scope".to_owned());
+        let bundle = dest.join(format!("{}-{}.tar.zst", name, now.as_secs()));
+
+        let encoder = zstd::Encoder::new(File::create(&bundle)?, 0)?;
+        let mut builder = Builder::new(encoder);
+        builder.follow_symlinks(false);
+        builder.append_dir_all("scope", root)?;
+
+        let env: String = std::env::vars_os()
+            .map(|(k, v)| format!("{}={}\n", k.to_string_lossy(), v.to_string_lossy()))
+            .collect();
+        append_text(&mut builder, "env.txt", &env)?;
+
+        let metadata = format!(
+            "path: {}\noriginal_dir: {}\ntest: {}\npanicking: {}\ntime: {}\n",
+            root.display(),
+            self.original_dir.display(),
+            self.test_name().unwrap_or("<unnamed>"),
+            std::thread::panicking(),
+            now.as_secs()
+        );
+        append_text(&mut builder, "metadata.txt", &metadata)?;
+        append_text(&mut builder, "events.txt", &self.events(left))?;
+
+        builder.into_inner()?.finish()?;
+        Ok(bundle)
+    }
+
+    /// The enter and leave events of this scope, one per line
+    fn events(&self, left: Option<&Result<PathBuf, std::io::Error>>) -> String {
+        let root = self.absolute_path();
+        let current = std::thread::current();
+        let guard = registry::guard(self.id);
+        let label = guard.as_ref().and_then(|g| g.label.clone());
+        let mut events = String::new();
+        // unregistered guards don't know when they were entered
+        if let Some(g) = &guard {
+            let enter = AuditEntry {
+                event: AuditEvent::Enter,
+                from: self.original_dir.clone(),
+                to: root.to_owned(),
+                thread: g.thread,
+                thread_name: g.thread_name.clone(),
+                time: g.created,
+                label: label.clone(),
+            };
+            events.push_str(&format!("{}\n", enter));
+        }
+        if let Some(left) = left {
+            let leave = AuditEntry {
+                event: AuditEvent::Leave,
+                from: root.to_owned(),
+                to: left.as_ref().unwrap_or(&self.original_dir).clone(),
+                thread: current.id(),
+                thread_name: current.name().map(str::to_owned),
+                time: SystemTime::now(),
+                label,
+            };
+            match left {
+                Ok(_) => events.push_str(&format!("{}\n", leave)),
+                Err(e) => events.push_str(&format!("{} failed: {}\n", leave, e)),
+            }
+        }
+        events
+    }
+
+    pub(crate) fn write_failure_bundle(&self, left: &Result<PathBuf, std::io::Error>) {
+        if let Some(dest) = &self.bundle_dir {
+            match self.write_bundle_with(dest, Some(left)) {
+                Ok(p) => eprintln!("with_dir: wrote failure bundle to {}", p.display()),
+                Err(e) => eprintln!("with_dir: failed to write failure bundle: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{read_dir, write},
+        panic::{catch_unwind, AssertUnwindSafe},
+    };
+
+    use super::*;
+
+    fn entries(bundle: &Path) -> Vec<String> {
+        let decoder = zstd::Decoder::new(File::open(bundle).unwrap()).unwrap();
+        tar::Archive::new(decoder)
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_write_bundle() {
+        let out = WithDir::temp().unwrap();
+        let wd = WithDir::temp().unwrap();
+        write("a.txt", "a").unwrap();
+
+        let bundle = wd.write_bundle(out.path()).unwrap();
+        let entries = entries(&bundle);
+        assert!(entries.contains(&"scope/a.txt".to_owned()));
+        assert!(entries.contains(&"env.txt".to_owned()));
+        assert!(entries.contains(&"metadata.txt".to_owned()));
+        assert!(entries.contains(&"events.txt".to_owned()));
+    }
+
+    #[test]
+    fn test_bundle_on_panic() {
+        let out = WithDir::temp().unwrap();
+        let dest = out.path().join("bundles");
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let mut wd = WithDir::temp().unwrap();
+            wd.bundle_on_failure(&dest);
+            panic!("test failed");
+        }));
+        assert!(result.is_err());
+        // written once, with the leave event
+        let bundles: Vec<_> = read_dir(&dest)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        assert_eq!(bundles.len(), 1);
+        let decoder = zstd::Decoder::new(File::open(&bundles[0]).unwrap()).unwrap();
+        let mut archive = tar::Archive::new(decoder);
+        let mut events = String::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            if entry.path().unwrap() == Path::new("events.txt") {
+                std::io::Read::read_to_string(&mut entry, &mut events).unwrap();
+            }
+        }
+        let lines: Vec<_> = events.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(" enter "));
+        assert!(lines[1].contains(" leave "));
+    }
+}
@@ -0,0 +1,314 @@
+//! Recursive copies into and out of a [WithDir](crate::WithDir).
+use std::{
+    fs::{
+        canonicalize, copy, create_dir_all, read_link, remove_dir_all, symlink_metadata, Metadata,
+    },
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::{walk::Walk, WithDir};
+
+/// What to do when a file being copied already exists at the destination
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Replace the existing file, or directory
+    #[default]
+    Replace,
+    /// Keep the existing file
+    Skip,
+    /// Stop and return an [AlreadyExists](std::io::ErrorKind::AlreadyExists) error
+    Error,
+}
+
+/// What to do with symlinks found in the source
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Symlinks {
+    /// Copy the target of the link. Links whose target doesn't exist are
+    /// left out.
+    #[default]
+    Follow,
+    /// Recreate the link at the destination
+    Preserve,
+    /// Leave links out of the copy
+    Skip,
+}
+
+/// Options for [WithDir::copy_from_with](crate::WithDir::copy_from_with) and
+/// [WithDir::copy_to_with](crate::WithDir::copy_to_with).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CopyOptions {
+    pub overwrite: Overwrite,
+    pub symlinks: Symlinks,
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> Result<(), std::io::Error> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> Result<(), std::io::Error> {
+    let resolved = link.parent().unwrap_or(Path::new("")).join(target);
+    if resolved.is_dir() {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+/// `path` with symlinks resolved, as far as it exists
+fn resolve(path: &Path) -> Result<PathBuf, std::io::Error> {
+    let mut existing = path;
+    let mut rest = Vec::new();
+    while symlink_metadata(existing).is_err() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            _ => break,
+        }
+    }
+    let mut resolved = canonicalize(existing)?;
+    resolved.extend(rest.iter().rev());
+    Ok(resolved)
+}
+
+/// Remove whatever is at `path`, described by `existing`, without
+/// following a symlink
+fn remove_existing(path: &Path, existing: &Metadata) -> Result<(), std::io::Error> {
+    if existing.is_dir() {
+        return remove_dir_all(path);
+    }
+    match std::fs::remove_file(path) {
+        // a directory symlink is removed as a directory on Windows
+        Err(_) if cfg!(windows) && existing.file_type().is_symlink() => std::fs::remove_dir(path),
+        ret => ret,
+    }
+}
+
+fn copy_tree(from: &Path, to: &Path, options: &CopyOptions) -> Result<(), std::io::Error> {
+    // the copy would be walked into as it is made
+    if resolve(to)?.starts_with(canonicalize(from)?) {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "can't copy {} into itself at {}",
+                from.display(),
+                to.display()
+            ),
+        ));
+    }
+    create_dir_all(to)?;
+    let walk = Walk::new(from.to_owned()).follow_links(options.symlinks == Symlinks::Follow);
+    // directories whose destination was skipped, with everything below them
+    let mut skipped: Vec<PathBuf> = Vec::new();
+    for entry in walk {
+        let entry = match entry {
+            // a dangling link has no target to copy
+            Err(e) if options.symlinks == Symlinks::Follow && e.kind() == ErrorKind::NotFound => {
+                continue
+            }
+            entry => entry?,
+        };
+        if skipped.iter().any(|s| entry.path().starts_with(s)) {
+            continue;
+        }
+        let src = from.join(entry.path());
+        let dst = to.join(entry.path());
+        let file_type = entry.file_type();
+        let existing = symlink_metadata(&dst).ok();
+        if file_type.is_dir() {
+            // merge into a real directory, but never follow a symlink out
+            // of the destination
+            let in_the_way = existing.filter(|m| !m.is_dir());
+            if let Some(existing) = in_the_way {
+                match options.overwrite {
+                    Overwrite::Replace => remove_existing(&dst, &existing)?,
+                    Overwrite::Skip => {
+                        skipped.push(entry.into_path());
+                        continue;
+                    }
+                    Overwrite::Error => {
+                        return Err(std::io::Error::new(
+                            ErrorKind::AlreadyExists,
+                            format!("{} already exists", dst.display()),
+                        ))
+                    }
+                }
+            }
+            create_dir_all(&dst)?;
+            continue;
+        }
+        if let Some(existing) = existing {
+            match options.overwrite {
+                Overwrite::Replace => remove_existing(&dst, &existing)?,
+                Overwrite::Skip => continue,
+                Overwrite::Error => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!("{} already exists", dst.display()),
+                    ))
+                }
+            }
+        }
+        if file_type.is_symlink() {
+            // only seen when not following links
+            if options.symlinks == Symlinks::Preserve {
+                symlink(&read_link(&src)?, &dst)?;
+            }
+        } else {
+            copy(&src, &dst)?;
+        }
+    }
+    Ok(())
+}
+
+impl WithDir<'_> {
+    /// Copy the contents of the directory `src` into this directory, replacing
+    /// existing files and following symlinks. Relative `src` are resolved
+    /// against the directory this `WithDir` was created from.
+    pub fn copy_from(&self, src: impl AsRef<Path>) -> Result<(), std::io::Error> {
+        self.copy_from_with(src, &CopyOptions::default())
+    }
+
+    /// See [copy_from](crate::WithDir::copy_from)
+    pub fn copy_from_with(
+        &self,
+        src: impl AsRef<Path>,
+        options: &CopyOptions,
+    ) -> Result<(), std::io::Error> {
+        copy_tree(&self.original_dir.join(src), self.absolute_path(), options)
+    }
+
+    /// Copy the contents of this directory into `dst`, creating it if needed,
+    /// replacing existing files and following symlinks. Relative `dst` are
+    /// resolved against the directory this `WithDir` was created from.
+    pub fn copy_to(&self, dst: impl AsRef<Path>) -> Result<(), std::io::Error> {
+        self.copy_to_with(dst, &CopyOptions::default())
+    }
+
+    /// See [copy_to](crate::WithDir::copy_to)
+    pub fn copy_to_with(
+        &self,
+        dst: impl AsRef<Path>,
+        options: &CopyOptions,
+    ) -> Result<(), std::io::Error> {
+        copy_tree(self.absolute_path(), &self.original_dir.join(dst), options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, read_to_string, write};
+
+    use super::*;
+
+    #[test]
+    fn test_copy() {
+        let src = WithDir::temp().unwrap();
+        create_dir_all("sub").unwrap();
+        write("sub/a", "new").unwrap();
+        write("b", "new").unwrap();
+
+        let wd = WithDir::temp().unwrap();
+        write("b", "old").unwrap();
+        let skip = CopyOptions {
+            overwrite: Overwrite::Skip,
+            ..Default::default()
+        };
+        wd.copy_from_with(src.path(), &skip).unwrap();
+        assert_eq!(read_to_string("sub/a").unwrap(), "new");
+        assert_eq!(read_to_string("b").unwrap(), "old");
+
+        let error = CopyOptions {
+            overwrite: Overwrite::Error,
+            ..Default::default()
+        };
+        let err = wd.copy_from_with(src.path(), &error).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+
+        wd.copy_from(src.path()).unwrap();
+        assert_eq!(read_to_string("b").unwrap(), "new");
+
+        let out = src.path().join("out");
+        wd.copy_to(&out).unwrap();
+        assert_eq!(read_to_string(out.join("sub/a")).unwrap(), "new");
+
+        // a directory in the way of a file is replaced
+        std::fs::remove_file(out.join("b")).unwrap();
+        create_dir_all(out.join("b/c")).unwrap();
+        wd.copy_to(&out).unwrap();
+        assert_eq!(read_to_string(out.join("b")).unwrap(), "new");
+
+        // a file in the way of a directory is subject to the policy too
+        let files = WithDir::temp().unwrap();
+        write("sub", "file").unwrap();
+        let err = files.copy_from_with(src.path(), &error).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        files.copy_from_with(src.path(), &skip).unwrap();
+        assert_eq!(read_to_string("sub").unwrap(), "file");
+        assert_eq!(read_to_string("b").unwrap(), "new");
+        files.copy_from(src.path()).unwrap();
+        assert_eq!(read_to_string("sub/a").unwrap(), "new");
+        drop(files);
+
+        let err = wd.copy_to(wd.path().join("nested/out")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(!Path::new("nested").exists());
+        let err = wd.copy_from(src.path().join("..")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_symlinks() {
+        let src = WithDir::temp().unwrap();
+        write("a", "a").unwrap();
+        symlink(Path::new("a"), Path::new("link")).unwrap();
+        symlink(Path::new("missing"), Path::new("dangling")).unwrap();
+
+        let wd = WithDir::temp().unwrap();
+        let preserve = CopyOptions {
+            symlinks: Symlinks::Preserve,
+            ..Default::default()
+        };
+        wd.copy_from_with(src.path(), &preserve).unwrap();
+        assert_eq!(read_link("link").unwrap(), Path::new("a"));
+
+        let skip = CopyOptions {
+            symlinks: Symlinks::Skip,
+            ..Default::default()
+        };
+        // relative to the directory wd was created from
+        wd.copy_to_with("skipped", &skip).unwrap();
+        let skipped = src.path().join("skipped");
+        assert!(skipped.join("a").exists());
+        assert!(symlink_metadata(skipped.join("link")).is_err());
+
+        // a symlinked directory at the destination isn't written through
+        let outside = WithDir::temp().unwrap();
+        let dirs = WithDir::temp().unwrap();
+        create_dir_all("sub").unwrap();
+        write("sub/a", "").unwrap();
+        let dst = WithDir::temp().unwrap();
+        symlink(outside.path(), Path::new("sub")).unwrap();
+        let error = CopyOptions {
+            overwrite: Overwrite::Error,
+            ..Default::default()
+        };
+        let err = dst.copy_from_with(dirs.path(), &error).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        dst.copy_from(dirs.path()).unwrap();
+        assert!(symlink_metadata("sub").unwrap().is_dir());
+        assert!(Path::new("sub/a").exists());
+        assert!(!outside.path().join("a").exists());
+        drop((dst, dirs, outside));
+
+        let follow = WithDir::temp().unwrap();
+        follow.copy_from(src.path()).unwrap();
+        assert_eq!(read_to_string("link").unwrap(), "a");
+        assert!(symlink_metadata("dangling").is_err());
+    }
+}
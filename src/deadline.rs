@@ -0,0 +1,115 @@
+//! Warnings for `WithDir`s that are kept alive too long. See
+//! [WithDir::warn_after](crate::WithDir::warn_after).
+use std::{
+    backtrace::Backtrace,
+    path::PathBuf,
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+
+use crate::{observer, WithDir};
+
+static DEFAULT_DEADLINE: Mutex<Option<Duration>> = Mutex::new(None);
+
+/// Make every `WithDir` created from now on behave as if
+/// [warn_after](crate::WithDir::warn_after) was called with `limit`, or
+/// stop doing so with `None`. Useful for finding a leaked guard that is
+/// serializing a whole test run.
+pub fn set_default_deadline(limit: Option<Duration>) {
+    *DEFAULT_DEADLINE.lock() = limit;
+}
+
+pub(crate) fn default_deadline() -> Option<Duration> {
+    *DEFAULT_DEADLINE.lock()
+}
+
+pub(crate) struct Deadline {
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Deadline {
+    pub(crate) fn start(path: PathBuf, limit: Duration) -> Deadline {
+        let (stop, rx) = channel();
+        // only captures anything when RUST_BACKTRACE is set
+        let backtrace = Backtrace::capture();
+        let thread = thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(limit) {
+                eprintln!(
+                    "with_dir: WithDir for {} has been alive for more than {:?}, created at:\n{}",
+                    path.display(),
+                    limit,
+                    backtrace
+                );
+                observer::notify(|o| o.on_held_too_long(&path, limit, &backtrace));
+            }
+        });
+        Deadline {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Deadline {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl WithDir<'_> {
+    /// Print a warning to stderr, and call
+    /// [on_held_too_long](crate::DirChangeObserver::on_held_too_long) on
+    /// observers, if this instance is still alive after `limit`. The warning
+    /// includes a backtrace of this call when `RUST_BACKTRACE` is set.
+    /// Calling this again restarts the clock.
+    pub fn warn_after(&mut self, limit: Duration) {
+        self.deadline = None;
+        self.deadline = Some(Deadline::start(self.absolute_path().to_owned(), limit));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use super::*;
+    use crate::{add_observer, remove_observer, DirChangeObserver};
+
+    #[derive(Default)]
+    struct Record(Mutex<Vec<PathBuf>>);
+
+    impl DirChangeObserver for Record {
+        fn on_held_too_long(&self, path: &Path, _: Duration, _: &Backtrace) {
+            self.0.lock().push(path.to_owned());
+        }
+    }
+
+    #[test]
+    fn test_warn_after() {
+        let record = Arc::new(Record::default());
+        let observer: Arc<dyn DirChangeObserver> = record.clone();
+        add_observer(observer.clone());
+
+        let mut wd = WithDir::temp().unwrap();
+        wd.warn_after(Duration::from_secs(60));
+        drop(wd);
+
+        let mut wd = WithDir::temp().unwrap();
+        wd.warn_after(Duration::from_millis(10));
+        thread::sleep(Duration::from_millis(100));
+        let path = wd.path().to_owned();
+        drop(wd);
+
+        remove_observer(&observer);
+        let seen = record.0.lock();
+        assert!(seen.contains(&path));
+        assert_eq!(seen.len(), 1);
+    }
+}
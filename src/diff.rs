@@ -0,0 +1,206 @@
+//! Snapshots of directory contents, used by [WithDir::diff](crate::WithDir::diff)
+//! to report what changed inside a scope.
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    fs::{read, read_dir, symlink_metadata, FileType},
+    hash::{Hash, Hasher},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::WithDir;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    file_type: FileType,
+    len: u64,
+    modified: Option<SystemTime>,
+    hash: Option<u64>,
+}
+
+/// Recorded state of every entry beneath a directory. Symlinks are
+/// recorded but not followed.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    entries: BTreeMap<PathBuf, Entry>,
+    hash_contents: bool,
+}
+
+impl Snapshot {
+    /// Record the contents of `dir`. If `hash_contents` is true the content
+    /// of each file is also hashed, so modifications that keep the same
+    /// size and timestamp are still detected.
+    pub fn take(dir: impl AsRef<Path>, hash_contents: bool) -> Result<Snapshot, std::io::Error> {
+        let mut snapshot = Snapshot {
+            entries: BTreeMap::new(),
+            hash_contents,
+        };
+        snapshot.visit(dir.as_ref(), Path::new(""))?;
+        Ok(snapshot)
+    }
+
+    fn visit(&mut self, root: &Path, rel: &Path) -> Result<(), std::io::Error> {
+        for dir_entry in read_dir(root.join(rel))? {
+            let dir_entry = dir_entry?;
+            let rel = rel.join(dir_entry.file_name());
+            let meta = match symlink_metadata(root.join(&rel)) {
+                Ok(m) => m,
+                // removed while we were looking at it
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let hash = if self.hash_contents && meta.is_file() {
+                let mut hasher = DefaultHasher::new();
+                read(root.join(&rel))?.hash(&mut hasher);
+                Some(hasher.finish())
+            } else {
+                None
+            };
+            self.entries.insert(
+                rel.clone(),
+                Entry {
+                    file_type: meta.file_type(),
+                    len: meta.len(),
+                    modified: meta.modified().ok(),
+                    hash,
+                },
+            );
+            if meta.is_dir() {
+                self.visit(root, &rel)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Paths recorded in this snapshot, relative to the snapshot root
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.entries.keys().map(|p| p.as_path())
+    }
+
+    /// Hash of the recorded paths, types, sizes and (if taken with
+    /// `hash_contents`) file contents, ignoring timestamps and anything at
+    /// `skip`. Only stable for a given build of the standard library.
+    pub(crate) fn digest(&self, skip: &Path) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for (path, entry) in self.entries.iter().filter(|(p, _)| *p != skip) {
+            path.hash(&mut hasher);
+            entry.file_type.is_dir().hash(&mut hasher);
+            entry.file_type.is_symlink().hash(&mut hasher);
+            entry.len.hash(&mut hasher);
+            entry.hash.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Compare this snapshot against a later one.
+    pub fn diff(&self, later: &Snapshot) -> Diff {
+        let mut diff = Diff::default();
+        for (path, entry) in &later.entries {
+            match self.entries.get(path) {
+                None => diff.created.push(path.clone()),
+                Some(before)
+                    if before.file_type != entry.file_type
+                        || (!entry.file_type.is_dir() && before != entry) =>
+                {
+                    diff.modified.push(path.clone())
+                }
+                _ => {}
+            }
+        }
+        diff.deleted = self
+            .entries
+            .keys()
+            .filter(|p| !later.entries.contains_key(*p))
+            .cloned()
+            .collect();
+        diff
+    }
+}
+
+/// Entries that changed between two [Snapshot]s. All paths are relative to
+/// the snapshot root and sorted. Directories are only reported as created or
+/// deleted, changes to their contents are reported against the contents.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Diff {
+    pub created: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    pub deleted: Vec<PathBuf>,
+}
+
+impl Diff {
+    /// True if nothing changed
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.modified.is_empty() && self.deleted.is_empty()
+    }
+}
+
+impl<'a> WithDir<'a> {
+    /// Same as [new](crate::WithDir::new) but records a [Snapshot] of the
+    /// directory on entry so [diff](crate::WithDir::diff) can be used.
+    pub fn tracked(path: impl AsRef<Path>) -> Result<WithDir<'a>, std::io::Error> {
+        let mut wd = WithDir::new(path)?;
+        wd.track(false)?;
+        Ok(wd)
+    }
+
+    /// Record a [Snapshot] of the directory now, replacing any earlier one.
+    /// Later calls to [diff](crate::WithDir::diff) are relative to this point.
+    pub fn track(&mut self, hash_contents: bool) -> Result<(), std::io::Error> {
+        self.snapshot = Some(Snapshot::take(self.absolute_path(), hash_contents)?);
+        Ok(())
+    }
+
+    /// Report entries created, modified or deleted since this scope was
+    /// entered. Scopes from [temp](crate::WithDir::temp) and
+    /// [create](crate::WithDir::create) start out empty so always support
+    /// this, other constructors need [tracked](crate::WithDir::tracked)
+    /// or a call to [track](crate::WithDir::track) first.
+    pub fn diff(&self) -> Result<Diff, std::io::Error> {
+        let before = self.snapshot.as_ref().ok_or_else(|| {
+            std::io::Error::other(format!(
+                "no snapshot was taken of {}",
+                self.path().display()
+            ))
+        })?;
+        Ok(before.diff(&Snapshot::take(self.absolute_path(), before.hash_contents)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir, remove_file, write};
+
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let wd = WithDir::temp().unwrap();
+        write("keep", "a").unwrap();
+        write("change", "a").unwrap();
+        write("remove", "a").unwrap();
+
+        {
+            let mut inner = WithDir::new(wd.path()).unwrap();
+            assert!(inner.diff().is_err());
+            inner.track(true).unwrap();
+            assert!(inner.diff().unwrap().is_empty());
+
+            write("change", "b").unwrap();
+            remove_file("remove").unwrap();
+            create_dir("sub").unwrap();
+            write("sub/new", "a").unwrap();
+
+            let diff = inner.diff().unwrap();
+            assert_eq!(
+                diff.created,
+                [Path::new("sub"), &Path::new("sub").join("new")]
+            );
+            assert_eq!(diff.modified, [Path::new("change")]);
+            assert_eq!(diff.deleted, [Path::new("remove")]);
+        }
+
+        // temp scopes are compared against an empty directory
+        assert_eq!(wd.diff().unwrap().created.len(), 4);
+    }
+}
@@ -0,0 +1,267 @@
+//! A [CwdBackend] that checks and records directory changes without making
+//! them, see [DryRun].
+use std::{
+    collections::BTreeSet,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
+
+use parking_lot::Mutex;
+use tempfile::TempDir;
+
+use crate::{backend::Metadata, CwdBackend, OsBackend};
+
+/// A step recorded by [DryRun]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Planned {
+    /// The current directory would have been changed to this absolute path
+    ChangeDir(PathBuf),
+    /// This directory would have been made, with missing parents if
+    /// `recursive`
+    CreateDir { path: PathBuf, recursive: bool },
+    /// A temporary directory was made here, see [DryRun]
+    TempDir(PathBuf),
+    /// This directory would have been removed with everything in it
+    RemoveDirAll(PathBuf),
+}
+
+/// A [CwdBackend] for `--dry-run` modes. Directories are checked as they
+/// would be by [OsBackend], they have to exist, be directories and be
+/// readable, and each change is recorded in [plan](DryRun::plan), but the
+/// process current directory is never changed and nothing is created.
+/// Instead it keeps its own current directory, starting from the real one,
+/// so relative paths and nesting behave as they would for real. Directories
+/// it pretends to have made can be entered afterwards.
+///
+/// A [TempDir] has to exist on disk, so
+/// [WithDir::temp](crate::WithDir::temp) does make one in the system temp
+/// directory, deleted as usual, but doesn't enter it.
+///
+/// ```
+/// use std::sync::Arc;
+/// use with_dir::{dry_run::Planned, DryRun, WithDir};
+///
+/// let dry = Arc::new(DryRun::new());
+/// let cwd = std::env::current_dir().unwrap();
+/// let wd = WithDir::options()
+///     .backend(dry.clone())
+///     .create(true)
+///     .recursive(true)
+///     .enter("not/made/yet")
+///     .unwrap();
+/// assert_eq!(std::env::current_dir().unwrap(), cwd);
+/// assert!(!wd.path().exists());
+/// drop(wd);
+/// assert_eq!(
+///     dry.plan(),
+///     [
+///         Planned::CreateDir { path: cwd.join("not/made/yet"), recursive: true },
+///         Planned::ChangeDir(cwd.join("not/made/yet")),
+///         Planned::ChangeDir(cwd),
+///     ]
+/// );
+/// ```
+#[derive(Debug, Default)]
+pub struct DryRun {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// `None` until the first change, the real current directory until then
+    cwd: Option<PathBuf>,
+    /// Directories that would have been made
+    created: BTreeSet<PathBuf>,
+    plan: Vec<Planned>,
+}
+
+impl State {
+    fn cwd(&self) -> Result<PathBuf, std::io::Error> {
+        match &self.cwd {
+            Some(cwd) => Ok(cwd.clone()),
+            None => OsBackend.current_dir(),
+        }
+    }
+
+    /// `path` made absolute against the current directory, with `.` and
+    /// `..` removed lexically so it can be looked up in `created`
+    fn resolve(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        let mut out = self.cwd()?;
+        for c in path.components() {
+            match c {
+                Component::Prefix(_) | Component::RootDir => out.push(c),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    out.pop();
+                }
+                Component::Normal(n) => out.push(n),
+            }
+        }
+        Ok(out)
+    }
+
+    /// Whether `path` is a directory, really or in the plan
+    fn is_dir(&self, path: &Path) -> bool {
+        self.created.contains(path) || path.is_dir()
+    }
+}
+
+impl DryRun {
+    /// Nothing planned yet, in the real current directory
+    pub fn new() -> DryRun {
+        DryRun::default()
+    }
+
+    /// Every step so far, in order, including restoring the original
+    /// directory when a guard is dropped
+    pub fn plan(&self) -> Vec<Planned> {
+        self.state.lock().plan.clone()
+    }
+
+    /// Where the process would be now
+    pub fn cwd(&self) -> Result<PathBuf, std::io::Error> {
+        self.state.lock().cwd()
+    }
+}
+
+impl CwdBackend for DryRun {
+    fn current_dir(&self) -> Result<PathBuf, std::io::Error> {
+        self.cwd()
+    }
+
+    fn set_current_dir(&self, path: &Path) -> Result<(), std::io::Error> {
+        let mut state = self.state.lock();
+        let path = state.resolve(path)?;
+        if !state.created.contains(&path) {
+            if !std::fs::metadata(&path)?.is_dir() {
+                return Err(std::io::Error::new(
+                    ErrorKind::NotADirectory,
+                    format!("{} is not a directory", path.display()),
+                ));
+            }
+            // entering needs search permission, listing is the closest check
+            std::fs::read_dir(&path)?;
+        }
+        state.plan.push(Planned::ChangeDir(path.clone()));
+        state.cwd = Some(path);
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &Path, recursive: bool) -> Result<(), std::io::Error> {
+        let mut state = self.state.lock();
+        let path = state.resolve(path)?;
+        if state.is_dir(&path) {
+            if recursive {
+                return Ok(());
+            }
+            return Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            ));
+        }
+        if path.exists() {
+            return Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and is not a directory", path.display()),
+            ));
+        }
+        let parent = path.parent().unwrap_or(&path).to_owned();
+        if !recursive && !state.is_dir(&parent) {
+            return Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("{} does not exist", parent.display()),
+            ));
+        }
+        for a in path
+            .ancestors()
+            .take_while(|a| !state.is_dir(a))
+            .collect::<Vec<_>>()
+        {
+            state.created.insert(a.to_owned());
+        }
+        state.plan.push(Planned::CreateDir { path, recursive });
+        Ok(())
+    }
+
+    fn temp_dir(&self, prefix: &str) -> Result<TempDir, std::io::Error> {
+        let temp_dir = OsBackend.temp_dir(prefix)?;
+        self.state
+            .lock()
+            .plan
+            .push(Planned::TempDir(temp_dir.path().to_owned()));
+        Ok(temp_dir)
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata, std::io::Error> {
+        let state = self.state.lock();
+        let path = state.resolve(path)?;
+        match state.created.contains(&path) {
+            true => Ok(Metadata { is_dir: true }),
+            false => std::fs::metadata(&path).map(Metadata::from),
+        }
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        let state = self.state.lock();
+        let path = state.resolve(path)?;
+        match state.created.contains(&path) {
+            // nothing to resolve in a directory that doesn't exist
+            true => Ok(path),
+            false => std::fs::canonicalize(&path),
+        }
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<(), std::io::Error> {
+        let mut state = self.state.lock();
+        let path = state.resolve(path)?;
+        if !state.is_dir(&path) {
+            std::fs::metadata(&path)?;
+        }
+        state.created.retain(|d| !d.starts_with(&path));
+        state.plan.push(Planned::RemoveDirAll(path));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::WithDir;
+
+    #[test]
+    fn test_dry_run() {
+        let tmp = WithDir::temp().unwrap();
+        std::fs::write("file", "").unwrap();
+        let dry = Arc::new(DryRun::new());
+        let options = || WithDir::options().backend(dry.clone());
+
+        let wd = options().create(true).recursive(true).enter("a/b").unwrap();
+        let inner = options().enter("..").unwrap();
+        assert_eq!(dry.cwd().unwrap(), tmp.path().join("a"));
+        drop(inner);
+        drop(wd);
+        assert!(tmp.is_current());
+        assert!(!Path::new("a").exists());
+        assert_eq!(
+            dry.plan(),
+            [
+                Planned::CreateDir {
+                    path: tmp.path().join("a/b"),
+                    recursive: true
+                },
+                Planned::ChangeDir(tmp.path().join("a/b")),
+                Planned::ChangeDir(tmp.path().join("a")),
+                Planned::ChangeDir(tmp.path().join("a/b")),
+                Planned::ChangeDir(tmp.path().to_owned()),
+            ]
+        );
+
+        let err = options().enter("file").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+        assert!(options().enter("missing").is_err());
+        assert!(options().create(true).enter("x/y").is_err());
+        assert_eq!(dry.plan().len(), 5);
+    }
+}
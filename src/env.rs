@@ -0,0 +1,218 @@
+//! Environment describing a [WithDir](crate::WithDir) scope, for handing to
+//! child processes.
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    process::Command,
+};
+
+use crate::{Cwd, WithDir};
+
+/// The variables pointed at a temporary directory by
+/// [export_tmpdir](crate::WithDir::export_tmpdir)
+pub(crate) const TMPDIR_VARS: &[&str] = if cfg!(windows) {
+    &["TMP", "TEMP"]
+} else {
+    &["TMPDIR"]
+};
+
+/// A variable of the current process's environment as it was before a
+/// `WithDir` set it
+#[derive(Debug)]
+pub(crate) struct SavedVar {
+    key: &'static str,
+    value: Option<OsString>,
+}
+
+impl SavedVar {
+    /// Put the variable back, removing it if it wasn't set
+    fn restore(self) {
+        match self.value {
+            Some(v) => std::env::set_var(self.key, v),
+            None => std::env::remove_var(self.key),
+        }
+    }
+}
+
+impl WithDir<'_> {
+    /// Set `key` in the current process's environment until this instance
+    /// is dropped
+    pub(crate) fn set_process_var(&mut self, key: &'static str, value: impl AsRef<OsStr>) {
+        if !self.has_process_var(key) {
+            let value = std::env::var_os(key);
+            self.saved_vars.push(SavedVar { key, value });
+        }
+        std::env::set_var(key, value);
+    }
+
+    /// Whether `key` was set by [set_process_var](WithDir::set_process_var)
+    pub(crate) fn has_process_var(&self, key: &str) -> bool {
+        self.saved_vars.iter().any(|v| v.key == key)
+    }
+
+    /// Put back the variables set by
+    /// [set_process_var](WithDir::set_process_var)
+    pub(crate) fn restore_process_vars(&mut self) {
+        while let Some(var) = self.saved_vars.pop() {
+            var.restore();
+        }
+    }
+
+    /// Point `TMPDIR` (`TMP` and `TEMP` on Windows) in the current
+    /// process's environment at this directory until this instance is
+    /// dropped. For a [temp](crate::WithDir::temp) directory this means
+    /// scratch files made by child processes, and by this process through
+    /// [std::env::temp_dir], are cleaned up with it. Calling it again does
+    /// nothing.
+    ///
+    /// Like any change to the environment this races with other threads
+    /// reading it, so only use it where they don't.
+    ///
+    /// ```
+    /// use with_dir::WithDir;
+    ///
+    /// let before = std::env::temp_dir();
+    /// let mut wd = WithDir::temp().unwrap();
+    /// wd.export_tmpdir();
+    /// let scratch = tempfile::tempdir().unwrap();
+    /// assert!(scratch.path().starts_with(wd.path()));
+    /// drop(scratch);
+    /// wd.leave().unwrap();
+    /// assert_eq!(std::env::temp_dir(), before);
+    /// ```
+    pub fn export_tmpdir(&mut self) -> &mut Self {
+        let path = self.absolute_path().to_owned();
+        for key in TMPDIR_VARS {
+            self.set_process_var(key, &path);
+        }
+        self
+    }
+
+    /// Add a variable to the scope's environment, see
+    /// [to_env_map](crate::WithDir::to_env_map). This does not modify the
+    /// environment of the current process.
+    pub fn set_env(&mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> &mut Self {
+        self.env
+            .insert(key.as_ref().to_owned(), value.as_ref().to_owned());
+        self
+    }
+
+    /// The environment variables that represent this scope: `PWD` is the
+    /// absolute path of the directory, `TMPDIR` is set for scopes created with
+    /// [temp](crate::WithDir::temp), and anything added with
+    /// [set_env](crate::WithDir::set_env) overrides both.
+    pub fn to_env_map(&self) -> BTreeMap<OsString, OsString> {
+        let mut env = BTreeMap::new();
+        env.insert("PWD".into(), self.absolute_path().into());
+        if let Cwd::Temp(t) = &self.cwd {
+            env.insert("TMPDIR".into(), t.path().as_os_str().to_owned());
+        }
+        env.extend(self.env.clone());
+        env
+    }
+
+    /// Set the working directory and [to_env_map](crate::WithDir::to_env_map)
+    /// variables on `command`, so it runs inside this scope regardless of the
+    /// current directory when it is spawned.
+    pub fn apply_to<'c>(&self, command: &'c mut Command) -> &'c mut Command {
+        command
+            .current_dir(self.absolute_path())
+            .envs(self.to_env_map())
+    }
+
+    /// A [Command] for `program` that runs in this directory with the
+    /// variables from [to_env_map](crate::WithDir::to_env_map). The directory
+    /// is fixed when the command is created, so it doesn't matter what the
+    /// current directory is when it is spawned, or if this `WithDir` has
+    /// been dropped by then.
+    pub fn command(&self, program: impl AsRef<OsStr>) -> Command {
+        let mut command = Command::new(program);
+        self.apply_to(&mut command);
+        command
+    }
+
+    /// Run `command_line` with `sh -c` (`cmd /C` on Windows) in this
+    /// directory, see [command](crate::WithDir::command), and wait for it
+    /// to finish. Stdout and stderr are captured in the returned
+    /// [Output](std::process::Output).
+    pub fn shell(&self, command_line: &str) -> Result<std::process::Output, std::io::Error> {
+        let mut command = if cfg!(windows) {
+            let mut c = self.command("cmd");
+            c.arg("/C");
+            c
+        } else {
+            let mut c = self.command("sh");
+            c.arg("-c");
+            c
+        };
+        command.arg(command_line).output()
+    }
+
+    /// Launch an interactive shell inside this scope and block until it exits.
+    /// Uses `$SHELL` (`%COMSPEC%` on Windows), falling back to `sh`/`cmd`.
+    /// Handy for poking around a failing test's directory before it is cleaned up.
+    /// Requires the `debug-shell` feature.
+    #[cfg(feature = "debug-shell")]
+    pub fn debug_shell(&self) -> Result<std::process::ExitStatus, std::io::Error> {
+        let (var, fallback) = if cfg!(windows) {
+            ("COMSPEC", "cmd")
+        } else {
+            ("SHELL", "sh")
+        };
+        let shell = std::env::var_os(var).unwrap_or_else(|| fallback.into());
+        eprintln!(
+            "with_dir: starting {:?} in {}",
+            shell,
+            self.path().display()
+        );
+        self.apply_to(&mut Command::new(shell)).status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_env_map() {
+        let mut wd = WithDir::temp().unwrap();
+        wd.set_env("FOO", "bar");
+        let env = wd.to_env_map();
+        assert_eq!(Path::new(&env[OsStr::new("PWD")]), wd.path());
+        assert_eq!(Path::new(&env[OsStr::new("TMPDIR")]), wd.path());
+        assert_eq!(env[OsStr::new("FOO")], "bar");
+
+        let mut cmd = Command::new("true");
+        wd.apply_to(&mut cmd);
+        assert_eq!(cmd.get_current_dir(), Some(wd.path()));
+        assert_eq!(cmd.get_envs().count(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command() {
+        let tmp = WithDir::temp().unwrap();
+        let wd = WithDir::new(tmp.path()).unwrap();
+        let mut cmd = wd.command("pwd");
+        wd.leave().unwrap();
+        let expected = std::fs::canonicalize(tmp.path()).unwrap();
+        let _elsewhere = WithDir::new(std::env::temp_dir()).unwrap();
+
+        let out = cmd.output().unwrap();
+        let pwd = String::from_utf8(out.stdout).unwrap();
+        assert_eq!(std::fs::canonicalize(pwd.trim()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_shell() {
+        let wd = WithDir::temp().unwrap();
+        let _elsewhere = WithDir::new(std::env::temp_dir()).unwrap();
+        let out = wd.shell("echo hello > out.txt && echo done").unwrap();
+        assert!(out.status.success());
+        assert!(String::from_utf8_lossy(&out.stdout).starts_with("done"));
+        assert!(wd.path().join("out.txt").exists());
+        assert!(!wd.shell("exit 3").unwrap().status.success());
+    }
+}
@@ -0,0 +1,214 @@
+//! Saying which step of entering a directory failed, see [EnterError], and
+//! who held the directory lock when waiting for it timed out, see
+//! [LockTimeout].
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{registry, ActiveGuard};
+
+/// The step of entering a directory that failed, see [EnterError]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Operation {
+    /// Reading the current directory, to restore later
+    CurrentDir,
+    /// Creating the directory
+    CreateDir,
+    /// Creating a temporary directory, `path` is where
+    TempDir,
+    /// Changing to the directory
+    SetCurrentDir,
+}
+
+/// Context for an error entering a directory. Constructors return it inside
+/// their [std::io::Error], which keeps the [kind](std::io::Error::kind) of
+/// the underlying error, so the message says what was being done and
+/// where. Get it back with [get_ref](std::io::Error::get_ref) and
+/// `downcast_ref`, and the underlying error with
+/// [source](std::error::Error::source).
+///
+/// ```
+/// use with_dir::{EnterError, Operation, WithDir};
+///
+/// let err = WithDir::new("no/such/dir").unwrap_err();
+/// assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+/// let context = err.get_ref().unwrap().downcast_ref::<EnterError>().unwrap();
+/// assert_eq!(context.operation, Operation::SetCurrentDir);
+/// assert!(err.to_string().starts_with("can't change to no/such/dir from "));
+/// ```
+#[derive(Debug)]
+pub struct EnterError {
+    /// What failed
+    pub operation: Operation,
+    /// The directory being entered, as given, if known by then
+    pub path: Option<PathBuf>,
+    /// The directory that was current, if it could be read
+    pub original: Option<PathBuf>,
+    source: std::io::Error,
+}
+
+impl std::fmt::Display for EnterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let path = self.path.as_deref().unwrap_or(Path::new("?"));
+        match self.operation {
+            Operation::CurrentDir => write!(f, "can't read the current directory")?,
+            Operation::CreateDir => write!(f, "can't create {}", path.display())?,
+            Operation::TempDir => write!(
+                f,
+                "can't create a temporary directory in {}",
+                path.display()
+            )?,
+            Operation::SetCurrentDir => write!(f, "can't change to {}", path.display())?,
+        }
+        if let Some(original) = &self.original {
+            write!(f, " from {}", original.display())?;
+        }
+        write!(f, ": {}", self.source)
+    }
+}
+
+impl std::error::Error for EnterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Why a [lock_timeout](crate::Options::lock_timeout) expired. Returned
+/// inside a [TimedOut](std::io::ErrorKind::TimedOut) [std::io::Error], get
+/// it back with [get_ref](std::io::Error::get_ref) and `downcast_ref`. The
+/// message says who holds the directory, for instance
+/// `timed out after 1s waiting for the directory lock, held by thread
+/// "tests::slow" (ThreadId(3)) in /tmp/.tmpXyZ [fixture] for 12.5s`.
+#[derive(Clone, Debug)]
+pub struct LockTimeout {
+    /// How long it waited
+    pub waited: Duration,
+    /// The outermost `WithDir` alive on another thread when it gave up,
+    /// which is normally what holds the lock. `None` if there was none, as
+    /// when the lock is held by [lock](crate::lock).
+    pub holder: Option<ActiveGuard>,
+}
+
+impl std::fmt::Display for LockTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "timed out after {:?} waiting for the directory lock",
+            self.waited
+        )?;
+        let Some(holder) = &self.holder else {
+            return write!(f, ", held by something other than a WithDir");
+        };
+        write!(f, ", held by thread ")?;
+        if let Some(name) = &holder.thread_name {
+            write!(f, "{:?} ", name)?;
+        }
+        write!(f, "({:?}) in {}", holder.thread, holder.path.display())?;
+        if let Some(label) = &holder.label {
+            write!(f, " [{}]", label)?;
+        }
+        if let Ok(held) = holder.created.elapsed() {
+            write!(f, " for {:.1?}", held)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LockTimeout {}
+
+impl LockTimeout {
+    /// A [TimedOut](std::io::ErrorKind::TimedOut) error for having waited
+    /// `waited`, naming whoever holds the lock now
+    pub(crate) fn error(waited: Duration) -> std::io::Error {
+        let timeout = LockTimeout {
+            waited,
+            holder: registry::holder(),
+        };
+        std::io::Error::new(std::io::ErrorKind::TimedOut, timeout)
+    }
+}
+
+/// Adds an [EnterError] to failures while entering a directory
+pub(crate) trait Context<T> {
+    /// Reading the current directory failed
+    fn reading_cwd(self) -> Result<T, std::io::Error>;
+
+    /// `operation` on `path`, while in `original`, failed
+    fn entering(
+        self,
+        operation: Operation,
+        path: &Path,
+        original: &Path,
+    ) -> Result<T, std::io::Error>;
+}
+
+impl<T> Context<T> for Result<T, std::io::Error> {
+    fn reading_cwd(self) -> Result<T, std::io::Error> {
+        self.map_err(|e| wrap(Operation::CurrentDir, None, None, e))
+    }
+
+    fn entering(
+        self,
+        operation: Operation,
+        path: &Path,
+        original: &Path,
+    ) -> Result<T, std::io::Error> {
+        self.map_err(|e| wrap(operation, Some(path), Some(original), e))
+    }
+}
+
+fn wrap(
+    operation: Operation,
+    path: Option<&Path>,
+    original: Option<&Path>,
+    source: std::io::Error,
+) -> std::io::Error {
+    // already has context, from a constructor this one is built on
+    if source.get_ref().is_some_and(|e| e.is::<EnterError>()) {
+        return source;
+    }
+    let kind = source.kind();
+    std::io::Error::new(
+        kind,
+        EnterError {
+            operation,
+            path: path.map(Path::to_owned),
+            original: original.map(Path::to_owned),
+            source,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, io::ErrorKind};
+
+    use super::*;
+    use crate::WithDir;
+
+    #[test]
+    fn test_enter_error() {
+        let tmp = WithDir::temp().unwrap();
+        std::fs::write("file", "").unwrap();
+        let err = WithDir::create("file").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        let context = err.get_ref().unwrap().downcast_ref::<EnterError>().unwrap();
+        assert_eq!(context.operation, Operation::CreateDir);
+        assert_eq!(context.path.as_deref(), Some(Path::new("file")));
+        assert_eq!(context.original.as_deref(), Some(tmp.path()));
+        let source = context.source().unwrap();
+        let source = source.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(source.kind(), ErrorKind::AlreadyExists);
+
+        // not wrapped twice by constructors built on others
+        let err = WithDir::new_or_create("file").unwrap_err();
+        let context = err.get_ref().unwrap().downcast_ref::<EnterError>().unwrap();
+        assert!(context
+            .source()
+            .unwrap()
+            .downcast_ref::<EnterError>()
+            .is_none());
+    }
+}
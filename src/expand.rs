@@ -0,0 +1,130 @@
+//! Shell style expansion of `~` and environment variables in paths given by
+//! users, see [expand](crate::expand::expand).
+use std::{io::ErrorKind, path::PathBuf};
+
+use crate::WithDir;
+
+fn invalid(msg: String) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidInput, msg)
+}
+
+/// Expand `input` using `lookup` to find variables:
+///
+/// * `~` on its own or followed by `/` (or `\` on Windows) at the start is
+///   replaced with `HOME`, or `USERPROFILE` if that isn't set. `~user` is
+///   left alone.
+/// * `$NAME` and `${NAME}` are replaced with the variable `NAME`, where a
+///   name is letters, digits and `_`.
+/// * `$$` is a literal `$`, as is a `$` not followed by a name.
+///
+/// It is an error if a variable, or the home directory, isn't set, rather
+/// than silently expanding to nothing. Pass a custom `lookup` to expand
+/// from somewhere other than the process environment.
+///
+/// ```
+/// use with_dir::expand::expand;
+///
+/// let vars = |name: &str| (name == "PROJECT").then(|| "demo".to_string());
+/// assert_eq!(expand("build/$PROJECT/${PROJECT}_out", vars).unwrap(),
+///            std::path::Path::new("build/demo/demo_out"));
+/// assert!(expand("$MISSING", vars).is_err());
+/// ```
+pub fn expand(
+    input: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<PathBuf, std::io::Error> {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    let after_tilde = rest
+        .strip_prefix('~')
+        .filter(|r| r.is_empty() || r.starts_with('/') || (cfg!(windows) && r.starts_with('\\')));
+    if let Some(r) = after_tilde {
+        let home = lookup("HOME")
+            .or_else(|| lookup("USERPROFILE"))
+            .ok_or_else(|| invalid(format!("can't expand ~ in {}, no home directory", input)))?;
+        out.push_str(&home);
+        rest = r;
+    }
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        let (name, after) = if let Some(r) = rest.strip_prefix('{') {
+            let end = r
+                .find('}')
+                .ok_or_else(|| invalid(format!("unclosed ${{ in {}", input)))?;
+            (&r[..end], &r[end + 1..])
+        } else if let Some(r) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = r;
+            continue;
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        if name.is_empty() {
+            out.push('$');
+            continue;
+        }
+        let value = lookup(name)
+            .ok_or_else(|| invalid(format!("can't expand ${} in {}, it isn't set", name, input)))?;
+        out.push_str(&value);
+        rest = after;
+    }
+    out.push_str(rest);
+    Ok(PathBuf::from(out))
+}
+
+/// [expand] using the process environment
+pub fn expand_env(input: &str) -> Result<PathBuf, std::io::Error> {
+    expand(input, |name| std::env::var(name).ok())
+}
+
+impl<'a> WithDir<'a> {
+    /// [new](crate::WithDir::new) after expanding `~` and environment
+    /// variables in `input`, see [expand](crate::expand::expand) for the
+    /// rules. Intended for directories given by users, like a CLI's
+    /// `--directory` option.
+    pub fn new_expanded(input: &str) -> Result<WithDir<'a>, std::io::Error> {
+        WithDir::new(expand_env(input)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn vars(name: &str) -> Option<String> {
+        match name {
+            "HOME" => Some("/home/me".into()),
+            "A" => Some("x".into()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        let e = |s| expand(s, vars).unwrap();
+        assert_eq!(e("~"), Path::new("/home/me"));
+        assert_eq!(e("~/p/$A"), Path::new("/home/me/p/x"));
+        assert_eq!(e("~other/a"), Path::new("~other/a"));
+        assert_eq!(e("a~/$A.${A}_$$"), Path::new("a~/x.x_$"));
+        assert_eq!(e("cost $ 5"), Path::new("cost $ 5"));
+        assert!(expand("$B", vars).is_err());
+        assert!(expand("${A", vars).is_err());
+        assert!(expand("~", |_| None).is_err());
+    }
+
+    #[test]
+    fn test_new_expanded() {
+        let tmp = WithDir::temp().unwrap();
+        std::fs::create_dir("sub").unwrap();
+        std::env::set_var("WITH_DIR_TEST_EXPAND", tmp.path());
+        let wd = WithDir::new_expanded("$WITH_DIR_TEST_EXPAND/sub").unwrap();
+        assert!(wd.is_current());
+        assert_eq!(wd.path(), tmp.path().join("sub"));
+    }
+}
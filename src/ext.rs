@@ -0,0 +1,112 @@
+//! Extension traits for entering directories from the types that name
+//! them, see [ScopedChdir] and [TempDirExt].
+use std::path::Path;
+
+use tempfile::TempDir;
+
+use crate::{chdir, current_dir, error::Context, lock_to_enter, Cwd, Operation, WithDir};
+
+/// Enter a directory straight from its [Path], or a
+/// [PathBuf](std::path::PathBuf) through deref, which reads more naturally
+/// in fluent test code than [WithDir::new].
+///
+/// ```
+/// use std::path::Path;
+/// use with_dir::ScopedChdir;
+///
+/// let found = Path::new("src").as_cwd(|_| Path::new("lib.rs").exists()).unwrap();
+/// assert!(found);
+///
+/// let wd = Path::new("src").enter_scoped().unwrap();
+/// assert!(wd.is_current());
+/// ```
+pub trait ScopedChdir {
+    /// Same as [WithDir::new] for this path
+    fn enter_scoped<'a>(&self) -> Result<WithDir<'a>, std::io::Error>;
+
+    /// Run `f` with this path as the current directory, returning what it
+    /// returns. The original directory is restored before returning, an
+    /// error restoring it is returned rather than panicking.
+    fn as_cwd<R>(&self, f: impl FnOnce(&WithDir) -> R) -> Result<R, std::io::Error>;
+}
+
+impl ScopedChdir for Path {
+    fn enter_scoped<'a>(&self) -> Result<WithDir<'a>, std::io::Error> {
+        WithDir::new(self)
+    }
+
+    fn as_cwd<R>(&self, f: impl FnOnce(&WithDir) -> R) -> Result<R, std::io::Error> {
+        let wd = WithDir::new(self)?;
+        let ret = f(&wd);
+        wd.leave()?;
+        Ok(ret)
+    }
+}
+
+/// Enter a [TempDir] made some other way, for example with a custom
+/// [tempfile::Builder], handing it to the guard
+pub trait TempDirExt {
+    /// Change to this directory. The returned `WithDir` owns it and deletes
+    /// it when dropped, as one made by [temp](crate::WithDir::temp) does.
+    /// If the directory can't be entered it is deleted straight away.
+    ///
+    /// ```
+    /// use with_dir::TempDirExt;
+    ///
+    /// let tmp = tempfile::Builder::new().prefix("fixture-").tempdir().unwrap();
+    /// let wd = tmp.enter().unwrap();
+    /// let path = wd.path().to_owned();
+    /// drop(wd);
+    /// assert!(!path.exists());
+    /// ```
+    fn enter<'a>(self) -> Result<WithDir<'a>, std::io::Error>;
+}
+
+impl TempDirExt for TempDir {
+    fn enter<'a>(self) -> Result<WithDir<'a>, std::io::Error> {
+        let m = lock_to_enter()?;
+        let original_dir = current_dir()?;
+        chdir(self.path()).entering(Operation::SetCurrentDir, self.path(), &original_dir)?;
+        Ok(WithDir::from_parts(m, original_dir, Cwd::Temp(self)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_scoped_chdir() {
+        let tmp = WithDir::temp().unwrap();
+        let dir = PathBuf::from("a");
+        std::fs::create_dir(&dir).unwrap();
+        let cwd = dir.as_cwd(|wd| wd.path().to_owned()).unwrap();
+        assert_eq!(cwd, tmp.path().join("a"));
+        assert!(tmp.is_current());
+        {
+            let wd = dir.enter_scoped().unwrap();
+            assert!(wd.is_current());
+        }
+        assert!(tmp.is_current());
+        assert!(Path::new("missing").as_cwd(|_| ()).is_err());
+    }
+
+    #[test]
+    fn test_temp_dir_enter() {
+        let tmp = WithDir::temp().unwrap();
+        let dir = tempfile::Builder::new()
+            .prefix("custom-")
+            .tempdir_in(tmp.path())
+            .unwrap();
+        let wd = dir.enter().unwrap();
+        assert!(wd.is_current());
+        assert_eq!(wd.original_path(), tmp.path());
+        let left = wd.leave_into().unwrap();
+        assert!(left.temp_dir.is_some());
+        assert!(left.path.exists());
+        drop(left);
+        assert_eq!(std::fs::read_dir(".").unwrap().count(), 0);
+    }
+}
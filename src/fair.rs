@@ -0,0 +1,114 @@
+//! Serving waiters for the directory lock in the order they arrived. See
+//! [set_fair_locking](crate::set_fair_locking).
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use parking_lot::{Condvar, Mutex, ReentrantMutexGuard};
+
+use crate::DIR_MUTEX;
+
+static FAIR: AtomicBool = AtomicBool::new(false);
+
+/// Tickets of the threads waiting for the lock, in arrival order
+struct Queue {
+    next: u64,
+    waiting: VecDeque<u64>,
+}
+
+static QUEUE: Mutex<Queue> = Mutex::new(Queue {
+    next: 0,
+    waiting: VecDeque::new(),
+});
+
+/// Signalled whenever the front of [QUEUE] changes
+static TURN: Condvar = Condvar::new();
+
+/// Hand out the lock `WithDir` uses in the order threads asked for it, or
+/// go back to the default with `false`. By default the lock is unfair, a
+/// thread that releases it and asks again straight away usually gets it
+/// back, which is faster but can starve other threads under heavy
+/// contention, for instance a large test suite where every test enters a
+/// directory. A thread that already holds the lock doesn't queue to take
+/// it again.
+///
+/// ```
+/// with_dir::set_fair_locking(true);
+/// let wd = with_dir::WithDir::temp().unwrap();
+/// # with_dir::set_fair_locking(false);
+/// ```
+pub fn set_fair_locking(fair: bool) {
+    FAIR.store(fair, Ordering::Relaxed);
+}
+
+pub(crate) fn is_fair() -> bool {
+    FAIR.load(Ordering::Relaxed)
+}
+
+/// Lock [DIR_MUTEX] once every thread that asked before this one has had
+/// it, giving up after `timeout` if there is one
+pub(crate) fn lock_in_turn(timeout: Option<Duration>) -> Option<ReentrantMutexGuard<'static, ()>> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let mut queue = QUEUE.lock();
+    let ticket = queue.next;
+    queue.next += 1;
+    queue.waiting.push_back(ticket);
+    while queue.waiting.front() != Some(&ticket) {
+        let timed_out = match deadline {
+            Some(d) => TURN.wait_until(&mut queue, d).timed_out(),
+            None => {
+                TURN.wait(&mut queue);
+                false
+            }
+        };
+        if timed_out && queue.waiting.front() != Some(&ticket) {
+            queue.waiting.retain(|t| *t != ticket);
+            return None;
+        }
+    }
+    drop(queue);
+    // first in line, so only the holder is in the way
+    let guard = match deadline {
+        Some(d) => DIR_MUTEX.try_lock_until(d),
+        None => Some(DIR_MUTEX.lock()),
+    };
+    QUEUE.lock().waiting.pop_front();
+    TURN.notify_all();
+    guard
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    // uses the queue directly, since turning fair locking on for the
+    // process would change how every other test waits
+    #[test]
+    fn test_fair_locking() {
+        let lock = crate::lock();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let order = order.clone();
+                let t = thread::spawn(move || {
+                    let _guard = lock_in_turn(None).unwrap();
+                    order.lock().push(i);
+                });
+                // let it join the queue before the next one
+                thread::sleep(Duration::from_millis(50));
+                t
+            })
+            .collect();
+        // a waiter that times out leaves the queue without holding it up
+        assert!(lock_in_turn(Some(Duration::from_millis(10))).is_none());
+        drop(lock);
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*order.lock(), [0, 1, 2, 3]);
+    }
+}
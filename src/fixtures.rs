@@ -0,0 +1,52 @@
+//! [rstest](https://docs.rs/rstest) fixtures, enabled with the `rstest`
+//! feature.
+//!
+//! ```
+//! use rstest::rstest;
+//! use with_dir::{fixtures::temp_cwd, WithDir};
+//!
+//! #[rstest]
+//! fn writes_output(temp_cwd: WithDir) {
+//!     std::fs::write("out.txt", "").unwrap();
+//!     temp_cwd.assert_exists("out.txt");
+//! }
+//! ```
+use rstest::fixture;
+
+use crate::WithDir;
+
+/// A fresh [temp](crate::WithDir::temp) working directory, kept if the
+/// test panics.
+#[fixture]
+pub fn temp_cwd() -> WithDir<'static> {
+    let mut wd = WithDir::temp().expect("failed to create temporary working directory");
+    wd.keep_on_panic();
+    wd
+}
+
+/// The working directory set to `path`, which defaults to the current
+/// directory. Pass the directory with `#[with("tests/data")]`.
+#[fixture]
+pub fn fixture_cwd(#[default(".")] path: &str) -> WithDir<'static> {
+    WithDir::new(path).unwrap_or_else(|e| panic!("failed to enter {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::current_dir;
+
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    fn test_temp_cwd(temp_cwd: WithDir) {
+        assert_eq!(current_dir().unwrap(), temp_cwd.path());
+    }
+
+    #[rstest]
+    fn test_fixture_cwd(temp_cwd: WithDir, #[from(fixture_cwd)] src: WithDir) {
+        assert_eq!(src.path(), temp_cwd.path());
+        assert_eq!(current_dir().unwrap(), temp_cwd.path());
+    }
+}
@@ -0,0 +1,271 @@
+//! File operations relative to a [WithDir](crate::WithDir). These resolve
+//! paths against the guard's directory rather than the current working
+//! directory, so they behave the same whatever the cwd is.
+use std::{
+    borrow::Cow,
+    fs::{self, File},
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::WithDir;
+
+/// `path` without the `\\?\` verbatim prefix that Windows adds when
+/// canonicalizing, if the result means the same thing without it: a drive
+/// path short enough for the legacy APIs with no reserved device names or
+/// trailing dots or spaces in its components. `\\?\UNC\` becomes `\\`.
+/// `None` for anything else.
+pub(crate) fn strip_verbatim(path: &str) -> Option<String> {
+    if let Some(rest) = path.strip_prefix(r"\\?\UNC\") {
+        return Some(format!(r"\\{}", rest));
+    }
+    let rest = path.strip_prefix(r"\\?\")?;
+    let bytes = rest.as_bytes();
+    if rest.len() >= 260
+        || bytes.len() < 3
+        || !bytes[0].is_ascii_alphabetic()
+        || &bytes[1..3] != b":\\"
+    {
+        return None;
+    }
+    const RESERVED: &[&str] = &[
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+        "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+    let ok = rest[3..].split('\\').filter(|c| !c.is_empty()).all(|c| {
+        let stem = c.split('.').next().unwrap_or(c).trim_end();
+        !c.ends_with(['.', ' ']) && !RESERVED.iter().any(|r| r.eq_ignore_ascii_case(stem))
+    });
+    ok.then(|| rest.to_owned())
+}
+
+/// Verbatim form of an absolute Windows path, `\\?\C:\...` or
+/// `\\?\UNC\server\...`. `None` if it already is one or isn't absolute.
+pub(crate) fn to_verbatim(path: &str) -> Option<String> {
+    if path.starts_with(r"\\?\") || path.starts_with(r"\\.\") {
+        return None;
+    }
+    if let Some(rest) = path.strip_prefix(r"\\") {
+        return Some(format!(r"\\?\UNC\{}", rest));
+    }
+    let bytes = path.as_bytes();
+    if bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && bytes[2] == b'\\' {
+        return Some(format!(r"\\?\{}", path));
+    }
+    None
+}
+
+/// On Windows, paths of `MAX_PATH` (260) characters or more are made
+/// absolute and given the `\\?\` prefix so the file system APIs accept
+/// them. Anything else, and every path on other platforms, is returned
+/// as is.
+pub(crate) fn long_path(path: &Path) -> Cow<'_, Path> {
+    if !cfg!(windows) || path.as_os_str().len() < 260 && !path.is_relative() {
+        return Cow::Borrowed(path);
+    }
+    // `absolute` also resolves `.` and `..`, which verbatim paths don't
+    let Ok(abs) = std::path::absolute(path) else {
+        return Cow::Borrowed(path);
+    };
+    if abs.as_os_str().len() < 260 {
+        return Cow::Borrowed(path);
+    }
+    match abs.to_str().and_then(to_verbatim) {
+        Some(v) => Cow::Owned(v.into()),
+        None => Cow::Owned(abs),
+    }
+}
+
+impl WithDir<'_> {
+    /// `rel` joined onto the absolute path of this directory. Absolute
+    /// `rel` are returned unchanged.
+    pub fn resolve(&self, rel: impl AsRef<Path>) -> PathBuf {
+        self.absolute_path().join(rel)
+    }
+
+    /// See [std::fs::canonicalize]
+    pub fn canonicalize(&self, rel: impl AsRef<Path>) -> Result<PathBuf, std::io::Error> {
+        fs::canonicalize(self.resolve(rel))
+    }
+
+    /// Canonical absolute path of this directory. On Windows the `\\?\`
+    /// prefix is removed where that doesn't change the meaning, so the
+    /// result can be compared with [current_dir](std::env::current_dir)
+    /// and paths written by hand.
+    pub fn canonical_path(&self) -> Result<PathBuf, std::io::Error> {
+        let path = self.canonicalize("")?;
+        if cfg!(windows) {
+            if let Some(stripped) = path.to_str().and_then(strip_verbatim) {
+                return Ok(stripped.into());
+            }
+        }
+        Ok(path)
+    }
+
+    /// Like [canonicalize](crate::WithDir::canonicalize) but `rel` doesn't
+    /// need to exist. The longest existing prefix is canonicalized and the
+    /// rest is appended, with `.` and `..` in the rest resolved lexically.
+    /// Useful for output paths that haven't been written yet.
+    pub fn soft_canonicalize(&self, rel: impl AsRef<Path>) -> Result<PathBuf, std::io::Error> {
+        let full = self.resolve(rel);
+        let components: Vec<_> = full.components().collect();
+        let mut split = components.len();
+        let mut base = loop {
+            let prefix: PathBuf = components[..split].iter().collect();
+            match fs::canonicalize(&prefix) {
+                Ok(p) => break p,
+                Err(e) if e.kind() == ErrorKind::NotFound && split > 1 => split -= 1,
+                Err(e) => return Err(e),
+            }
+        };
+        for component in &components[split..] {
+            match component {
+                Component::ParentDir => {
+                    base.pop();
+                }
+                Component::Normal(name) => base.push(name),
+                _ => {}
+            }
+        }
+        Ok(base)
+    }
+
+    /// See [std::fs::read_to_string]
+    pub fn read_to_string(&self, rel: impl AsRef<Path>) -> Result<String, std::io::Error> {
+        fs::read_to_string(self.resolve(rel))
+    }
+
+    /// See [std::fs::write]
+    pub fn write(
+        &self,
+        rel: impl AsRef<Path>,
+        contents: impl AsRef<[u8]>,
+    ) -> Result<(), std::io::Error> {
+        fs::write(self.resolve(rel), contents)
+    }
+
+    /// See [std::fs::File::open]
+    pub fn open(&self, rel: impl AsRef<Path>) -> Result<File, std::io::Error> {
+        File::open(self.resolve(rel))
+    }
+
+    /// See [std::fs::File::create]
+    pub fn create_file(&self, rel: impl AsRef<Path>) -> Result<File, std::io::Error> {
+        File::create(self.resolve(rel))
+    }
+
+    /// Remove everything inside this directory, leaving the directory itself.
+    /// Symlinks are removed, not followed. Returns the top level entries that
+    /// were removed, relative to the directory.
+    pub fn clear(&self) -> Result<Vec<PathBuf>, std::io::Error> {
+        let removed = self.clear_dry_run()?;
+        for rel in &removed {
+            let path = self.resolve(rel);
+            if fs::symlink_metadata(&path)?.is_dir() {
+                fs::remove_dir_all(path)?;
+            } else {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// The entries [clear](crate::WithDir::clear) would remove, without
+    /// removing them.
+    pub fn clear_dry_run(&self) -> Result<Vec<PathBuf>, std::io::Error> {
+        self.walk()
+            .max_depth(1)
+            .map(|e| e.map(|e| e.into_path()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env::temp_dir, io::Read};
+
+    use super::*;
+
+    #[test]
+    fn test_strip_verbatim() {
+        assert_eq!(
+            strip_verbatim(r"\\?\C:\Users\me").as_deref(),
+            Some(r"C:\Users\me")
+        );
+        assert_eq!(
+            strip_verbatim(r"\\?\UNC\server\share").as_deref(),
+            Some(r"\\server\share")
+        );
+        assert_eq!(strip_verbatim(r"C:\Users"), None);
+        assert_eq!(strip_verbatim(r"\\?\C:\dir\con.txt"), None);
+        assert_eq!(strip_verbatim(r"\\?\C:\dir\trailing."), None);
+        assert_eq!(strip_verbatim(r"\\?\Volume{1234}\x"), None);
+        let long = format!(r"\\?\C:\{}", "a".repeat(300));
+        assert_eq!(strip_verbatim(&long), None);
+    }
+
+    #[test]
+    fn test_to_verbatim() {
+        assert_eq!(to_verbatim(r"C:\a\b").as_deref(), Some(r"\\?\C:\a\b"));
+        assert_eq!(
+            to_verbatim(r"\\server\share\a").as_deref(),
+            Some(r"\\?\UNC\server\share\a")
+        );
+        assert_eq!(to_verbatim(r"\\?\C:\a"), None);
+        assert_eq!(to_verbatim(r"a\b"), None);
+        assert_eq!(long_path(Path::new("short")), Path::new("short"));
+    }
+
+    #[test]
+    fn test_canonical_path() {
+        let wd = WithDir::temp().unwrap();
+        assert_eq!(wd.canonical_path().unwrap(), wd.path());
+    }
+
+    #[test]
+    fn test_relative_fs() {
+        let wd = WithDir::temp().unwrap();
+        // move the cwd somewhere else, the methods should ignore it
+        let _other = WithDir::new(temp_dir()).unwrap();
+
+        wd.write("a", "hello").unwrap();
+        assert_eq!(wd.read_to_string("a").unwrap(), "hello");
+        assert!(wd.path().join("a").exists());
+
+        drop(wd.create_file("b").unwrap());
+        let mut s = String::new();
+        wd.open("b").unwrap().read_to_string(&mut s).unwrap();
+        assert!(s.is_empty());
+        assert_eq!(wd.resolve("b"), wd.path().join("b"));
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let wd = WithDir::temp().unwrap();
+        let root = fs::canonicalize(wd.path()).unwrap();
+        fs::create_dir("a").unwrap();
+
+        assert_eq!(wd.canonicalize("a/../a").unwrap(), root.join("a"));
+        assert!(wd.canonicalize("missing").is_err());
+        assert_eq!(
+            wd.soft_canonicalize("a/../a/b/./c/../d").unwrap(),
+            root.join("a/b/d")
+        );
+        assert_eq!(wd.soft_canonicalize("").unwrap(), root);
+    }
+
+    #[test]
+    fn test_clear() {
+        let wd = WithDir::temp().unwrap();
+        fs::create_dir_all("sub/dir").unwrap();
+        fs::write("file", "").unwrap();
+
+        let expected = [Path::new("file"), Path::new("sub")];
+        assert_eq!(wd.clear_dry_run().unwrap(), expected);
+        assert!(Path::new("sub/dir").exists());
+
+        assert_eq!(wd.clear().unwrap(), expected);
+        assert!(wd.clear_dry_run().unwrap().is_empty());
+        assert!(wd.path().exists());
+    }
+}
@@ -0,0 +1,52 @@
+//! Glob matching relative to a [WithDir](crate::WithDir). Requires the `glob`
+//! feature.
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use glob::{glob, Pattern};
+
+use crate::WithDir;
+
+impl WithDir<'_> {
+    /// Return paths inside this directory matching `pattern`, relative to
+    /// the directory and in alphabetical order. The pattern uses
+    /// [glob](https://docs.rs/glob) syntax and is always matched against
+    /// this directory, not the current working directory.
+    pub fn glob(&self, pattern: &str) -> Result<Vec<PathBuf>, std::io::Error> {
+        let root = self.absolute_path();
+        let full = Path::new(&Pattern::escape(&root.to_string_lossy())).join(pattern);
+        let paths = glob(&full.to_string_lossy())
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+        paths
+            .map(|p| {
+                let p = p.map_err(std::io::Error::from)?;
+                Ok(p.strip_prefix(root).map(Path::to_path_buf).unwrap_or(p))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir, write};
+
+    use super::*;
+
+    #[test]
+    fn test_glob() {
+        let wd = WithDir::temp().unwrap();
+        create_dir("out").unwrap();
+        write("out/a.json", "").unwrap();
+        write("out/b.json", "").unwrap();
+        write("out/c.txt", "").unwrap();
+
+        assert_eq!(
+            wd.glob("out/*.json").unwrap(),
+            [Path::new("out/a.json"), Path::new("out/b.json")]
+        );
+        assert_eq!(wd.glob("**/*.txt").unwrap(), [Path::new("out/c.txt")]);
+        assert!(wd.glob("[").is_err());
+    }
+}
@@ -0,0 +1,141 @@
+//! Entering directories from an open handle rather than a path.
+#[cfg(windows)]
+use std::path::PathBuf;
+
+use crate::{backend, error::Context, lock_to_enter, Cwd, Held, Operation, WithDir};
+
+#[cfg(unix)]
+impl<'a> WithDir<'a> {
+    /// Change the current working directory to the directory open as `fd`,
+    /// using `fchdir`. This works for directories that were never given a
+    /// path, such as a descriptor received over a socket or opened with
+    /// `openat`. [path](crate::WithDir::path) is whatever
+    /// [current_dir](std::env::current_dir) reports after the change.
+    ///
+    /// A descriptor is always a real directory, so this reads, changes and
+    /// restores the process current directory whatever
+    /// [backend](crate::backend) is installed.
+    pub fn from_fd(fd: impl std::os::fd::AsFd) -> Result<WithDir<'a>, std::io::Error> {
+        use std::os::fd::AsRawFd;
+
+        let backend = backend::default_backend();
+        let m = lock_to_enter()?;
+        let original_dir = backend.current_dir().reading_cwd()?;
+        let raw = fd.as_fd().as_raw_fd();
+        // SAFETY: the descriptor is borrowed for the duration of the call
+        if unsafe { libc::fchdir(raw) } != 0 {
+            return Err(std::io::Error::last_os_error()).entering(
+                Operation::SetCurrentDir,
+                std::path::Path::new(&format!("fd {}", raw)),
+                &original_dir,
+            );
+        }
+        let path = match backend.current_dir().reading_cwd() {
+            Ok(p) => p,
+            Err(e) => {
+                backend.set_current_dir(&original_dir)?;
+                return Err(e);
+            }
+        };
+        Ok(WithDir::registered(
+            Held::Lock(m),
+            original_dir,
+            Cwd::NotTemp(path),
+            backend,
+        ))
+    }
+}
+
+#[cfg(windows)]
+fn handle_path(handle: std::os::windows::io::BorrowedHandle) -> Result<PathBuf, std::io::Error> {
+    use std::os::windows::{ffi::OsStringExt, io::AsRawHandle};
+    use windows_sys::Win32::Storage::FileSystem::{
+        GetFinalPathNameByHandleW, FILE_NAME_NORMALIZED, VOLUME_NAME_DOS,
+    };
+
+    let mut buf = vec![0u16; 512];
+    loop {
+        // SAFETY: buf is valid for buf.len() u16s and the handle is borrowed
+        let len = unsafe {
+            GetFinalPathNameByHandleW(
+                handle.as_raw_handle(),
+                buf.as_mut_ptr(),
+                buf.len() as u32,
+                FILE_NAME_NORMALIZED | VOLUME_NAME_DOS,
+            )
+        } as usize;
+        if len == 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if len < buf.len() {
+            buf.truncate(len);
+            break;
+        }
+        // too small, len is the required size including the nul
+        buf.resize(len, 0);
+    }
+    let path = std::ffi::OsString::from_wide(&buf)
+        .to_string_lossy()
+        .into_owned();
+    Ok(PathBuf::from(
+        crate::fs::strip_verbatim(&path).unwrap_or(path),
+    ))
+}
+
+#[cfg(windows)]
+impl<'a> WithDir<'a> {
+    /// Change the current working directory to the directory open as
+    /// `handle`. Windows can't change directory by handle, so the handle's
+    /// path is looked up with `GetFinalPathNameByHandleW` and entered.
+    /// The handle must have been opened with `FILE_FLAG_BACKUP_SEMANTICS`.
+    pub fn from_handle(
+        handle: impl std::os::windows::io::AsHandle,
+    ) -> Result<WithDir<'a>, std::io::Error> {
+        let path = handle_path(handle.as_handle())?;
+        let backend = backend::backend();
+        let m = lock_to_enter()?;
+        let original_dir = backend.current_dir().reading_cwd()?;
+        backend
+            .set_current_dir(&path)
+            .entering(Operation::SetCurrentDir, &path, &original_dir)?;
+        Ok(WithDir::registered(
+            Held::Lock(m),
+            original_dir,
+            Cwd::NotTemp(path),
+            backend,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    #[test]
+    fn test_from_fd() {
+        use std::{env::current_dir, fs::File, os::fd::OwnedFd};
+
+        use crate::WithDir;
+
+        let tmp = WithDir::temp().unwrap();
+        let target = tmp.canonicalize("").unwrap();
+        std::fs::create_dir("sub").unwrap();
+        let fd: OwnedFd = File::open("sub").unwrap().into();
+        {
+            let wd = WithDir::from_fd(&fd).unwrap();
+            assert_eq!(current_dir().unwrap(), target.join("sub"));
+            assert_eq!(wd.path(), target.join("sub"));
+        }
+        assert_eq!(current_dir().unwrap(), target);
+
+        let file: OwnedFd = File::create("file").unwrap().into();
+        let err = WithDir::from_fd(file).unwrap_err();
+        let err = err
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<crate::EnterError>()
+            .unwrap();
+        assert_eq!(err.operation, crate::Operation::SetCurrentDir);
+        assert_eq!(err.original.as_deref(), Some(target.as_path()));
+        assert_eq!(current_dir().unwrap(), target);
+    }
+}
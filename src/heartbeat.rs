@@ -0,0 +1,88 @@
+//! Periodic heartbeat file for long running scopes. See
+//! [WithDir::heartbeat](crate::WithDir::heartbeat).
+use std::{
+    fs::write,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::WithDir;
+
+/// Name of the heartbeat file created inside the scope directory.
+pub const HEARTBEAT_FILE_NAME: &str = ".with_dir.heartbeat";
+
+pub(crate) struct Heartbeat {
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+fn beat(path: &Path) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    // a missed beat is not worth stopping for, the next one may succeed
+    let _ = write(path, format!("{}\n", now));
+}
+
+impl Heartbeat {
+    fn start(path: PathBuf, interval: Duration) -> Heartbeat {
+        let (stop, rx) = channel();
+        beat(&path);
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                beat(&path);
+            }
+        });
+        Heartbeat {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl WithDir<'_> {
+    /// Start writing the current unix time to a file named
+    /// [HEARTBEAT_FILE_NAME](crate::heartbeat::HEARTBEAT_FILE_NAME) inside
+    /// this directory every `interval`, until this `WithDir` is dropped.
+    /// Janitor jobs can check the file's age to tell an active directory
+    /// made with [create](crate::WithDir::create) from an abandoned one.
+    ///
+    /// Calling this again replaces the previous heartbeat. The file is left
+    /// in place when the heartbeat stops.
+    pub fn heartbeat(&mut self, interval: Duration) {
+        self.heartbeat = None;
+        let path = self.absolute_path().join(HEARTBEAT_FILE_NAME);
+        self.heartbeat = Some(Heartbeat::start(path, interval));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{metadata, read_to_string};
+
+    use super::*;
+
+    #[test]
+    fn test_heartbeat() {
+        let mut wd = WithDir::temp().unwrap();
+        wd.heartbeat(Duration::from_millis(10));
+        let first = metadata(HEARTBEAT_FILE_NAME).unwrap().modified().unwrap();
+        assert!(!read_to_string(HEARTBEAT_FILE_NAME).unwrap().is_empty());
+
+        thread::sleep(Duration::from_millis(50));
+        let later = metadata(HEARTBEAT_FILE_NAME).unwrap().modified().unwrap();
+        assert!(later > first);
+    }
+}
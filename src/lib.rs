@@ -1,19 +1,396 @@
 //! Library provides the struct [WithDir](crate::WithDir) which uses RAII
 //! to enable scoped change of working directory. See docs for [WithDir](crate::WithDir)
 //! for simple example.
-use parking_lot::{ReentrantMutex, ReentrantMutexGuard};
+use parking_lot::{Mutex, ReentrantMutex, ReentrantMutexGuard};
 use std::{
-    env::{current_dir, set_current_dir},
-    fs::{create_dir, create_dir_all},
-    path::{Path, PathBuf},
+    borrow::Cow,
+    collections::BTreeMap,
+    ffi::OsString,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
 };
 use tempfile::TempDir;
 
+mod adapters;
+mod anchor;
+mod assert;
+#[cfg(feature = "async")]
+mod asynchronous;
+pub mod audit;
+pub mod backend;
+pub mod baseline;
+pub mod block;
+#[cfg(feature = "bundle")]
+mod bundle;
+pub mod copy;
+mod deadline;
+pub mod diff;
+pub mod dry_run;
+mod env;
+mod error;
+pub mod expand;
+mod ext;
+mod fair;
+#[cfg(feature = "rstest")]
+pub mod fixtures;
+mod fs;
+#[cfg(feature = "glob")]
+mod glob;
+mod handle;
+pub mod heartbeat;
+#[cfg(feature = "fs4")]
+pub mod lock;
+#[cfg(feature = "memfs")]
+mod memfs;
+mod nesting;
+pub mod observer;
+mod options;
+mod poison;
+mod process;
+#[cfg(feature = "glob")]
+mod publish;
+mod pwd;
+mod registry;
+pub mod retry;
+#[cfg(all(feature = "chroot", unix))]
+mod root;
+#[cfg(feature = "serial_test")]
+pub mod serial;
+mod shared;
+#[cfg(feature = "metrics")]
+pub mod stats;
+mod strict;
+mod temp_set;
+mod transfer;
+#[cfg(all(feature = "linux-unshare", target_os = "linux"))]
+mod unshare;
+#[cfg(feature = "unstable")]
+pub mod unstable;
+mod virtual_dir;
+pub mod walk;
+#[cfg(any(target_os = "wasi", test))]
+mod wasi;
+#[cfg(feature = "watchdog")]
+mod watchdog;
+pub mod worker;
+
+pub use audit::AuditLog;
+pub use backend::{set_backend, CwdBackend, OsBackend};
+pub use copy::{CopyOptions, Overwrite, Symlinks};
+pub use deadline::set_default_deadline;
+use deadline::Deadline;
+pub use diff::{Diff, Snapshot};
+pub use dry_run::DryRun;
+use error::Context;
+pub use error::{EnterError, LockTimeout, Operation};
+pub use ext::{ScopedChdir, TempDirExt};
+pub use fair::set_fair_locking;
+use heartbeat::Heartbeat;
+#[cfg(feature = "fs4")]
+pub use lock::DirLock;
+#[cfg(feature = "memfs")]
+pub use memfs::MemBackend;
+pub use nesting::OutOfOrder;
+pub use observer::{add_observer, remove_observer, DirChangeObserver};
+pub use options::Options;
+pub use poison::{clear_poison, is_poisoned, Poisoned};
+pub use registry::{active, stack, ActiveGuard};
+pub use retry::Retry;
+#[cfg(all(feature = "chroot", unix))]
+pub use root::WithRoot;
+pub use shared::SharedWithDir;
+pub use strict::StrictOptions;
+pub use temp_set::TempSet;
+pub use transfer::Detached;
+pub use virtual_dir::{VirtualDir, VirtualOpenOptions};
+pub use walk::{Walk, WalkEntry};
+#[cfg(target_os = "wasi")]
+pub use wasi::WasiBackend;
+#[cfg(feature = "watchdog")]
+pub use watchdog::Watchdog;
+pub use worker::CwdWorker;
+
+// lets the macros refer to `::with_dir` from inside this crate
+extern crate self as with_dir;
+
+#[cfg(feature = "macros")]
+pub use with_dir_macros::{test, with_dir};
+
 static DIR_MUTEX: ReentrantMutex<()> = ReentrantMutex::new(());
 
+/// Lock [DIR_MUTEX]
+fn lock_dir() -> ReentrantMutexGuard<'static, ()> {
+    lock_dir_within(None).expect("lock without a timeout")
+}
+
+/// Lock [DIR_MUTEX], giving up after `timeout` if there is one, in turn
+/// if [fair](crate::set_fair_locking). With the `metrics` feature the time
+/// spent waiting is recorded.
+fn lock_dir_within(
+    timeout: Option<std::time::Duration>,
+) -> Option<ReentrantMutexGuard<'static, ()>> {
+    #[cfg(feature = "metrics")]
+    let start = std::time::Instant::now();
+    let guard = match timeout {
+        _ if fair::is_fair() && !DIR_MUTEX.is_owned_by_current_thread() => {
+            fair::lock_in_turn(timeout)
+        }
+        Some(t) => DIR_MUTEX.try_lock_for(t),
+        None => Some(DIR_MUTEX.lock()),
+    };
+    #[cfg(feature = "metrics")]
+    stats::lock_waited(start);
+    guard
+}
+
+/// Lock [DIR_MUTEX] to create a `WithDir`, failing if the directory state
+/// is [poisoned](crate::is_poisoned)
+fn lock_to_enter() -> Result<ReentrantMutexGuard<'static, ()>, std::io::Error> {
+    let m = lock_dir();
+    poison::check()?;
+    Ok(m)
+}
+
+/// Guard returned by [lock](crate::lock). While it exists no `WithDir` can be
+/// created on another thread. Like `WithDir` it is reentrant, so it can be
+/// held while creating `WithDir`s on the same thread.
+pub struct LockGuard(#[allow(dead_code)] ReentrantMutexGuard<'static, ()>);
+
+/// Acquire the lock `WithDir` uses without changing directory, blocking
+/// until no other thread holds a `WithDir`. Code that has to call
+/// [set_current_dir](std::env::set_current_dir) itself, for example through
+/// FFI, can hold this to cooperate with `WithDir` instead of racing it.
+///
+/// ```
+/// let _lock = with_dir::lock();
+/// let before = std::env::current_dir().unwrap();
+/// std::env::set_current_dir("src").unwrap();
+/// // ...
+/// std::env::set_current_dir(before).unwrap();
+/// ```
+pub fn lock() -> LockGuard {
+    LockGuard(lock_dir())
+}
+
+/// Whether a `WithDir` or [LockGuard] is alive on any thread, including
+/// this one. Guards made with
+/// [new_unsynchronized](crate::WithDir::new_unsynchronized) don't count,
+/// see [active](crate::active) for those.
+pub fn is_locked() -> bool {
+    DIR_MUTEX.is_locked()
+}
+
+/// Block until no `WithDir` or [LockGuard] is alive, without keeping the
+/// lock, so cwd sensitive work can be scheduled around other threads'.
+/// Returns `false` if that didn't happen within `timeout`, or immediately
+/// if this thread holds the lock itself, since waiting would never end.
+/// Another thread may of course take the lock as soon as this returns.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// assert!(with_dir::wait_until_free(Duration::from_secs(10)));
+/// let _lock = with_dir::lock();
+/// assert!(with_dir::is_locked());
+/// assert!(!with_dir::wait_until_free(Duration::from_secs(10)));
+/// ```
+pub fn wait_until_free(timeout: std::time::Duration) -> bool {
+    if DIR_MUTEX.is_owned_by_current_thread() {
+        return false;
+    }
+    DIR_MUTEX.try_lock_for(timeout).is_some()
+}
+
+/// Run `f` while holding the [lock](crate::lock), without changing
+/// directory. Use it around code that reads
+/// [current_dir](std::env::current_dir) or does relative IO and mustn't see
+/// a `WithDir` from another thread part way through.
+///
+/// ```
+/// let manifest = with_dir::guarded(|| std::fs::read_to_string("Cargo.toml")).unwrap();
+/// ```
+pub fn guarded<R>(f: impl FnOnce() -> R) -> R {
+    let _lock = lock();
+    f()
+}
+
+/// Run `f` with a new temporary [VirtualDir], deleted once `f` returns.
+/// The current directory is never changed and no lock is taken, so tests
+/// using this run fully in parallel. Prefer it over
+/// [WithDir::temp](crate::WithDir::temp) unless the code under test
+/// really needs the process current directory, for example because it
+/// takes relative paths it doesn't let you root somewhere else.
+///
+/// ```
+/// let text = with_dir::isolated(|dir| {
+///     dir.write("config.toml", "name = 'demo'").unwrap();
+///     dir.read_to_string("config.toml").unwrap()
+/// })
+/// .unwrap();
+/// assert_eq!(text, "name = 'demo'");
+/// ```
+pub fn isolated<R>(f: impl FnOnce(&VirtualDir) -> R) -> Result<R, std::io::Error> {
+    let dir = VirtualDir::temp()?;
+    let ret = f(&dir);
+    dir.close()?;
+    Ok(ret)
+}
+
+/// Name of the current thread, which under `cargo test` is the name of
+/// the running test.
+fn current_test_name() -> Option<String> {
+    std::thread::current()
+        .name()
+        .filter(|n| *n != "main")
+        .map(str::to_owned)
+}
+
+/// Prefix for temp directory names, including the test name if there is one
+fn temp_prefix(test_name: Option<&str>) -> String {
+    match test_name {
+        Some(name) => {
+            let name: String = name
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .take(64)
+                .collect();
+            format!(".tmp-{}-", name)
+        }
+        None => ".tmp".to_owned(),
+    }
+}
+
+/// [set_current_dir] through the installed [backend](crate::backend)
+fn chdir(path: impl AsRef<Path>) -> Result<(), std::io::Error> {
+    backend::backend().set_current_dir(path.as_ref())
+}
+
+/// Whether `path` is already the directory `cwd`, so there's no need to
+/// change to it. Only checked lexically, it's a shortcut rather than a test.
+fn is_same_dir(cwd: &Path, path: &Path) -> bool {
+    if path.as_os_str().is_empty() {
+        return false;
+    }
+    path == cwd || path.components().all(|c| c == Component::CurDir)
+}
+
+/// [current_dir](std::env::current_dir) through the installed
+/// [CwdBackend]
+fn current_dir() -> Result<PathBuf, std::io::Error> {
+    backend::backend().current_dir().reading_cwd()
+}
+
+/// Where temporary directories are made. On unix this is
+/// [temp_dir](std::env::temp_dir) canonicalized, so that on macOS, where it
+/// is a symlink into `/private`, [path](crate::WithDir::path) agrees with
+/// [current_dir](std::env::current_dir). Windows canonical paths have a
+/// `\\?\` prefix that `current_dir` doesn't, so it is left alone there.
+fn temp_root() -> PathBuf {
+    let dir = std::env::temp_dir();
+    if cfg!(windows) {
+        return dir;
+    }
+    std::fs::canonicalize(&dir).unwrap_or(dir)
+}
+
+/// A directory made by [create_ephemeral](crate::WithDir::create_ephemeral),
+/// removed along with `remove` when dropped, through the backend that
+/// made it
+struct Ephemeral {
+    path: PathBuf,
+    /// Absolute path of the outermost directory that was created
+    remove: PathBuf,
+    keep: bool,
+    backend: Arc<dyn CwdBackend>,
+}
+
+impl Ephemeral {
+    /// Outermost directory that `create_dir_all(path)` would make in
+    /// `backend`, made absolute against its current directory
+    fn outermost_missing(backend: &dyn CwdBackend, path: &Path) -> Result<PathBuf, std::io::Error> {
+        let abs = backend.current_dir().reading_cwd()?.join(path);
+        let mut missing = abs.clone();
+        for ancestor in abs.ancestors() {
+            if backend.exists(ancestor) {
+                break;
+            }
+            missing = ancestor.to_owned();
+        }
+        Ok(missing)
+    }
+}
+
+impl Drop for Ephemeral {
+    fn drop(&mut self) {
+        if !self.keep {
+            // like TempDir, failing to clean up is not worth a panic
+            let _ = self.backend.remove_dir_all(&self.remove);
+        }
+    }
+}
+
 enum Cwd {
     Temp(TempDir),
     NotTemp(PathBuf),
+    Ephemeral(Ephemeral),
+    /// A member of a [TempSet], which is kept alive by `_set`
+    Shared {
+        path: PathBuf,
+        _set: Arc<Mutex<Vec<TempDir>>>,
+    },
+}
+
+/// What [WithDir::new_with_fallback](crate::WithDir::new_with_fallback) should
+/// restore to when the current directory can't be determined, for example
+/// because it has been deleted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Fallback {
+    /// Return the error from [current_dir](std::env::current_dir)
+    #[default]
+    Error,
+    /// Use [temp_dir](std::env::temp_dir)
+    TempDir,
+    /// Use the home directory, from `HOME` or `USERPROFILE`
+    Home,
+    /// Use the given path
+    Path(PathBuf),
+}
+
+impl Fallback {
+    fn original_dir(&self, backend: &dyn CwdBackend) -> Result<PathBuf, std::io::Error> {
+        let err = match backend.current_dir().reading_cwd() {
+            Ok(p) => return Ok(p),
+            Err(e) => e,
+        };
+        match self {
+            Fallback::Error => Err(err),
+            Fallback::TempDir => Ok(std::env::temp_dir()),
+            Fallback::Home => ["HOME", "USERPROFILE"]
+                .iter()
+                .find_map(std::env::var_os)
+                .map(PathBuf::from)
+                .ok_or(err),
+            Fallback::Path(p) => Ok(p.clone()),
+        }
+    }
+}
+
+/// What a [WithDir] does when it is dropped and finds the process is no
+/// longer in its directory, because something called
+/// [set_current_dir](std::env::set_current_dir) behind its back. The
+/// original directory is restored either way. Set with
+/// [on_clobber](crate::WithDir::on_clobber).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Clobber {
+    /// Restore without saying anything
+    Ignore,
+    /// Print the expected and actual directories to stderr
+    #[default]
+    Warn,
+    /// Panic after restoring, unless already panicking.
+    /// [leave](crate::WithDir::leave) returns an error instead.
+    Panic,
 }
 
 /// Scoped modifier of the current working directory. This uses RAII to set the
@@ -21,7 +398,13 @@ enum Cwd {
 /// This struct uses a static `parking_lot::ReentrantMutex` to prevent `WithDir` on other
 /// threads from updating the current working directory while any WithDir instances
 /// exist. However there is nothing stopping other threads from calling `std::env::set_current_dir`
-/// directly which would override the working directory.
+/// directly which would override the working directory. If that happens
+/// [refresh](crate::WithDir::refresh) puts it back.
+///
+/// The lock belongs to the thread that took it, so a `WithDir` can't be sent
+/// to another thread. Move a scope between threads with
+/// [detach](crate::WithDir::detach), or share one with
+/// [share](crate::WithDir::share).
 ///
 /// WithDir should be created with `new` which returns a result. Result couldbe Err if the
 /// directory doesn't exist, or if the user does not have permission to access.
@@ -29,14 +412,9 @@ enum Cwd {
 /// ```
 /// use with_dir::WithDir;
 ///
-/// // create a directory
+/// // enter a directory, creating it if it doesn't exist
 /// let path = std::env::current_dir().unwrap().join("a");
-/// if !path.exists() {
-///     std::fs::create_dir(&path);
-/// }
-///
-/// // enter that directory
-/// WithDir::new(&path).map( |_| {
+/// WithDir::new_or_create(&path).map( |_| {
 ///     assert_eq!(std::env::current_dir().unwrap(), path);
 /// }).unwrap();
 ///
@@ -52,86 +430,752 @@ enum Cwd {
 pub struct WithDir<'a> {
     original_dir: PathBuf,
     cwd: Cwd,
-    mutex: Option<ReentrantMutexGuard<'a, ()>>,
+    /// The path as given to the constructor
+    requested: PathBuf,
+    mutex: Option<Held<'a>>,
+    /// Key in the [active] registry, 0 if it isn't in it
+    id: u64,
+    snapshot: Option<Snapshot>,
+    heartbeat: Option<Heartbeat>,
+    deadline: Option<Deadline>,
+    env: BTreeMap<OsString, OsString>,
+    test_name: Option<String>,
+    keep_on_panic: bool,
+    clobber: Clobber,
+    out_of_order: OutOfOrder,
+    retry: Retry,
+    backend: Arc<dyn CwdBackend>,
+    /// Created in the directory that was already current, see
+    /// [new](crate::WithDir::new)
+    unchanged: bool,
+    /// Put back when this instance is dropped, see
+    /// [sync_pwd](crate::WithDir::sync_pwd) and
+    /// [export_tmpdir](crate::WithDir::export_tmpdir)
+    saved_vars: Vec<env::SavedVar>,
+    /// See [new_async](crate::WithDir::new_async), released after `mutex`
+    #[cfg(feature = "async")]
+    gate: Option<tokio::sync::MutexGuard<'static, ()>>,
+    #[cfg(feature = "bundle")]
+    bundle_dir: Option<PathBuf>,
+    #[cfg(feature = "metrics")]
+    created: std::time::Instant,
+}
+
+/// What an active [WithDir] holds until it restores the directory
+enum Held<'a> {
+    Lock(#[allow(dead_code)] ReentrantMutexGuard<'a, ()>),
+    /// See [new_unsynchronized](crate::WithDir::new_unsynchronized)
+    Nothing,
 }
 
 impl<'a> WithDir<'a> {
+    fn from_parts(mutex: ReentrantMutexGuard<'a, ()>, original_dir: PathBuf, cwd: Cwd) -> Self {
+        WithDir::from_held(Held::Lock(mutex), original_dir, cwd)
+    }
+
+    fn from_held(held: Held<'a>, original_dir: PathBuf, cwd: Cwd) -> Self {
+        WithDir::registered(held, original_dir, cwd, backend::backend())
+    }
+
+    /// A `WithDir` using `backend`, added to [active] and announced to
+    /// observers
+    fn registered(
+        held: Held<'a>,
+        original_dir: PathBuf,
+        cwd: Cwd,
+        backend: Arc<dyn CwdBackend>,
+    ) -> Self {
+        let mut wd = WithDir::unregistered(held, original_dir, cwd, backend);
+        wd.test_name = current_test_name();
+        #[cfg(feature = "metrics")]
+        stats::guard_created();
+        if let Some(limit) = deadline::default_deadline() {
+            wd.deadline = Some(Deadline::start(wd.absolute_path().to_owned(), limit));
+        }
+        wd.id = registry::register(wd.absolute_path().to_owned());
+        #[cfg(feature = "log")]
+        log::debug!(
+            "entered {} from {}",
+            wd.absolute_path().display(),
+            wd.original_dir.display()
+        );
+        observer::notify(|o| o.on_enter(&wd.original_dir, wd.absolute_path()));
+        wd
+    }
+
+    /// A `WithDir` with default settings that nothing else knows about yet
+    fn unregistered(
+        held: Held<'a>,
+        original_dir: PathBuf,
+        cwd: Cwd,
+        backend: Arc<dyn CwdBackend>,
+    ) -> Self {
+        // store absolute paths, so they stay valid after other changes of
+        // directory, keeping the path as given for requested_path
+        let (cwd, requested) = match cwd {
+            Cwd::NotTemp(p) => (Cwd::NotTemp(original_dir.join(&p)), p),
+            Cwd::Ephemeral(mut e) => {
+                let absolute = original_dir.join(&e.path);
+                let requested = std::mem::replace(&mut e.path, absolute);
+                (Cwd::Ephemeral(e), requested)
+            }
+            Cwd::Temp(t) => {
+                let requested = t.path().to_owned();
+                (Cwd::Temp(t), requested)
+            }
+            Cwd::Shared { path, _set } => {
+                let requested = path.clone();
+                (Cwd::Shared { path, _set }, requested)
+            }
+        };
+        WithDir {
+            original_dir,
+            cwd,
+            requested,
+            mutex: Some(held),
+            id: 0,
+            snapshot: None,
+            heartbeat: None,
+            deadline: None,
+            env: BTreeMap::new(),
+            test_name: None,
+            keep_on_panic: false,
+            clobber: Clobber::default(),
+            out_of_order: OutOfOrder::default(),
+            retry: Retry::default(),
+            backend,
+            unchanged: false,
+            saved_vars: Vec::new(),
+            #[cfg(feature = "async")]
+            gate: None,
+            #[cfg(feature = "bundle")]
+            bundle_dir: None,
+            #[cfg(feature = "metrics")]
+            created: std::time::Instant::now(),
+        }
+    }
+
     /// On creation, the current working directory is set to `path`
     /// and a [ReentrantMutexGuard](parking_lot::ReentrantMutexGuard) is claimed.
+    ///
+    /// If `path` is `.` or the current directory itself the directory isn't
+    /// changed, and when dropped it is only restored if something has
+    /// moved it, so entering the same directory in a loop is cheap.
     pub fn new(path: impl AsRef<Path>) -> Result<WithDir<'a>, std::io::Error> {
-        let m = DIR_MUTEX.lock();
+        let m = lock_to_enter()?;
+        let backend = backend::backend();
+        let original_dir = backend.current_dir().reading_cwd()?;
+        WithDir::enter_unless_same(Held::Lock(m), original_dir, path.as_ref(), backend)
+    }
+
+    /// Change to `path` unless it is `original_dir`, the current directory,
+    /// already
+    fn enter_unless_same(
+        held: Held<'a>,
+        original_dir: PathBuf,
+        path: &Path,
+        backend: Arc<dyn CwdBackend>,
+    ) -> Result<WithDir<'a>, std::io::Error> {
+        let unchanged = is_same_dir(&original_dir, path);
+        if !unchanged {
+            backend.set_current_dir(path).entering(
+                Operation::SetCurrentDir,
+                path,
+                &original_dir,
+            )?;
+        }
+        let cwd = Cwd::NotTemp(path.to_owned());
+        let mut wd = WithDir::registered(held, original_dir, cwd, backend);
+        wd.unchanged = unchanged;
+        Ok(wd)
+    }
+
+    /// Same as [new](crate::WithDir::new), for callers that already know the
+    /// current directory, such as a loop making many scoped changes from
+    /// the same place. It saves reading the current directory. `origin` is
+    /// what gets restored on drop, so it must be the current directory,
+    /// which isn't checked, and absolute.
+    ///
+    /// ```
+    /// use with_dir::WithDir;
+    ///
+    /// let origin = std::env::current_dir().unwrap();
+    /// for dir in ["src", "with_dir_macros"] {
+    ///     let wd = WithDir::new_with_origin(dir, &origin).unwrap();
+    ///     assert!(wd.is_current());
+    /// }
+    /// ```
+    pub fn new_with_origin(
+        path: impl AsRef<Path>,
+        origin: impl Into<PathBuf>,
+    ) -> Result<WithDir<'a>, std::io::Error> {
+        let original_dir = origin.into();
+        if !original_dir.is_absolute() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("origin {} is not absolute", original_dir.display()),
+            ));
+        }
+        let m = lock_to_enter()?;
+        let backend = backend::backend();
+        WithDir::enter_unless_same(Held::Lock(m), original_dir, path.as_ref(), backend)
+    }
+
+    /// Same as [new](crate::WithDir::new), but without claiming the lock,
+    /// for single threaded programs where it is pure overhead. Nothing
+    /// stops another thread, or another `WithDir`, from changing the
+    /// directory while this instance is alive, so only use it when no
+    /// other thread can be changing directory.
+    pub fn new_unsynchronized(path: impl AsRef<Path>) -> Result<WithDir<'a>, std::io::Error> {
+        poison::check()?;
+        let backend = backend::backend();
+        let original_dir = backend.current_dir().reading_cwd()?;
+        WithDir::enter_unless_same(Held::Nothing, original_dir, path.as_ref(), backend)
+    }
+
+    /// Change to `path` with as little overhead as possible, for
+    /// performance critical single threaded tools. Compared to
+    /// [new_unsynchronized](crate::WithDir::new_unsynchronized) this also
+    /// skips the [poison](crate::is_poisoned) check, the installed
+    /// [CwdBackend] (the OS is always used), registering in [active],
+    /// observers, logging and the [deadline](crate::set_default_deadline),
+    /// and checks for a [clobbered](crate::Clobber) or
+    /// [out of order](crate::OutOfOrder) restore when dropped. The original
+    /// directory is still restored.
+    ///
+    /// # Safety
+    ///
+    /// Getting this wrong can't cause undefined behaviour in this crate,
+    /// `unsafe` marks that the caller takes over what the lock and checks
+    /// normally guarantee, which code relying on relative paths, through FFI
+    /// for instance, may depend on for its own soundness. While the
+    /// returned `WithDir` is alive:
+    ///
+    /// - no other thread may read or change the current directory, or
+    ///   create a `WithDir`,
+    /// - nothing may change the current directory except `WithDir`s nested
+    ///   inside this one, and those must be dropped first,
+    /// - the directory state must not be poisoned.
+    ///
+    /// ```
+    /// use with_dir::WithDir;
+    ///
+    /// // SAFETY: nothing else in this program touches the current directory
+    /// let wd = unsafe { WithDir::new_unchecked("src") }.unwrap();
+    /// assert!(std::path::Path::new("lib.rs").exists());
+    /// ```
+    pub unsafe fn new_unchecked(path: impl AsRef<Path>) -> Result<WithDir<'a>, std::io::Error> {
+        let path = path.as_ref();
+        let original_dir = std::env::current_dir().reading_cwd()?;
+        let unchanged = is_same_dir(&original_dir, path);
+        if !unchanged {
+            OsBackend.set_current_dir(path).entering(
+                Operation::SetCurrentDir,
+                path,
+                &original_dir,
+            )?;
+        }
+        let cwd = Cwd::NotTemp(path.to_owned());
+        let mut wd = WithDir::unregistered(Held::Nothing, original_dir, cwd, Arc::new(OsBackend));
+        wd.unchanged = unchanged;
+        wd.clobber = Clobber::Ignore;
+        wd.out_of_order = OutOfOrder::Ignore;
+        Ok(wd)
+    }
+
+    /// Claim the lock and stay in the current directory, so other threads
+    /// can't move it until this instance is dropped. The directory is still
+    /// restored on drop in case something changes it in the meantime.
+    pub fn current() -> Result<WithDir<'a>, std::io::Error> {
+        let m = lock_to_enter()?;
         let original_dir = current_dir()?;
-        set_current_dir(&path)?;
-        Ok(WithDir {
+        let cwd = Cwd::NotTemp(original_dir.clone());
+        let mut wd = WithDir::from_parts(m, original_dir, cwd);
+        wd.unchanged = true;
+        Ok(wd)
+    }
+
+    /// Same as [new](crate::WithDir::new), but if the current directory can't
+    /// be read (some daemons are started in directories that have since been
+    /// deleted) the directory to restore to on drop is picked by `fallback`.
+    pub fn new_with_fallback(
+        path: impl AsRef<Path>,
+        fallback: Fallback,
+    ) -> Result<WithDir<'a>, std::io::Error> {
+        let m = lock_to_enter()?;
+        let original_dir = fallback.original_dir(&*backend::backend())?;
+        chdir(&path).entering(Operation::SetCurrentDir, path.as_ref(), &original_dir)?;
+        Ok(WithDir::from_parts(
+            m,
             original_dir,
-            cwd: Cwd::NotTemp(path.as_ref().to_owned()),
-            mutex: Some(m),
-        })
+            Cwd::NotTemp(path.as_ref().to_owned()),
+        ))
     }
 
     /// Uses [TempDir](tempfile::TempDir) to create a temporary
     /// directory that with the same lifetime as the returned
-    /// `WithDir`. The current working dir is change to the temp_dir
+    /// `WithDir`. The current working dir is change to the temp_dir.
+    /// When run from a test the directory name includes the test name.
+    /// The path is canonical, so it is equal to
+    /// [current_dir](std::env::current_dir) even where the system temp
+    /// directory is behind a symlink.
     pub fn temp() -> Result<WithDir<'a>, std::io::Error> {
-        let m = DIR_MUTEX.lock();
+        let m = lock_to_enter()?;
         let original_dir = current_dir()?;
-        let temp_dir = TempDir::new()?;
-        set_current_dir(temp_dir.path())?;
-        Ok(WithDir {
-            original_dir,
-            cwd: Cwd::Temp(temp_dir),
-            mutex: Some(m),
-        })
+        let temp_dir = backend::backend()
+            .temp_dir(&temp_prefix(current_test_name().as_deref()))
+            .entering(Operation::TempDir, &temp_root(), &original_dir)?;
+        #[cfg(feature = "log")]
+        log::debug!("created temporary directory {}", temp_dir.path().display());
+        chdir(temp_dir.path()).entering(
+            Operation::SetCurrentDir,
+            temp_dir.path(),
+            &original_dir,
+        )?;
+        let mut wd = WithDir::from_parts(m, original_dir, Cwd::Temp(temp_dir));
+        wd.snapshot = Some(Snapshot::default());
+        Ok(wd)
     }
 
     /// Makes a directory and changes the current working dir to that directory,
     /// the directory will persist after this `WithDir` is dropped. Use
     /// [create_all](crate::WithDir::create_all) if you want to also make the parent directories
     pub fn create(path: impl AsRef<Path>) -> Result<WithDir<'a>, std::io::Error> {
-        let m = DIR_MUTEX.lock();
+        let m = lock_to_enter()?;
         let original_dir = current_dir()?;
-        create_dir(&path)?;
-        set_current_dir(&path)?;
-        Ok(WithDir {
-            original_dir,
-            cwd: Cwd::NotTemp(path.as_ref().to_path_buf()),
-            mutex: Some(m),
-        })
+        backend::backend()
+            .create_dir(path.as_ref(), false)
+            .entering(Operation::CreateDir, path.as_ref(), &original_dir)?;
+        chdir(&path).entering(Operation::SetCurrentDir, path.as_ref(), &original_dir)?;
+        let mut wd =
+            WithDir::from_parts(m, original_dir, Cwd::NotTemp(path.as_ref().to_path_buf()));
+        wd.snapshot = Some(Snapshot::default());
+        Ok(wd)
     }
 
     /// See [create](crate::WithDir::create) for docs
     pub fn create_all(path: impl AsRef<Path>) -> Result<WithDir<'a>, std::io::Error> {
-        let m = DIR_MUTEX.lock();
+        let m = lock_to_enter()?;
         let original_dir = current_dir()?;
-        create_dir_all(&path)?;
-        set_current_dir(&path)?;
-        Ok(WithDir {
+        backend::backend()
+            .create_dir(path.as_ref(), true)
+            .entering(Operation::CreateDir, path.as_ref(), &original_dir)?;
+        chdir(&path).entering(Operation::SetCurrentDir, path.as_ref(), &original_dir)?;
+        Ok(WithDir::from_parts(
+            m,
             original_dir,
-            cwd: Cwd::NotTemp(path.as_ref().to_path_buf()),
-            mutex: Some(m),
-        })
+            Cwd::NotTemp(path.as_ref().to_path_buf()),
+        ))
+    }
+
+    /// Enter `path`, creating it and any missing parents first if it doesn't
+    /// exist. Unlike checking whether it exists and then calling
+    /// [new](crate::WithDir::new) or [create](crate::WithDir::create), this
+    /// doesn't fail if another process creates it in between. Use
+    /// [options](crate::WithDir::options) to control whether parents are
+    /// made.
+    pub fn new_or_create(path: impl AsRef<Path>) -> Result<WithDir<'a>, std::io::Error> {
+        WithDir::options().create(true).recursive(true).enter(path)
+    }
+
+    /// Like [create](crate::WithDir::create), but the directory is removed,
+    /// with everything in it, when this instance is dropped. Unlike
+    /// [temp](crate::WithDir::temp) you choose where it goes, for example
+    /// under the cargo target directory. It is kept if this instance is
+    /// dropped by a panic and [keep_on_panic](crate::WithDir::keep_on_panic)
+    /// is set.
+    pub fn create_ephemeral(path: impl AsRef<Path>) -> Result<WithDir<'a>, std::io::Error> {
+        let m = lock_to_enter()?;
+        let original_dir = current_dir()?;
+        let backend = backend::backend();
+        let remove = Ephemeral::outermost_missing(&*backend, path.as_ref())?;
+        backend.create_dir(path.as_ref(), false).entering(
+            Operation::CreateDir,
+            path.as_ref(),
+            &original_dir,
+        )?;
+        WithDir::enter_ephemeral(m, backend, original_dir, path.as_ref(), remove)
+    }
+
+    /// Like [create_ephemeral](crate::WithDir::create_ephemeral), but also
+    /// makes missing parents, which are removed too on drop.
+    pub fn create_all_ephemeral(path: impl AsRef<Path>) -> Result<WithDir<'a>, std::io::Error> {
+        let m = lock_to_enter()?;
+        let original_dir = current_dir()?;
+        let backend = backend::backend();
+        let remove = Ephemeral::outermost_missing(&*backend, path.as_ref())?;
+        backend.create_dir(path.as_ref(), true).entering(
+            Operation::CreateDir,
+            path.as_ref(),
+            &original_dir,
+        )?;
+        WithDir::enter_ephemeral(m, backend, original_dir, path.as_ref(), remove)
+    }
+
+    fn enter_ephemeral(
+        m: ReentrantMutexGuard<'a, ()>,
+        backend: Arc<dyn CwdBackend>,
+        original_dir: PathBuf,
+        path: &Path,
+        remove: PathBuf,
+    ) -> Result<WithDir<'a>, std::io::Error> {
+        let ephemeral = Ephemeral {
+            path: path.to_owned(),
+            remove,
+            keep: false,
+            backend: backend.clone(),
+        };
+        backend
+            .set_current_dir(path)
+            .entering(Operation::SetCurrentDir, path, &original_dir)?;
+        let mut wd = WithDir::from_parts(m, original_dir, Cwd::Ephemeral(ephemeral));
+        wd.snapshot = Some(Snapshot::default());
+        Ok(wd)
     }
 
     /// Get that path that was changed to when this instance
-    /// was created
+    /// was created. Relative paths are joined onto the directory that was
+    /// current at the time, so this is always absolute.
     pub fn path(&self) -> &Path {
         match &self.cwd {
             Cwd::NotTemp(p) => p,
             Cwd::Temp(p) => p.path(),
+            Cwd::Ephemeral(e) => &e.path,
+            Cwd::Shared { path, .. } => path,
+        }
+    }
+
+    /// The directory that was current when this instance was created, which
+    /// is restored when it is dropped. Use it to resolve paths relative to
+    /// where the program was started from.
+    pub fn original_path(&self) -> &Path {
+        &self.original_dir
+    }
+
+    /// Like shell `cd -`, go back to the [original
+    /// directory](crate::WithDir::original_path) in a nested `WithDir`,
+    /// returning here when that is dropped. Use it for the odd step that
+    /// must run where the program started without passing the path around.
+    ///
+    /// ```
+    /// use with_dir::WithDir;
+    ///
+    /// let wd = WithDir::new("src").unwrap();
+    /// {
+    ///     let _back = wd.previous().unwrap();
+    ///     assert!(std::path::Path::new("Cargo.toml").exists());
+    /// }
+    /// assert!(wd.is_current());
+    /// ```
+    pub fn previous(&self) -> Result<WithDir<'a>, std::io::Error> {
+        WithDir::options()
+            .backend(self.backend.clone())
+            .enter(&self.original_dir)
+    }
+
+    /// If this is a [temp](crate::WithDir::temp) directory, don't delete it
+    /// when this instance is dropped during a panic, and print its path to
+    /// stderr so a failing test's output can be inspected.
+    pub fn keep_on_panic(&mut self) {
+        self.keep_on_panic = true;
+    }
+
+    /// What to do if the directory has been changed behind this instance's
+    /// back by the time it is dropped, see [Clobber].
+    pub fn on_clobber(&mut self, clobber: Clobber) {
+        self.clobber = clobber;
+    }
+
+    /// Name of the thread that created this instance. Under `cargo test`
+    /// this is the name of the test, which makes it easy to trace a
+    /// directory back to the test that created it.
+    pub fn test_name(&self) -> Option<&str> {
+        self.test_name.as_deref()
+    }
+
+    /// Whether the process is still in this instance's directory. Paths are
+    /// compared after [canonicalize](std::fs::canonicalize), so symlinks
+    /// and, on Windows, differences in case don't matter. This is `false`
+    /// while a nested `WithDir` is alive, or if something has called
+    /// [set_current_dir](std::env::set_current_dir) behind this guard's back.
+    pub fn is_current(&self) -> bool {
+        let Ok(actual) = self.backend.current_dir() else {
+            return false;
+        };
+        if actual == self.absolute_path() {
+            return true;
+        }
+        let expected = std::fs::canonicalize(self.absolute_path());
+        matches!((expected, std::fs::canonicalize(actual)), (Ok(e), Ok(a)) if e == a)
+    }
+
+    /// Change back to this instance's directory if something else has moved
+    /// the process elsewhere, see [is_current](crate::WithDir::is_current).
+    /// Returns whether a change was needed. Don't call this while a nested
+    /// `WithDir` is alive, it would be moved out of that one's directory.
+    pub fn refresh(&self) -> Result<bool, std::io::Error> {
+        if self.is_current() {
+            return Ok(false);
+        }
+        self.backend.set_current_dir(self.absolute_path())?;
+        Ok(true)
+    }
+
+    /// The path as it was passed to the constructor, which may be relative.
+    /// Use it in messages to show paths the way the user wrote them.
+    pub fn requested_path(&self) -> &Path {
+        &self.requested
+    }
+
+    /// Absolute path of the directory, the same as
+    /// [path](crate::WithDir::path). Use it for file operations.
+    pub fn absolute_path(&self) -> &Path {
+        self.path()
+    }
+
+    /// Apply the [Clobber] policy, returning an error if it is
+    /// [Panic](crate::Clobber::Panic) and the directory has been changed.
+    /// A directory that has been deleted doesn't count as changed.
+    fn check_clobbered(&self) -> Result<(), std::io::Error> {
+        if self.clobber == Clobber::Ignore {
+            return Ok(());
         }
+        let Ok(actual) = self.backend.current_dir() else {
+            return Ok(());
+        };
+        // only canonicalize, which is slow, if they don't obviously match
+        if actual == self.absolute_path() {
+            return Ok(());
+        }
+        let Ok(expected) = std::fs::canonicalize(self.absolute_path()) else {
+            return Ok(());
+        };
+        let actual = match std::fs::canonicalize(actual) {
+            Ok(a) if a != expected => a,
+            _ => return Ok(()),
+        };
+        let msg = format!(
+            "current directory was changed behind WithDir's back, expected {} but was {}",
+            expected.display(),
+            actual.display()
+        );
+        match self.clobber {
+            Clobber::Panic => Err(std::io::Error::other(msg)),
+            _ => {
+                eprintln!("with_dir: {}", msg);
+                Ok(())
+            }
+        }
+    }
+
+    /// Remove this instance from [active], unless it was made by
+    /// [new_unchecked](crate::WithDir::new_unchecked) and never added
+    fn unregister(&self) {
+        if self.id == 0 {
+            return;
+        }
+        registry::unregister(self.id);
+        #[cfg(feature = "metrics")]
+        stats::guard_released(self.created);
+    }
+
+    /// Restore the original directory, or the nearest ancestor of it that
+    /// still exists, returning which
+    fn reset_cwd(&self) -> Result<PathBuf, std::io::Error> {
+        self.restore().run()
+    }
+
+    /// What [reset_cwd](WithDir::reset_cwd) needs, which once
+    /// [owned](Restore::into_owned) can be sent to another thread
+    fn restore(&self) -> Restore<'_> {
+        Restore {
+            path: Cow::Borrowed(self.absolute_path()),
+            original_dir: Cow::Borrowed(&self.original_dir),
+            backend: self.backend.clone(),
+            retry: self.retry,
+            unchanged: self.unchanged,
+            notify: self.id != 0,
+        }
+    }
+
+    /// [Poison](crate::is_poisoned) the directory state unless `restored`
+    /// is the directory the backend is now in
+    fn poison_unless_restored(&self, restored: Result<PathBuf, std::io::Error>) {
+        let actual = self.backend.current_dir().ok();
+        if let (Ok(dir), Some(actual)) = (&restored, &actual) {
+            if dir == actual {
+                return;
+            }
+            if matches!((std::fs::canonicalize(dir), std::fs::canonicalize(actual)), (Ok(d), Ok(a)) if d == a)
+            {
+                return;
+            }
+        }
+        poison::poison(Poisoned {
+            path: self.absolute_path().to_owned(),
+            original: self.original_dir.clone(),
+            actual,
+        });
     }
 
-    fn reset_cwd(&self) -> Result<(), std::io::Error> {
-        set_current_dir(&self.original_dir)
+    /// Called when the scope ends in a panic or the original directory
+    /// can't be restored.
+    /// `left` is how restoring the original directory went.
+    fn on_failure(&self, left: &Result<PathBuf, std::io::Error>) {
+        #[cfg(feature = "bundle")]
+        self.write_failure_bundle(left);
+        #[cfg(not(feature = "bundle"))]
+        let _ = left;
     }
 
     /// Return to original working directory. This is exactly the
     /// same as dropping the instance but will not panic.
-    pub fn leave(mut self) -> Result<(), std::io::Error> {
+    pub fn leave(self) -> Result<(), std::io::Error> {
+        self.leave_into().map(|_| ())
+    }
+
+    /// Same as [leave](crate::WithDir::leave), but returns the absolute
+    /// path of the directory that was left, the one that was restored and,
+    /// for
+    /// [temp](crate::WithDir::temp) directories, the [TempDir] so the
+    /// directory lives on until the caller drops it.
+    pub fn leave_into(self) -> Result<Left, std::io::Error> {
+        let clobbered = self.check_restore();
         let ret = self.reset_cwd();
+        self.release(clobbered, ret)
+    }
+
+    /// The rest of [leave_into](crate::WithDir::leave_into) once the
+    /// original directory has been restored, or not
+    fn release(
+        mut self,
+        clobbered: Result<(), std::io::Error>,
+        ret: Result<PathBuf, std::io::Error>,
+    ) -> Result<Left, std::io::Error> {
+        if ret.is_err() {
+            self.on_failure(&ret);
+        }
+        self.unregister();
+        self.restore_process_vars();
         self.mutex = None;
-        ret
+        #[cfg(feature = "async")]
+        let _ = self.gate.take();
+        let restored = ret.and_then(|r| clobbered.map(|_| r))?;
+        let path = self.absolute_path().to_owned();
+        let temp_dir = match std::mem::replace(&mut self.cwd, Cwd::NotTemp(path.clone())) {
+            Cwd::Temp(t) => Some(t),
+            _ => None,
+        };
+        Ok(Left {
+            path,
+            restored,
+            temp_dir,
+        })
+    }
+}
+
+/// Restores the directory a [WithDir] was created in
+struct Restore<'r> {
+    /// Absolute path of the directory being left
+    path: Cow<'r, Path>,
+    original_dir: Cow<'r, Path>,
+    backend: Arc<dyn CwdBackend>,
+    retry: Retry,
+    /// See [WithDir::unchanged]
+    unchanged: bool,
+    /// Tell observers, which weren't told about entering an unregistered
+    /// guard
+    notify: bool,
+}
+
+impl Restore<'_> {
+    /// Own the paths, to restore on another thread
+    #[cfg(feature = "async")]
+    fn into_owned(self) -> Restore<'static> {
+        Restore {
+            path: Cow::Owned(self.path.into_owned()),
+            original_dir: Cow::Owned(self.original_dir.into_owned()),
+            backend: self.backend,
+            retry: self.retry,
+            unchanged: self.unchanged,
+            notify: self.notify,
+        }
+    }
+
+    fn run(&self) -> Result<PathBuf, std::io::Error> {
+        let path = &*self.path;
+        let restored = match self.restore_changed() {
+            Ok(()) => Ok(self.original_dir.to_path_buf()),
+            Err(e) if e.kind() == ErrorKind::NotFound => self.restore_ancestor().ok_or(e),
+            Err(e) => Err(e),
+        };
+        match restored {
+            Ok(dir) => {
+                #[cfg(feature = "log")]
+                log::debug!("left {} for {}", path.display(), dir.display());
+                if self.notify {
+                    observer::notify(|o| o.on_exit(path, &dir));
+                }
+                Ok(dir)
+            }
+            Err(e) => {
+                #[cfg(feature = "metrics")]
+                stats::restore_failed();
+                #[cfg(feature = "log")]
+                log::warn!(
+                    "failed to restore {} when leaving {}: {}",
+                    self.original_dir.display(),
+                    path.display(),
+                    e
+                );
+                if self.notify {
+                    observer::notify(|o| o.on_restore_error(path, &self.original_dir, &e));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Change back to the original directory, unless this instance never
+    /// left it and nothing has moved the process since
+    fn restore_changed(&self) -> Result<(), std::io::Error> {
+        if self.unchanged && self.backend.current_dir().ok().as_deref() == Some(&*self.original_dir)
+        {
+            return Ok(());
+        }
+        self.retry
+            .run(|| self.backend.set_current_dir(&self.original_dir))
     }
+
+    /// When the original directory has been deleted, change to its nearest
+    /// ancestor that can still be entered and say so on stderr
+    fn restore_ancestor(&self) -> Option<PathBuf> {
+        let dir = self
+            .original_dir
+            .ancestors()
+            .skip(1)
+            .find(|a| self.backend.set_current_dir(a).is_ok())?;
+        eprintln!(
+            "with_dir: {} no longer exists, restored {} instead",
+            self.original_dir.display(),
+            dir.display()
+        );
+        Some(dir.to_owned())
+    }
+}
+
+/// Returned by [WithDir::leave_into](crate::WithDir::leave_into)
+#[derive(Debug)]
+pub struct Left {
+    /// Absolute path of the directory that was left
+    pub path: PathBuf,
+    /// The directory that is current again. That is the
+    /// [original_path](crate::WithDir::original_path), unless it no longer
+    /// existed and its nearest ancestor that did was restored instead.
+    pub restored: PathBuf,
+    /// The temporary directory, if it was one. It is deleted when this is
+    /// dropped unless [keep](tempfile::TempDir::keep) is called.
+    pub temp_dir: Option<TempDir>,
 }
 
 impl AsRef<Path> for WithDir<'_> {
@@ -142,16 +1186,82 @@ impl AsRef<Path> for WithDir<'_> {
     }
 }
 
+impl std::fmt::Debug for WithDir<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WithDir")
+            .field("original", &self.original_dir)
+            .field("path", &self.path())
+            .field("temp", &matches!(self.cwd, Cwd::Temp(_)))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Shows the original and entered directories, e.g. `/home/me/proj -> /tmp/xyz`
+impl std::fmt::Display for WithDir<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} -> {}",
+            self.original_dir.display(),
+            self.absolute_path().display()
+        )
+    }
+}
+
+impl std::ops::Deref for WithDir<'_> {
+    type Target = Path;
+
+    /// Same as [path](crate::WithDir::path), so `Path` methods can be called
+    /// directly.
+    fn deref(&self) -> &Path {
+        self.path()
+    }
+}
+
 impl Drop for WithDir<'_> {
     /// Resets current working directory to whatever it was
     /// when this instance was created.
     ///
     /// # Panics
     ///
-    /// Panics if the original directory is no longer accesible (has been deleted, etc.)
+    /// Panics if the original directory is no longer accesible and neither
+    /// is any of its parents (a deleted original directory is replaced by
+    /// its nearest existing ancestor), or if the directory was changed behind this instance's back and
+    /// [on_clobber](crate::WithDir::on_clobber) is [Panic](crate::Clobber::Panic).
+    /// When already panicking it doesn't panic again, the directory state
+    /// is [poisoned](crate::is_poisoned) instead if the original directory
+    /// couldn't be restored.
     fn drop(&mut self) {
+        if std::thread::panicking() {
+            match (self.keep_on_panic, &mut self.cwd) {
+                (true, Cwd::Temp(t)) => {
+                    t.disable_cleanup(true);
+                    eprintln!("with_dir: keeping {}", t.path().display());
+                }
+                (true, Cwd::Ephemeral(e)) => {
+                    e.keep = true;
+                    eprintln!("with_dir: keeping {}", e.remove.display());
+                }
+                _ => {}
+            }
+        }
         if self.mutex.is_some() {
-            self.reset_cwd().unwrap();
+            #[cfg(feature = "async")]
+            asynchronous::warn_if_dropped_in_runtime(self.absolute_path());
+            let clobbered = self.check_restore();
+            let ret = self.reset_cwd();
+            if std::thread::panicking() || ret.is_err() {
+                self.on_failure(&ret);
+            }
+            self.unregister();
+            self.restore_process_vars();
+            if std::thread::panicking() {
+                // panicking again would abort, poison instead
+                self.poison_unless_restored(ret);
+                return;
+            }
+            ret.unwrap();
+            clobbered.unwrap();
         }
     }
 }
@@ -163,9 +1273,16 @@ pub struct ReadmeDoctests;
 
 #[cfg(test)]
 mod tests {
-    use std::{fs::create_dir_all, thread};
+    use std::{
+        env::set_current_dir,
+        fs::{create_dir, create_dir_all},
+        thread,
+    };
 
     use super::*;
+    // `super::*` also brings in `#[with_dir::test]` with the `macros` feature
+    #[allow(unused_imports)]
+    use core::prelude::v1::test;
 
     #[test]
     fn it_works() {
@@ -278,4 +1395,360 @@ mod tests {
         // temp dir was deleted
         assert!(!dir.unwrap().exists());
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fallback() {
+        let _lock = lock();
+        let cwd = current_dir().unwrap();
+        let doomed = TempDir::new().unwrap();
+        let target = TempDir::new().unwrap();
+        {
+            let _wd = WithDir::new(doomed.path()).unwrap();
+            std::fs::remove_dir(doomed.path()).unwrap();
+            assert!(current_dir().is_err());
+            assert!(WithDir::new(target.path()).is_err());
+
+            WithDir::new_with_fallback(target.path(), Fallback::Path(cwd.clone()))
+                .unwrap()
+                .leave()
+                .unwrap();
+            assert_eq!(current_dir().unwrap(), cwd);
+        }
+        assert_eq!(current_dir().unwrap(), cwd);
+    }
+
+    #[test]
+    fn test_keep_on_panic() {
+        let mut kept = None;
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut wd = WithDir::temp().unwrap();
+            wd.keep_on_panic();
+            kept = Some(wd.path().to_owned());
+            panic!("test failed");
+        }));
+        assert!(result.is_err());
+        let kept = kept.unwrap();
+        assert!(kept.exists());
+        std::fs::remove_dir(kept).unwrap();
+    }
+
+    #[cfg(feature = "macros")]
+    #[crate::test]
+    fn test_macro() {
+        assert!(current_dir().unwrap().starts_with(std::env::temp_dir()));
+        std::fs::write("out", "").unwrap();
+    }
+
+    #[cfg(feature = "macros")]
+    #[test]
+    fn test_with_dir_macro() {
+        #[crate::with_dir("macro/dir", create)]
+        fn in_dir() -> PathBuf {
+            current_dir().unwrap()
+        }
+
+        #[crate::with_dir("macro")]
+        fn again() -> bool {
+            Path::new("dir").exists()
+        }
+
+        let tmp = WithDir::temp().unwrap();
+        let cwd = current_dir().unwrap();
+        assert_eq!(in_dir(), cwd.join("macro/dir"));
+        assert!(again());
+        assert_eq!(current_dir().unwrap(), tmp.path());
+    }
+
+    #[test]
+    fn test_lock() {
+        let guard = lock();
+        let cwd = current_dir().unwrap();
+        let t = thread::spawn(|| {
+            let _wd = WithDir::temp().unwrap();
+        });
+        // the other thread can't enter while we hold the lock
+        thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!t.is_finished());
+        assert_eq!(current_dir().unwrap(), cwd);
+
+        // reentrant on this thread
+        WithDir::temp().unwrap();
+        drop(guard);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_wait_until_free() {
+        let guard = lock();
+        assert!(is_locked());
+        let t = thread::spawn(|| {
+            assert!(!wait_until_free(std::time::Duration::from_millis(10)));
+            wait_until_free(std::time::Duration::from_secs(60))
+        });
+        thread::sleep(std::time::Duration::from_millis(50));
+        drop(guard);
+        assert!(t.join().unwrap());
+    }
+
+    #[test]
+    fn test_guarded() {
+        let wd = WithDir::temp().unwrap();
+        let path = wd.path().to_owned();
+        // guarded on another thread waits for wd to be dropped
+        let t = thread::spawn(|| guarded(|| current_dir().unwrap()));
+        drop(wd);
+        assert_ne!(t.join().unwrap(), path);
+        assert_eq!(guarded(|| 1 + 1), 2);
+    }
+
+    #[test]
+    fn test_isolated() {
+        let path = isolated(|dir| {
+            dir.write("a", "").unwrap();
+            dir.path().to_owned()
+        })
+        .unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_current() {
+        let wd = WithDir::current().unwrap();
+        let cwd = current_dir().unwrap();
+        assert_eq!(wd.path(), cwd);
+        let t = thread::spawn(|| {
+            let _wd = WithDir::temp().unwrap();
+        });
+        thread::sleep(std::time::Duration::from_millis(50));
+        assert_eq!(current_dir().unwrap(), cwd);
+        drop(wd);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_is_current() {
+        let mut wd = WithDir::temp().unwrap();
+        wd.on_clobber(Clobber::Ignore);
+        assert!(wd.is_current());
+        create_dir("inner").unwrap();
+        {
+            let inner = WithDir::new("inner").unwrap();
+            assert!(inner.is_current());
+            assert!(!wd.is_current());
+        }
+        assert!(wd.is_current());
+        set_current_dir("inner").unwrap();
+        assert!(!wd.is_current());
+    }
+
+    #[test]
+    fn test_refresh() {
+        let wd = WithDir::temp().unwrap();
+        assert!(!wd.refresh().unwrap());
+        create_dir("inner").unwrap();
+        set_current_dir("inner").unwrap();
+        assert!(wd.refresh().unwrap());
+        assert!(wd.is_current());
+        assert!(Path::new("inner").exists());
+    }
+
+    #[test]
+    fn test_clobber() {
+        let tmp = WithDir::temp().unwrap();
+        create_dir("inner").unwrap();
+        let mut wd = WithDir::new(tmp.path()).unwrap();
+        wd.on_clobber(Clobber::Panic);
+        set_current_dir("inner").unwrap();
+        assert!(wd.leave().is_err());
+        assert!(tmp.is_current());
+
+        let mut wd = WithDir::new(tmp.path()).unwrap();
+        wd.on_clobber(Clobber::Panic);
+        set_current_dir("inner").unwrap();
+        let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(wd)));
+        assert!(err.is_err());
+        assert!(tmp.is_current());
+    }
+
+    #[test]
+    fn test_original_path() {
+        let tmp = WithDir::temp().unwrap();
+        create_dir("inner").unwrap();
+        let wd = WithDir::new("inner").unwrap();
+        assert_eq!(wd.original_path(), tmp.path());
+        assert_eq!(wd.original_path().join("inner"), current_dir().unwrap());
+    }
+
+    #[test]
+    fn test_new_with_origin() {
+        let tmp = WithDir::temp().unwrap();
+        create_dir("inner").unwrap();
+        let wd = WithDir::new_with_origin("inner", tmp.path()).unwrap();
+        assert_eq!(wd.path(), tmp.path().join("inner"));
+        drop(wd);
+        assert!(tmp.is_current());
+        let err = WithDir::new_with_origin("inner", "relative").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_new_unchecked() {
+        let tmp = WithDir::temp().unwrap();
+        create_dir("inner").unwrap();
+        let count = stack().len();
+        // SAFETY: `tmp` holds the lock, so no other test can move the directory
+        let wd = unsafe { WithDir::new_unchecked("inner") }.unwrap();
+        assert_eq!(wd.path(), tmp.path().join("inner"));
+        assert_eq!(stack().len(), count);
+        drop(wd);
+        assert!(tmp.is_current());
+        assert_eq!(stack().len(), count);
+    }
+
+    #[test]
+    fn test_previous() {
+        let tmp = WithDir::temp().unwrap();
+        create_dir("inner").unwrap();
+        let wd = WithDir::new("inner").unwrap();
+        let back = wd.previous().unwrap();
+        assert!(tmp.is_current());
+        assert_eq!(back.original_path(), wd.path());
+        drop(back);
+        assert!(wd.is_current());
+    }
+
+    #[test]
+    fn test_leave_into() {
+        let tmp = WithDir::temp().unwrap();
+        create_dir("inner").unwrap();
+        let left = WithDir::new("inner").unwrap().leave_into().unwrap();
+        assert_eq!(left.path, tmp.path().join("inner"));
+        assert!(left.temp_dir.is_none());
+
+        let left = WithDir::temp().unwrap().leave_into().unwrap();
+        assert!(tmp.is_current());
+        assert!(left.path.exists());
+        assert_eq!(left.temp_dir.as_ref().unwrap().path(), left.path);
+        drop(left);
+    }
+
+    #[test]
+    fn test_deref() {
+        let wd = WithDir::temp().unwrap();
+        std::fs::write("f", "").unwrap();
+        assert!(wd.exists());
+        assert!(wd.join("f").is_file());
+        assert_eq!(wd.file_name(), wd.path().file_name());
+    }
+
+    #[test]
+    fn test_fmt() {
+        let tmp = WithDir::temp().unwrap();
+        create_dir("inner").unwrap();
+        let wd = WithDir::new("inner").unwrap();
+        assert_eq!(
+            wd.to_string(),
+            format!(
+                "{} -> {}",
+                tmp.path().display(),
+                tmp.path().join("inner").display()
+            )
+        );
+        let debug = format!("{:?}", tmp);
+        assert!(debug.starts_with("WithDir {"));
+        assert!(debug.contains("temp: true"));
+        assert!(format!("{:?}", wd).contains("temp: false"));
+    }
+
+    #[test]
+    fn test_temp_path_is_current_dir() {
+        let wd = WithDir::temp().unwrap();
+        assert_eq!(wd.path(), current_dir().unwrap());
+        assert_eq!(wd.path(), std::fs::canonicalize(wd.path()).unwrap());
+    }
+
+    #[test]
+    fn test_create_ephemeral() {
+        let _tmp = WithDir::temp().unwrap();
+        create_dir("kept").unwrap();
+        {
+            let _wd = WithDir::create_ephemeral("scratch").unwrap();
+            std::fs::write("f", "").unwrap();
+        }
+        assert!(!Path::new("scratch").exists());
+        {
+            let _wd = WithDir::create_all_ephemeral("kept/a/b").unwrap();
+        }
+        assert!(Path::new("kept").exists());
+        assert!(!Path::new("kept/a").exists());
+        assert!(WithDir::create_ephemeral("kept").is_err());
+        assert!(Path::new("kept").exists());
+    }
+
+    #[test]
+    fn test_new_or_create() {
+        let tmp = WithDir::temp().unwrap();
+        {
+            let wd = WithDir::new_or_create("a/b").unwrap();
+            assert_eq!(wd.original_path(), tmp.path());
+            assert!(wd.is_current());
+        }
+        std::fs::write("a/b/f", "").unwrap();
+        let _wd = WithDir::new_or_create("a/b").unwrap();
+        assert!(Path::new("f").exists());
+    }
+
+    #[test]
+    fn test_restore_ancestor() {
+        let tmp = WithDir::temp().unwrap();
+        create_dir_all("a/b").unwrap();
+        let outer = WithDir::new("a/b").unwrap();
+        let inner = WithDir::new(tmp.path()).unwrap();
+        std::fs::remove_dir_all("a").unwrap();
+        let left = inner.leave_into().unwrap();
+        assert_eq!(left.restored, tmp.path());
+        assert!(tmp.is_current());
+        assert_eq!(outer.leave_into().unwrap().restored, tmp.path());
+    }
+
+    #[test]
+    fn test_relative_path_is_resolved() {
+        let tmp = WithDir::temp().unwrap();
+        create_dir_all("a/b").unwrap();
+        let a = WithDir::new("a").unwrap();
+        let b = WithDir::new("b").unwrap();
+        assert_eq!(a.path(), tmp.path().join("a"));
+        assert_eq!(b.path(), tmp.path().join("a/b"));
+        assert!(a.path().is_dir());
+    }
+
+    #[test]
+    fn test_requested_path() {
+        let tmp = WithDir::temp().unwrap();
+        assert_eq!(tmp.requested_path(), tmp.path());
+        create_dir("a").unwrap();
+        let wd = WithDir::new("a/../a").unwrap();
+        assert_eq!(wd.requested_path(), Path::new("a/../a"));
+        assert_eq!(wd.absolute_path(), tmp.path().join("a/../a"));
+    }
+
+    #[test]
+    fn test_new_unsynchronized() {
+        // the temp dir's lock keeps other tests out
+        let tmp = WithDir::temp().unwrap();
+        create_dir("a").unwrap();
+        let wd = WithDir::new_unsynchronized("a").unwrap();
+        assert!(wd.is_current());
+        wd.leave().unwrap();
+        assert!(tmp.is_current());
+    }
+
+    #[test]
+    fn test_test_name() {
+        let wd = WithDir::temp().unwrap();
+        assert_eq!(wd.test_name(), Some("tests::test_test_name"));
+        let name = wd.path().file_name().unwrap().to_string_lossy();
+        assert!(name.starts_with(".tmp-tests__test_test_name-"));
+    }
 }
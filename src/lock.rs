@@ -0,0 +1,104 @@
+//! Advisory reader/writer locks on a [WithDir](crate::WithDir) scope, backed
+//! by [fs4](https://docs.rs/fs4). Requires the `fs4` feature.
+use fs4::FileExt;
+use std::{
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+};
+
+use crate::WithDir;
+
+/// Name of the lock file created inside the scope directory.
+pub const LOCK_FILE_NAME: &str = ".with_dir.lock";
+
+/// RAII guard for an advisory lock on a scope directory. The lock is
+/// released when this is dropped or [unlock](crate::DirLock::unlock) is called.
+///
+/// Locks are advisory, they only coordinate processes that also take them.
+/// They do not interact with the in-process mutex used by `WithDir`.
+pub struct DirLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl DirLock {
+    fn open(dir: &Path) -> Result<File, std::io::Error> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOCK_FILE_NAME))
+    }
+
+    pub(crate) fn shared(dir: &Path) -> Result<DirLock, std::io::Error> {
+        let file = Self::open(dir)?;
+        FileExt::lock_shared(&file)?;
+        Ok(DirLock {
+            file,
+            path: dir.join(LOCK_FILE_NAME),
+        })
+    }
+
+    pub(crate) fn exclusive(dir: &Path) -> Result<DirLock, std::io::Error> {
+        let file = Self::open(dir)?;
+        FileExt::lock(&file)?;
+        Ok(DirLock {
+            file,
+            path: dir.join(LOCK_FILE_NAME),
+        })
+    }
+
+    /// Path to the lock file backing this guard
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Release the lock. This is the same as dropping the guard but
+    /// reports any error from the OS.
+    pub fn unlock(self) -> Result<(), std::io::Error> {
+        FileExt::unlock(&self.file)
+    }
+}
+
+impl WithDir<'_> {
+    /// Take a shared (reader) advisory lock on this scope. Blocks until
+    /// no other process holds an [exclusive_lock](crate::WithDir::exclusive_lock).
+    /// The lock is taken on a file named [LOCK_FILE_NAME](crate::lock::LOCK_FILE_NAME)
+    /// inside the directory, which is created if it doesn't exist.
+    pub fn shared_lock(&self) -> Result<DirLock, std::io::Error> {
+        DirLock::shared(self.path())
+    }
+
+    /// Take an exclusive (writer) advisory lock on this scope. Blocks until
+    /// no other process holds any lock on it.
+    pub fn exclusive_lock(&self) -> Result<DirLock, std::io::Error> {
+        DirLock::exclusive(self.path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locks() {
+        let wd = WithDir::temp().unwrap();
+        let a = wd.shared_lock().unwrap();
+        let b = wd.shared_lock().unwrap();
+        assert!(a.path().exists());
+
+        // a second handle can't take an exclusive lock while readers exist
+        let other = DirLock::open(wd.path()).unwrap();
+        assert!(FileExt::try_lock(&other).is_err());
+
+        a.unlock().unwrap();
+        drop(b);
+        assert!(FileExt::try_lock(&other).is_ok());
+        FileExt::unlock(&other).unwrap();
+
+        let e = wd.exclusive_lock().unwrap();
+        assert!(FileExt::try_lock_shared(&other).is_err());
+        drop(e);
+    }
+}
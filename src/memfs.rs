@@ -0,0 +1,273 @@
+//! A [CwdBackend] that keeps directories in memory, see [MemBackend].
+//! Requires the `memfs` feature.
+use std::{
+    collections::BTreeSet,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+};
+
+use parking_lot::Mutex;
+use tempfile::TempDir;
+
+use crate::{backend::Metadata, CwdBackend};
+
+/// A [CwdBackend] with its own current directory and set of directories,
+/// held in memory, that never touches the real file system or process
+/// current directory. It starts with just the root `/` as the current
+/// directory. Install it with [Options::backend](crate::Options::backend)
+/// or [set_backend](crate::set_backend) to unit test code that uses
+/// `WithDir` without a writable file system, for example under Miri.
+///
+/// Only directories exist, there are no files. Paths are normalized
+/// lexically, without symlinks. [WithDir::temp](crate::WithDir::temp) isn't
+/// supported, since a [TempDir] has to be on disk, use
+/// [create](crate::WithDir::create) instead.
+///
+/// ```
+/// use std::{path::Path, sync::Arc};
+/// use with_dir::{MemBackend, WithDir};
+///
+/// let fs = Arc::new(MemBackend::new());
+/// fs.add_dir("/srv/app");
+/// let wd = WithDir::options().backend(fs.clone()).enter("/srv/app").unwrap();
+/// assert_eq!(fs.cwd(), Path::new("/srv/app"));
+/// drop(wd);
+/// assert_eq!(fs.cwd(), Path::new("/"));
+/// ```
+#[derive(Debug)]
+pub struct MemBackend {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    cwd: PathBuf,
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl State {
+    /// `path` made absolute against the current directory, with `.` and
+    /// `..` removed
+    fn resolve(&self, path: &Path) -> PathBuf {
+        let mut out = self.cwd.clone();
+        for c in path.components() {
+            match c {
+                Component::Prefix(_) | Component::RootDir => out = PathBuf::from("/"),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    out.pop();
+                }
+                Component::Normal(n) => out.push(n),
+            }
+        }
+        out
+    }
+}
+
+fn not_found(path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+impl Default for MemBackend {
+    fn default() -> Self {
+        MemBackend::new()
+    }
+}
+
+impl MemBackend {
+    /// A file system with only the root directory, which is current
+    pub fn new() -> MemBackend {
+        MemBackend {
+            state: Mutex::new(State {
+                cwd: PathBuf::from("/"),
+                dirs: BTreeSet::from([PathBuf::from("/")]),
+            }),
+        }
+    }
+
+    /// Make `path` and any missing parents, relative to the current
+    /// directory, as [create_dir_all](std::fs::create_dir_all) would
+    pub fn add_dir(&self, path: impl AsRef<Path>) {
+        let mut state = self.state.lock();
+        let path = state.resolve(path.as_ref());
+        for a in path.ancestors() {
+            state.dirs.insert(a.to_owned());
+        }
+    }
+
+    /// Whether the directory `path` exists
+    pub fn is_dir(&self, path: impl AsRef<Path>) -> bool {
+        let state = self.state.lock();
+        state.dirs.contains(&state.resolve(path.as_ref()))
+    }
+
+    /// The current directory
+    pub fn cwd(&self) -> PathBuf {
+        self.state.lock().cwd.clone()
+    }
+}
+
+impl CwdBackend for MemBackend {
+    fn current_dir(&self) -> Result<PathBuf, std::io::Error> {
+        let state = self.state.lock();
+        match state.dirs.contains(&state.cwd) {
+            true => Ok(state.cwd.clone()),
+            false => Err(not_found(&state.cwd)),
+        }
+    }
+
+    fn set_current_dir(&self, path: &Path) -> Result<(), std::io::Error> {
+        let mut state = self.state.lock();
+        let path = state.resolve(path);
+        if !state.dirs.contains(&path) {
+            return Err(not_found(&path));
+        }
+        state.cwd = path;
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &Path, recursive: bool) -> Result<(), std::io::Error> {
+        let mut state = self.state.lock();
+        let path = state.resolve(path);
+        if state.dirs.contains(&path) {
+            if recursive {
+                return Ok(());
+            }
+            return Err(std::io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            ));
+        }
+        let parent = path.parent().unwrap_or(&path).to_owned();
+        if !recursive && !state.dirs.contains(&parent) {
+            return Err(not_found(&parent));
+        }
+        for a in path.ancestors() {
+            state.dirs.insert(a.to_owned());
+        }
+        Ok(())
+    }
+
+    fn temp_dir(&self, _prefix: &str) -> Result<TempDir, std::io::Error> {
+        Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            "temporary directories aren't supported by MemBackend",
+        ))
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata, std::io::Error> {
+        let state = self.state.lock();
+        let path = state.resolve(path);
+        match state.dirs.contains(&path) {
+            true => Ok(Metadata { is_dir: true }),
+            false => Err(not_found(&path)),
+        }
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        let state = self.state.lock();
+        let path = state.resolve(path);
+        match state.dirs.contains(&path) {
+            true => Ok(path),
+            false => Err(not_found(&path)),
+        }
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<(), std::io::Error> {
+        let mut state = self.state.lock();
+        let path = state.resolve(path);
+        if !state.dirs.contains(&path) {
+            return Err(not_found(&path));
+        }
+        state.dirs.retain(|d| !d.starts_with(&path));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::WithDir;
+
+    #[test]
+    fn test_mem_backend() {
+        let fs = Arc::new(MemBackend::new());
+        let options = || WithDir::options().backend(fs.clone());
+
+        let wd = options()
+            .create(true)
+            .recursive(true)
+            .enter("/mem/a/b")
+            .unwrap();
+        assert!(wd.is_current());
+        assert_eq!(fs.cwd(), Path::new("/mem/a/b"));
+        let inner = options().enter("../..").unwrap();
+        assert_eq!(fs.cwd(), Path::new("/mem"));
+        drop(inner);
+        assert_eq!(fs.cwd(), Path::new("/mem/a/b"));
+        drop(wd);
+        assert_eq!(fs.cwd(), Path::new("/"));
+
+        assert!(fs.is_dir("mem/a"));
+        assert!(options().enter("/mem/missing").is_err());
+        assert!(options().create(true).enter("/x/y").is_err());
+        assert!(options().enter_temp().is_err());
+        assert_eq!(fs.cwd(), Path::new("/"));
+    }
+
+    #[test]
+    fn test_mem_backend_not_disk() {
+        let fs = Arc::new(MemBackend::new());
+        let options = || WithDir::options().backend(fs.clone());
+        // exists on disk, but not in memory
+        let real = std::env::temp_dir();
+
+        let wd = options()
+            .create(true)
+            .recursive(true)
+            .canonicalize(true)
+            .enter(&real)
+            .unwrap();
+        assert!(fs.is_dir(&real));
+        assert_eq!(fs.cwd(), real);
+        drop(wd);
+
+        // exists in memory, but not on disk
+        fs.add_dir("/mem/only");
+        let wd = options()
+            .create(true)
+            .canonicalize(true)
+            .enter("/mem/only")
+            .unwrap();
+        assert_eq!(wd.path(), Path::new("/mem/only"));
+        drop(wd);
+        assert_eq!(fs.cwd(), Path::new("/"));
+    }
+
+    #[test]
+    fn test_mem_backend_ephemeral() {
+        let fs = Arc::new(MemBackend::new());
+        let real = tempfile::tempdir().unwrap();
+        std::fs::write(real.path().join("f"), "x").unwrap();
+        fs.add_dir(real.path().parent().unwrap());
+
+        let wd = WithDir::options()
+            .backend(fs.clone())
+            .create(true)
+            .recursive(true)
+            .ephemeral(true)
+            .enter(real.path().join("a"))
+            .unwrap();
+        assert!(fs.is_dir(real.path()));
+        drop(wd);
+        // removed from memory, the real directory is untouched
+        assert!(!fs.is_dir(real.path()));
+        assert!(fs.is_dir(real.path().parent().unwrap()));
+        assert!(real.path().join("f").exists());
+    }
+}
@@ -0,0 +1,97 @@
+//! Checking that nested [WithDir](crate::WithDir)s are restored innermost
+//! first, see [OutOfOrder].
+use std::path::Path;
+
+use crate::{registry, WithDir};
+
+/// What a [WithDir] does when it is dropped or left while a `WithDir`
+/// created after it on the same thread is still alive. Restoring then
+/// moves the process out of the inner one's directory, which leaves it
+/// running in the wrong place. The original directory is restored either
+/// way. Set with [on_out_of_order](crate::WithDir::on_out_of_order).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutOfOrder {
+    /// Restore without saying anything
+    Ignore,
+    /// Print both directories to stderr
+    #[default]
+    Warn,
+    /// Panic after restoring, unless already panicking.
+    /// [leave](crate::WithDir::leave) returns an error instead.
+    Panic,
+}
+
+impl WithDir<'_> {
+    /// What to do if this instance is dropped or left before one nested
+    /// inside it, see [OutOfOrder]
+    pub fn on_out_of_order(&mut self, out_of_order: OutOfOrder) {
+        self.out_of_order = out_of_order;
+    }
+
+    /// Apply the [OutOfOrder] policy for leaving while `inner` is alive,
+    /// returning an error if it is [Panic](crate::OutOfOrder::Panic)
+    fn check_out_of_order(&self, inner: &Path) -> Result<(), std::io::Error> {
+        let msg = format!(
+            "WithDir for {} left while one for {} nested inside it is still alive",
+            self.absolute_path().display(),
+            inner.display()
+        );
+        match self.out_of_order {
+            OutOfOrder::Ignore => Ok(()),
+            OutOfOrder::Warn => {
+                eprintln!("with_dir: {}", msg);
+                Ok(())
+            }
+            OutOfOrder::Panic => Err(std::io::Error::other(msg)),
+        }
+    }
+
+    /// Check this instance can be restored, by the [OutOfOrder] policy if
+    /// something is nested inside it, otherwise by the
+    /// [Clobber](crate::Clobber) one
+    pub(crate) fn check_restore(&self) -> Result<(), std::io::Error> {
+        match registry::nested_inside(self.id) {
+            Some(inner) => self.check_out_of_order(&inner),
+            None => self.check_clobbered(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::create_dir;
+
+    use super::*;
+
+    #[test]
+    fn test_out_of_order() {
+        let tmp = WithDir::temp().unwrap();
+        let (a, b) = (tmp.path().join("a"), tmp.path().join("b"));
+        create_dir(&a).unwrap();
+        create_dir(&b).unwrap();
+
+        let mut outer = WithDir::new(&a).unwrap();
+        outer.on_out_of_order(OutOfOrder::Panic);
+        let inner = WithDir::new(&b).unwrap();
+        assert!(outer.leave().is_err());
+        assert!(tmp.is_current());
+        // restores the outer guard's directory, which is what went wrong
+        inner.leave().unwrap();
+        assert!(tmp.refresh().unwrap());
+
+        let mut outer = WithDir::new(&a).unwrap();
+        outer.on_out_of_order(OutOfOrder::Panic);
+        let inner = WithDir::new(&b).unwrap();
+        let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(outer)));
+        assert!(err.is_err());
+        drop(inner);
+        tmp.refresh().unwrap();
+
+        // in order is fine
+        let mut outer = WithDir::new(&a).unwrap();
+        outer.on_out_of_order(OutOfOrder::Panic);
+        WithDir::new(&b).unwrap().leave().unwrap();
+        outer.leave().unwrap();
+        assert!(tmp.is_current());
+    }
+}
@@ -0,0 +1,106 @@
+//! Process wide hooks for every [WithDir](crate::WithDir) transition, for
+//! attaching reporting or tracing without wrapping each guard.
+use std::{backtrace::Backtrace, path::Path, sync::Arc, time::Duration};
+
+use parking_lot::Mutex;
+
+/// Receives every [WithDir](crate::WithDir) transition once registered with
+/// [add_observer]. All methods do nothing by default. Unless noted they are
+/// called on the thread that owns the guard, with the directory lock held,
+/// so they may create `WithDir`s of their own.
+pub trait DirChangeObserver: Send + Sync {
+    /// The current directory changed from `from` to `to` when a `WithDir`
+    /// was created
+    fn on_enter(&self, from: &Path, to: &Path) {
+        let _ = (from, to);
+    }
+
+    /// The current directory was restored from `from` to `to` when a
+    /// `WithDir` was dropped or left. After `leave_async` this is called
+    /// from tokio's blocking pool, as is `on_restore_error`.
+    fn on_exit(&self, from: &Path, to: &Path) {
+        let _ = (from, to);
+    }
+
+    /// `original` couldn't be restored when leaving `path`
+    fn on_restore_error(&self, path: &Path, original: &Path, error: &std::io::Error) {
+        let _ = (path, original, error);
+    }
+
+    /// The `WithDir` for `path` is still alive after `limit`, see
+    /// [warn_after](crate::WithDir::warn_after). Called from a background
+    /// thread, without the directory lock.
+    fn on_held_too_long(&self, path: &Path, limit: Duration, backtrace: &Backtrace) {
+        let _ = (path, limit, backtrace);
+    }
+}
+
+static OBSERVERS: Mutex<Vec<Arc<dyn DirChangeObserver>>> = Mutex::new(Vec::new());
+
+/// Register `observer` for all `WithDir`s created from now on, in any
+/// thread.
+pub fn add_observer(observer: Arc<dyn DirChangeObserver>) {
+    OBSERVERS.lock().push(observer);
+}
+
+/// Unregister an observer added with [add_observer]. Returns whether it
+/// was registered.
+pub fn remove_observer(observer: &Arc<dyn DirChangeObserver>) -> bool {
+    let mut observers = OBSERVERS.lock();
+    let len = observers.len();
+    observers.retain(|o| !Arc::ptr_eq(o, observer));
+    observers.len() != len
+}
+
+/// Call `f` on each observer. The list is copied first so observers can
+/// add or remove observers, or create `WithDir`s.
+pub(crate) fn notify(f: impl Fn(&dyn DirChangeObserver)) {
+    let observers = OBSERVERS.lock().clone();
+    for o in observers {
+        f(&*o);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::WithDir;
+
+    #[derive(Default)]
+    struct Record(Mutex<Vec<(&'static str, PathBuf, PathBuf)>>);
+
+    impl DirChangeObserver for Record {
+        fn on_enter(&self, from: &Path, to: &Path) {
+            self.0.lock().push(("enter", from.into(), to.into()));
+        }
+
+        fn on_exit(&self, from: &Path, to: &Path) {
+            self.0.lock().push(("exit", from.into(), to.into()));
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let record = Arc::new(Record::default());
+        let observer: Arc<dyn DirChangeObserver> = record.clone();
+        add_observer(observer.clone());
+
+        let wd = WithDir::temp().unwrap();
+        let path = wd.path().to_owned();
+        drop(wd);
+        assert!(remove_observer(&observer));
+        assert!(!remove_observer(&observer));
+
+        // other tests may be creating WithDirs at the same time
+        let events: Vec<_> = record
+            .0
+            .lock()
+            .iter()
+            .filter(|(_, from, to)| *from == path || *to == path)
+            .map(|(event, ..)| *event)
+            .collect();
+        assert_eq!(events, ["enter", "exit"]);
+    }
+}
@@ -0,0 +1,431 @@
+//! Builder for [WithDir](crate::WithDir), see
+//! [WithDir::options](crate::WithDir::options).
+use std::{io::ErrorKind, path::Path, sync::Arc, time::Duration};
+
+use parking_lot::ReentrantMutexGuard;
+
+use crate::{
+    backend::{self, BackendRef},
+    current_test_name,
+    error::Context,
+    lock_dir_within, poison, temp_prefix, temp_root, Clobber, Cwd, CwdBackend, Ephemeral, Fallback,
+    LockTimeout, Operation, OutOfOrder, Retry, Snapshot, WithDir,
+};
+
+/// Configures how a [WithDir] is created. Start with
+/// [WithDir::options](crate::WithDir::options), set what you need and
+/// finish with [enter](crate::Options::enter) or
+/// [enter_temp](crate::Options::enter_temp).
+///
+/// ```
+/// use with_dir::WithDir;
+///
+/// let tmp = WithDir::temp().unwrap();
+/// let wd = WithDir::options()
+///     .create(true)
+///     .recursive(true)
+///     .label("generated")
+///     .enter("target/generated")
+///     .unwrap();
+/// assert!(wd.is_current());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    create: bool,
+    recursive: bool,
+    canonicalize: bool,
+    lock_timeout: Option<Duration>,
+    fallback: Fallback,
+    clobber: Clobber,
+    out_of_order: OutOfOrder,
+    temp_prefix: Option<String>,
+    keep: bool,
+    keep_on_panic: bool,
+    label: Option<String>,
+    sync_pwd: bool,
+    export_tmpdir: bool,
+    ephemeral: bool,
+    retry: Retry,
+    backend: Option<BackendRef>,
+    #[cfg(unix)]
+    mode: Option<u32>,
+    #[cfg(unix)]
+    owner: (Option<u32>, Option<u32>),
+}
+
+impl Options {
+    /// Create the directory if it doesn't exist. An existing directory is
+    /// not an error.
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// When creating, also create missing parents
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Canonicalize the path before entering it, so
+    /// [path](crate::WithDir::path) is absolute and free of symlinks
+    pub fn canonicalize(mut self, canonicalize: bool) -> Self {
+        self.canonicalize = canonicalize;
+        self
+    }
+
+    /// Give up with a [TimedOut](std::io::ErrorKind::TimedOut) error if
+    /// another thread holds the directory lock for longer than `timeout`.
+    /// The error holds a [LockTimeout](crate::LockTimeout) saying which
+    /// thread, label and directory have it. By default this waits forever.
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = Some(timeout);
+        self
+    }
+
+    /// What to restore to if the current directory can't be read, see
+    /// [new_with_fallback](crate::WithDir::new_with_fallback)
+    pub fn fallback(mut self, fallback: Fallback) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    /// See [on_clobber](crate::WithDir::on_clobber)
+    pub fn on_clobber(mut self, clobber: Clobber) -> Self {
+        self.clobber = clobber;
+        self
+    }
+
+    /// See [on_out_of_order](crate::WithDir::on_out_of_order)
+    pub fn on_out_of_order(mut self, out_of_order: OutOfOrder) -> Self {
+        self.out_of_order = out_of_order;
+        self
+    }
+
+    /// Prefix for the directory made by [enter_temp](crate::Options::enter_temp),
+    /// instead of one based on the test name
+    pub fn temp_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.temp_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Don't delete the directory made by
+    /// [enter_temp](crate::Options::enter_temp) when the `WithDir` is dropped
+    pub fn keep(mut self, keep: bool) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Remove a directory made by [create](crate::Options::create), and any
+    /// parents made with it, on drop, see
+    /// [create_ephemeral](crate::WithDir::create_ephemeral)
+    pub fn ephemeral(mut self, ephemeral: bool) -> Self {
+        self.ephemeral = ephemeral;
+        self
+    }
+
+    /// Retry failed directory changes, both on entering and on restoring
+    /// the original directory, see [Retry]
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Use `backend` instead of the one installed with
+    /// [set_backend](crate::set_backend) for this `WithDir`, both to enter
+    /// the directory and to restore the original one
+    pub fn backend(mut self, backend: Arc<dyn CwdBackend>) -> Self {
+        self.backend = Some(BackendRef(backend));
+        self
+    }
+
+    /// See [keep_on_panic](crate::WithDir::keep_on_panic)
+    pub fn keep_on_panic(mut self, keep_on_panic: bool) -> Self {
+        self.keep_on_panic = keep_on_panic;
+        self
+    }
+
+    /// See [label](crate::WithDir::label)
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// Keep `PWD` and `OLDPWD` in step, see
+    /// [sync_pwd](crate::WithDir::sync_pwd)
+    pub fn sync_pwd(mut self, sync_pwd: bool) -> Self {
+        self.sync_pwd = sync_pwd;
+        self
+    }
+
+    /// Point `TMPDIR` at the directory, see
+    /// [export_tmpdir](crate::WithDir::export_tmpdir)
+    pub fn export_tmpdir(mut self, export_tmpdir: bool) -> Self {
+        self.export_tmpdir = export_tmpdir;
+        self
+    }
+
+    /// Permission bits for a directory made by
+    /// [create](crate::Options::create). They are set exactly, regardless
+    /// of the umask. Parents made by [recursive](crate::Options::recursive)
+    /// get the same bits, filtered by the umask. Unix only.
+    #[cfg(unix)]
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// User and group ids to give a directory made by
+    /// [create](crate::Options::create), `None` leaves that id as it is.
+    /// Changing the user usually needs root. Unix only.
+    #[cfg(unix)]
+    pub fn owner(mut self, uid: Option<u32>, gid: Option<u32>) -> Self {
+        self.owner = (uid, gid);
+        self
+    }
+
+    fn current_backend(&self) -> Arc<dyn CwdBackend> {
+        match &self.backend {
+            Some(b) => b.0.clone(),
+            None => backend::backend(),
+        }
+    }
+
+    fn create_dir(&self, backend: &dyn CwdBackend, path: &Path) -> Result<(), std::io::Error> {
+        #[cfg(unix)]
+        if self.mode.is_some() || self.owner != (None, None) {
+            return self.create_dir_with_mode(path);
+        }
+        backend.create_dir(path, self.recursive)
+    }
+
+    /// Permissions and ownership only mean anything to the OS, so this
+    /// bypasses the backend
+    #[cfg(unix)]
+    fn create_dir_with_mode(&self, path: &Path) -> Result<(), std::io::Error> {
+        use std::os::unix::fs::{chown, DirBuilderExt, PermissionsExt};
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(self.recursive);
+        if let Some(mode) = self.mode {
+            builder.mode(mode);
+        }
+        builder.create(crate::fs::long_path(path))?;
+        if let Some(mode) = self.mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        if let (None, None) = self.owner {
+            return Ok(());
+        }
+        chown(path, self.owner.0, self.owner.1)
+    }
+
+    fn lock(&self) -> Result<ReentrantMutexGuard<'static, ()>, std::io::Error> {
+        let m = lock_dir_within(self.lock_timeout)
+            .ok_or_else(|| LockTimeout::error(self.lock_timeout.unwrap_or_default()))?;
+        poison::check()?;
+        Ok(m)
+    }
+
+    fn finish<'a>(&self, mut wd: WithDir<'a>) -> WithDir<'a> {
+        wd.clobber = self.clobber;
+        wd.out_of_order = self.out_of_order;
+        wd.keep_on_panic = self.keep_on_panic;
+        wd.retry = self.retry;
+        if let Some(b) = &self.backend {
+            wd.backend = b.0.clone();
+        }
+        if let Some(label) = &self.label {
+            wd.label(label.clone());
+        }
+        if self.sync_pwd {
+            wd.sync_pwd();
+        }
+        if self.export_tmpdir {
+            wd.export_tmpdir();
+        }
+        wd
+    }
+
+    /// Change to `path` with these options
+    pub fn enter<'a>(&self, path: impl AsRef<Path>) -> Result<WithDir<'a>, std::io::Error> {
+        let mut path = path.as_ref().to_owned();
+        let backend = self.current_backend();
+        let m = self.lock()?;
+        let original_dir = self.fallback.original_dir(&*backend)?;
+        let mut remove = None;
+        let is_dir = |path: &Path| backend.metadata(path).is_ok_and(|m| m.is_dir);
+        if self.create && !is_dir(&path) {
+            let outermost = Ephemeral::outermost_missing(&*backend, &path)?;
+            match self.create_dir(&*backend, &path) {
+                Ok(()) => remove = Some(outermost),
+                // made by someone else since we looked
+                Err(e) if e.kind() == ErrorKind::AlreadyExists && is_dir(&path) => {}
+                Err(e) => {
+                    return Err(e).entering(Operation::CreateDir, &path, &original_dir);
+                }
+            }
+        }
+        if self.canonicalize {
+            path = backend.canonicalize(&path)?;
+        }
+        let Some(remove) = remove else {
+            self.retry.run(|| backend.set_current_dir(&path)).entering(
+                Operation::SetCurrentDir,
+                &path,
+                &original_dir,
+            )?;
+            let wd = WithDir::from_parts(m, original_dir, Cwd::NotTemp(path));
+            return Ok(self.finish(wd));
+        };
+        let mut wd = if self.ephemeral {
+            WithDir::enter_ephemeral(m, backend, original_dir, &path, remove)?
+        } else {
+            self.retry.run(|| backend.set_current_dir(&path)).entering(
+                Operation::SetCurrentDir,
+                &path,
+                &original_dir,
+            )?;
+            WithDir::from_parts(m, original_dir, Cwd::NotTemp(path))
+        };
+        wd.snapshot = Some(Snapshot::default());
+        Ok(self.finish(wd))
+    }
+
+    /// Change to a new temporary directory with these options, see
+    /// [temp](crate::WithDir::temp)
+    pub fn enter_temp<'a>(&self) -> Result<WithDir<'a>, std::io::Error> {
+        let backend = self.current_backend();
+        let m = self.lock()?;
+        let original_dir = self.fallback.original_dir(&*backend)?;
+        let prefix = match &self.temp_prefix {
+            Some(p) => p.clone(),
+            None => temp_prefix(current_test_name().as_deref()),
+        };
+        let mut temp_dir =
+            backend
+                .temp_dir(&prefix)
+                .entering(Operation::TempDir, &temp_root(), &original_dir)?;
+        temp_dir.disable_cleanup(self.keep);
+        self.retry
+            .run(|| backend.set_current_dir(temp_dir.path()))
+            .entering(Operation::SetCurrentDir, temp_dir.path(), &original_dir)?;
+        let mut wd = WithDir::from_parts(m, original_dir, Cwd::Temp(temp_dir));
+        wd.snapshot = Some(Snapshot::default());
+        Ok(self.finish(wd))
+    }
+}
+
+impl WithDir<'_> {
+    /// Start building a `WithDir` with non default behaviour, see [Options]
+    pub fn options() -> Options {
+        Options::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc::channel, thread};
+
+    use super::*;
+
+    #[test]
+    fn test_options() {
+        let tmp = WithDir::temp().unwrap();
+        let wd = WithDir::options()
+            .create(true)
+            .recursive(true)
+            .canonicalize(true)
+            .label("nested")
+            .enter("a/b")
+            .unwrap();
+        assert!(wd.path().is_absolute());
+        assert_eq!(wd.path(), tmp.path().join("a/b"));
+        assert!(wd.diff().is_ok());
+        drop(wd);
+
+        // existing directories are fine when creating
+        let wd = WithDir::options().create(true).enter("a").unwrap();
+        assert!(wd.diff().is_err());
+        drop(wd);
+        assert!(WithDir::options().create(true).enter("x/y").is_err());
+        assert!(WithDir::options().enter("missing").is_err());
+
+        WithDir::options()
+            .create(true)
+            .recursive(true)
+            .ephemeral(true)
+            .enter("e/f")
+            .unwrap();
+        assert!(!Path::new("e").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mode() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let _tmp = WithDir::temp().unwrap();
+        let wd = WithDir::options()
+            .create(true)
+            .mode(0o707)
+            .owner(None, None)
+            .enter("private")
+            .unwrap();
+        let meta = std::fs::metadata(".").unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o707);
+        drop(wd);
+
+        // chown to our own ids always works
+        let meta = std::fs::metadata(".").unwrap();
+        let wd = WithDir::options()
+            .create(true)
+            .owner(Some(meta.uid()), Some(meta.gid()))
+            .enter("owned")
+            .unwrap();
+        assert_eq!(std::fs::metadata(".").unwrap().uid(), meta.uid());
+        drop(wd);
+    }
+
+    #[test]
+    fn test_options_temp() {
+        let wd = WithDir::options()
+            .temp_prefix("custom-")
+            .keep(true)
+            .enter_temp()
+            .unwrap();
+        let path = wd.path().to_owned();
+        assert!(path
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("custom-"));
+        drop(wd);
+        assert!(path.exists());
+        std::fs::remove_dir(path).unwrap();
+    }
+
+    #[test]
+    fn test_lock_timeout() {
+        let mut wd = WithDir::temp().unwrap();
+        wd.label("holder");
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let r = WithDir::options()
+                .lock_timeout(Duration::from_millis(10))
+                .enter_temp();
+            tx.send(r.err()).unwrap();
+        });
+        let err = rx.recv().unwrap().unwrap();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        let timeout = err
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<LockTimeout>()
+            .unwrap();
+        let holder = timeout.holder.as_ref().unwrap();
+        assert_eq!(holder.path, wd.path());
+        assert_eq!(holder.thread, thread::current().id());
+        assert!(err.to_string().contains("[holder]"));
+    }
+}
@@ -0,0 +1,139 @@
+//! Refusing new [WithDir](crate::WithDir)s after one couldn't put the
+//! directory back, see [is_poisoned].
+use std::path::PathBuf;
+
+use parking_lot::Mutex;
+
+/// Why the directory state is [poisoned](crate::is_poisoned). Constructors
+/// return it inside their [std::io::Error] while it is, get it back with
+/// [get_ref](std::io::Error::get_ref) and `downcast_ref`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Poisoned {
+    /// Absolute path of the directory that was being left
+    pub path: PathBuf,
+    /// The directory that should have been restored
+    pub original: PathBuf,
+    /// Where the process actually was, if that could be read
+    pub actual: Option<PathBuf>,
+}
+
+impl std::fmt::Display for Poisoned {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "directory state is poisoned: leaving {} didn't restore {}",
+            self.path.display(),
+            self.original.display()
+        )?;
+        match &self.actual {
+            Some(actual) => write!(f, ", current directory is {}", actual.display()),
+            None => write!(f, ", current directory can't be read"),
+        }
+    }
+}
+
+impl std::error::Error for Poisoned {}
+
+static POISONED: Mutex<Option<Poisoned>> = Mutex::new(None);
+
+/// Whether a `WithDir` failed to restore the original directory, or
+/// couldn't confirm it had, while being dropped by a panic. Like a
+/// poisoned [std::sync::Mutex] every constructor then fails, so later tests
+/// don't silently run in the wrong directory, until [clear_poison] is
+/// called.
+///
+/// ```
+/// assert!(!with_dir::is_poisoned());
+/// ```
+pub fn is_poisoned() -> bool {
+    POISONED.lock().is_some()
+}
+
+/// Let `WithDir`s be created again after the directory state was
+/// [poisoned](is_poisoned), returning what poisoned it. Change to a
+/// directory you trust first.
+pub fn clear_poison() -> Option<Poisoned> {
+    POISONED.lock().take()
+}
+
+/// Poison the directory state, keeping the first reason if it already is
+pub(crate) fn poison(poisoned: Poisoned) {
+    #[cfg(feature = "log")]
+    log::error!("{}", poisoned);
+    eprintln!("with_dir: {}", poisoned);
+    POISONED.lock().get_or_insert(poisoned);
+}
+
+/// Fails with a [Poisoned] error if the directory state is poisoned
+pub(crate) fn check() -> Result<(), std::io::Error> {
+    match &*POISONED.lock() {
+        Some(p) => Err(std::io::Error::other(p.clone())),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        panic::{catch_unwind, AssertUnwindSafe},
+        path::Path,
+        sync::Arc,
+    };
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{clear_poison, is_poisoned, CwdBackend, WithDir};
+
+    /// Can enter `/stuck` but never get back out
+    struct Stuck(Mutex<PathBuf>);
+
+    impl CwdBackend for Stuck {
+        fn current_dir(&self) -> Result<PathBuf, std::io::Error> {
+            Ok(self.0.lock().clone())
+        }
+
+        fn set_current_dir(&self, path: &Path) -> Result<(), std::io::Error> {
+            if path != Path::new("/stuck") {
+                return Err(std::io::ErrorKind::PermissionDenied.into());
+            }
+            *self.0.lock() = path.to_owned();
+            Ok(())
+        }
+
+        fn create_dir(&self, _path: &Path, _recursive: bool) -> Result<(), std::io::Error> {
+            Err(std::io::ErrorKind::Unsupported.into())
+        }
+
+        fn temp_dir(&self, _prefix: &str) -> Result<TempDir, std::io::Error> {
+            Err(std::io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    #[test]
+    fn test_poison() {
+        // other threads can't see the poison before it is cleared
+        let _lock = crate::lock();
+        let stuck = Arc::new(Stuck(Mutex::new(PathBuf::from("/start"))));
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let _wd = WithDir::options()
+                .backend(stuck.clone())
+                .enter("/stuck")
+                .unwrap();
+            panic!("test failed");
+        }));
+        assert!(result.is_err());
+        assert!(is_poisoned());
+
+        let err = WithDir::current().unwrap_err();
+        let poisoned = err.get_ref().unwrap().downcast_ref::<Poisoned>().unwrap();
+        assert_eq!(poisoned.original, Path::new("/start"));
+        assert_eq!(poisoned.actual.as_deref(), Some(Path::new("/stuck")));
+        assert!(WithDir::options().enter_temp().is_err());
+        assert!(WithDir::new_unsynchronized(".").is_err());
+
+        assert_eq!(clear_poison().as_ref(), Some(poisoned));
+        assert!(!is_poisoned());
+        WithDir::current().unwrap();
+    }
+}
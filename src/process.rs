@@ -0,0 +1,123 @@
+//! Integrations with process spawning crates. Each is behind its own feature.
+#[cfg(feature = "tokio-process")]
+use std::ffi::OsStr;
+
+#[cfg(any(feature = "assert_cmd", feature = "duct", feature = "tokio-process"))]
+use crate::WithDir;
+
+#[cfg(feature = "assert_cmd")]
+impl WithDir<'_> {
+    /// An [assert_cmd::Command] that runs in this directory, see
+    /// [command](crate::WithDir::command). If cargo has set
+    /// `CARGO_BIN_EXE_<bin>` in the environment that binary is used, otherwise
+    /// `bin` is run as given. In integration tests on older toolchains, pass
+    /// `assert_cmd::cargo::cargo_bin!("mycli")` to get the package's binary.
+    /// Requires the `assert_cmd` feature.
+    ///
+    /// ```no_run
+    /// # use with_dir::WithDir;
+    /// WithDir::temp().unwrap().assert_cmd("mycli").arg("build").assert().success();
+    /// ```
+    pub fn assert_cmd(&self, bin: impl AsRef<std::ffi::OsStr>) -> assert_cmd::Command {
+        let mut var = std::ffi::OsString::from("CARGO_BIN_EXE_");
+        var.push(bin.as_ref());
+        let program = std::env::var_os(var).unwrap_or_else(|| bin.as_ref().to_owned());
+        assert_cmd::Command::from_std(self.command(program))
+    }
+}
+
+#[cfg(feature = "duct")]
+impl WithDir<'_> {
+    /// `expression` with its working directory set to this directory and
+    /// the variables from [to_env_map](crate::WithDir::to_env_map) added.
+    /// Requires the `duct` feature.
+    ///
+    /// ```no_run
+    /// # use with_dir::WithDir;
+    /// let wd = WithDir::temp().unwrap();
+    /// let out = wd.duct(duct::cmd!("ls", "-a")).read().unwrap();
+    /// ```
+    pub fn duct(&self, expression: duct::Expression) -> duct::Expression {
+        self.to_env_map()
+            .into_iter()
+            .fold(expression.dir(self.absolute_path()), |e, (k, v)| {
+                e.env(k, v)
+            })
+    }
+
+    /// Same as [duct](crate::WithDir::duct) but stdout and stderr are written
+    /// to `<name>.stdout` and `<name>.stderr` in this directory, so they are
+    /// kept alongside the rest of the test's output.
+    pub fn duct_captured(&self, expression: duct::Expression, name: &str) -> duct::Expression {
+        self.duct(expression)
+            .stdout_path(self.resolve(format!("{}.stdout", name)))
+            .stderr_path(self.resolve(format!("{}.stderr", name)))
+    }
+}
+
+#[cfg(feature = "tokio-process")]
+impl WithDir<'_> {
+    /// The [tokio::process::Command] equivalent of
+    /// [command](crate::WithDir::command). Requires the `tokio-process` feature.
+    pub fn tokio_command(&self, program: impl AsRef<OsStr>) -> tokio::process::Command {
+        tokio::process::Command::from(self.command(program))
+    }
+
+    /// Wait for `child` to exit, keeping this `WithDir` alive until it has.
+    /// Useful when the child relies on the directory existing, as it does
+    /// for [temp](crate::WithDir::temp). Requires the `tokio-process` feature.
+    ///
+    /// The returned future is not `Send` as it holds the guard, so it must
+    /// be awaited on the thread that created the `WithDir`.
+    pub async fn wait_for(
+        self,
+        mut child: tokio::process::Child,
+    ) -> Result<std::process::ExitStatus, std::io::Error> {
+        let status = child.wait().await;
+        drop(self);
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(all(unix, feature = "duct"))]
+    #[test]
+    fn test_duct() {
+        use crate::WithDir;
+
+        let wd = WithDir::temp().unwrap();
+        let _elsewhere = WithDir::new(std::env::temp_dir()).unwrap();
+        wd.duct(duct::cmd!("touch", "out")).run().unwrap();
+        wd.assert_exists("out");
+
+        wd.duct_captured(duct::cmd!("sh", "-c", "echo hi; echo err >&2"), "echo")
+            .run()
+            .unwrap();
+        wd.assert_content("echo.stdout", "hi\n");
+        wd.assert_content("echo.stderr", "err\n");
+    }
+
+    #[cfg(all(unix, feature = "assert_cmd"))]
+    #[test]
+    fn test_assert_cmd() {
+        use crate::WithDir;
+
+        let wd = WithDir::temp().unwrap();
+        wd.assert_cmd("touch").arg("out").assert().success();
+        wd.assert_exists("out");
+    }
+
+    #[cfg(all(unix, feature = "tokio-process"))]
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_tokio_command() {
+        use crate::WithDir;
+
+        let wd = WithDir::temp().unwrap();
+        let path = wd.path().join("out");
+        let child = wd.tokio_command("touch").arg("out").spawn().unwrap();
+        assert!(wd.wait_for(child).await.unwrap().success());
+        // wait_for dropped the guard, and with it the temp dir
+        assert!(!path.exists());
+    }
+}
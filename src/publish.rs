@@ -0,0 +1,117 @@
+//! Copying files out of a [WithDir](crate::WithDir) to a destination whose
+//! name is built from a template. Requires the `glob` feature.
+use std::{
+    fs::{copy, create_dir_all},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::WithDir;
+
+fn hostname() -> String {
+    ["HOSTNAME", "COMPUTERNAME"]
+        .iter()
+        .find_map(|v| std::env::var(v).ok())
+        .or_else(|| {
+            std::fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|h| h.trim().to_owned())
+        })
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "localhost".to_owned())
+}
+
+impl WithDir<'_> {
+    /// Expand placeholders in `template`:
+    ///
+    /// * `{timestamp}` - seconds since the unix epoch
+    /// * `{scope_name}` - the final component of [path](crate::WithDir::path)
+    /// * `{hostname}` - the machine's host name, or `localhost` if unknown
+    pub fn render_template(&self, template: &str) -> PathBuf {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let scope_name = self
+            .absolute_path()
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut out = template
+            .replace("{timestamp}", &timestamp.to_string())
+            .replace("{scope_name}", &scope_name);
+        if out.contains("{hostname}") {
+            out = out.replace("{hostname}", &hostname());
+        }
+        PathBuf::from(out)
+    }
+
+    /// Copy every file matching `pattern` (see [glob](crate::WithDir::glob))
+    /// into the directory given by expanding `dest` with
+    /// [render_template](crate::WithDir::render_template), keeping their
+    /// path relative to this directory. Relative destinations are resolved
+    /// against the directory this `WithDir` was created from. Returns the
+    /// paths that were written.
+    ///
+    /// ```no_run
+    /// # use with_dir::WithDir;
+    /// let wd = WithDir::temp().unwrap();
+    /// // ... run the tool under test ...
+    /// wd.publish("**/*.log", "artifacts/{scope_name}-{timestamp}").unwrap();
+    /// ```
+    pub fn publish(&self, pattern: &str, dest: &str) -> Result<Vec<PathBuf>, std::io::Error> {
+        let dest = self.original_dir.join(self.render_template(dest));
+        let root = self.absolute_path();
+        let mut written = Vec::new();
+        for rel in self.glob(pattern)? {
+            let from = root.join(&rel);
+            if !from.is_file() {
+                continue;
+            }
+            let to = dest.join(&rel);
+            if let Some(parent) = to.parent() {
+                create_dir_all(parent)?;
+            }
+            copy(&from, &to)?;
+            written.push(to);
+        }
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{create_dir, read_to_string, write},
+        path::Path,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_publish() {
+        let out = WithDir::temp().unwrap();
+        let wd = WithDir::temp().unwrap();
+        create_dir("logs").unwrap();
+        write("logs/a.log", "a").unwrap();
+        write("b.txt", "b").unwrap();
+
+        let name = wd
+            .path()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        let dest = out.path().join("{scope_name}");
+        let written = wd.publish("**/*.log", dest.to_str().unwrap()).unwrap();
+        let expected: &Path = &out.path().join(&name).join("logs/a.log");
+        assert_eq!(written, [expected]);
+        assert_eq!(read_to_string(expected).unwrap(), "a");
+
+        assert!(!wd
+            .render_template("{timestamp}")
+            .to_string_lossy()
+            .contains('{'));
+        assert!(!wd.render_template("{hostname}").as_os_str().is_empty());
+    }
+}
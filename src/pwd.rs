@@ -0,0 +1,52 @@
+//! Keeping `PWD` and `OLDPWD` in step with a [WithDir](crate::WithDir), see
+//! [sync_pwd](crate::WithDir::sync_pwd).
+use crate::WithDir;
+
+impl WithDir<'_> {
+    /// Set the `PWD` and `OLDPWD` environment variables of the current
+    /// process to this directory and the original one, like a shell's
+    /// `cd`, and put back what they were when this instance is dropped.
+    /// Child processes and scripts that trust `$PWD` then agree with the
+    /// real current directory. Calling it again does nothing.
+    ///
+    /// Like any change to the environment this races with other threads
+    /// reading it, so only use it where they don't.
+    ///
+    /// ```
+    /// use with_dir::WithDir;
+    ///
+    /// let mut wd = WithDir::new("src").unwrap();
+    /// wd.sync_pwd();
+    /// assert_eq!(std::env::var_os("PWD").unwrap(), wd.path());
+    /// ```
+    pub fn sync_pwd(&mut self) -> &mut Self {
+        if !self.has_process_var("PWD") {
+            let (from, to) = (self.original_dir.clone(), self.absolute_path().to_owned());
+            self.set_process_var("OLDPWD", from);
+            self.set_process_var("PWD", to);
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_pwd() {
+        let tmp = WithDir::temp().unwrap();
+        std::fs::create_dir("a").unwrap();
+        std::env::set_var("PWD", tmp.path());
+        std::env::remove_var("OLDPWD");
+
+        let mut wd = WithDir::new("a").unwrap();
+        wd.sync_pwd();
+        assert_eq!(std::env::var_os("PWD").unwrap(), wd.path());
+        assert_eq!(std::env::var_os("OLDPWD").unwrap(), tmp.path());
+        wd.leave().unwrap();
+
+        assert_eq!(std::env::var_os("PWD").unwrap(), tmp.path());
+        assert!(std::env::var_os("OLDPWD").is_none());
+    }
+}
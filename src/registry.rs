@@ -0,0 +1,227 @@
+//! Process wide list of live [WithDir](crate::WithDir)s, see [active].
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    thread::{self, ThreadId},
+    time::SystemTime,
+};
+
+use parking_lot::Mutex;
+
+use crate::WithDir;
+
+/// A live `WithDir`, as returned by [active].
+#[derive(Clone, Debug)]
+pub struct ActiveGuard {
+    /// Absolute path of the directory it entered
+    pub path: PathBuf,
+    /// Thread that created it
+    pub thread: ThreadId,
+    /// Name of that thread, which under `cargo test` is the test name
+    pub thread_name: Option<String>,
+    /// When it was created
+    pub created: SystemTime,
+    /// Set with [label](crate::WithDir::label)
+    pub label: Option<String>,
+}
+
+struct Registry {
+    guards: Vec<(u64, ActiveGuard)>,
+    // bumped on every change, so a reader can tell if the list moved
+    version: u64,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    guards: Vec::new(),
+    version: 0,
+});
+
+/// Starts at 1, a guard with id 0 isn't registered
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+pub(crate) fn register(path: PathBuf) -> u64 {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let current = thread::current();
+    let guard = ActiveGuard {
+        path,
+        thread: current.id(),
+        thread_name: current.name().map(str::to_owned),
+        created: SystemTime::now(),
+        label: None,
+    };
+    let mut registry = REGISTRY.lock();
+    registry.guards.push((id, guard));
+    registry.version += 1;
+    id
+}
+
+pub(crate) fn unregister(id: u64) {
+    let mut registry = REGISTRY.lock();
+    registry.guards.retain(|(i, _)| *i != id);
+    registry.version += 1;
+}
+
+/// The outermost live guard on another thread, which is the one that
+/// has held the directory lock longest
+pub(crate) fn holder() -> Option<ActiveGuard> {
+    let thread = thread::current().id();
+    REGISTRY
+        .lock()
+        .guards
+        .iter()
+        .find(|(_, g)| g.thread != thread)
+        .map(|(_, g)| g.clone())
+}
+
+/// The innermost live guard's path and the version of the list
+#[cfg(feature = "watchdog")]
+pub(crate) fn top() -> (Option<PathBuf>, u64) {
+    let registry = REGISTRY.lock();
+    let path = registry.guards.last().map(|(_, g)| g.path.clone());
+    (path, registry.version)
+}
+
+/// The guard registered as `id`, if it is still alive
+pub(crate) fn guard(id: u64) -> Option<ActiveGuard> {
+    REGISTRY
+        .lock()
+        .guards
+        .iter()
+        .find(|(i, _)| *i == id)
+        .map(|(_, g)| g.clone())
+}
+
+/// Label of the innermost live guard for `path` on this thread
+pub(crate) fn label_of(path: &Path) -> Option<String> {
+    let thread = thread::current().id();
+    REGISTRY
+        .lock()
+        .guards
+        .iter()
+        .rev()
+        .find(|(_, g)| g.thread == thread && g.path == path)
+        .and_then(|(_, g)| g.label.clone())
+}
+
+/// Path of the innermost guard created after `id` on the same thread that
+/// is still alive
+pub(crate) fn nested_inside(id: u64) -> Option<PathBuf> {
+    let registry = REGISTRY.lock();
+    let pos = registry.guards.iter().position(|(i, _)| *i == id)?;
+    let thread = registry.guards[pos].1.thread;
+    registry.guards[pos + 1..]
+        .iter()
+        .rev()
+        .find(|(_, g)| g.thread == thread)
+        .map(|(_, g)| g.path.clone())
+}
+
+/// Every `WithDir` alive in the process, outermost first. Only one thread
+/// can hold `WithDir`s at a time, so when a test run stalls waiting for the
+/// directory lock this shows who has it.
+///
+/// ```
+/// let _wd = with_dir::WithDir::temp().unwrap();
+/// for g in with_dir::active() {
+///     eprintln!("{:?} holds {}", g.thread_name, g.path.display());
+/// }
+/// ```
+pub fn active() -> Vec<ActiveGuard> {
+    REGISTRY
+        .lock()
+        .guards
+        .iter()
+        .map(|(_, g)| g.clone())
+        .collect()
+}
+
+/// The live `WithDir`s created on this thread, outermost first, with
+/// their paths and labels. The last one is where the process should be.
+///
+/// ```
+/// use with_dir::WithDir;
+///
+/// let mut outer = WithDir::temp().unwrap();
+/// outer.label("build");
+/// let inner = WithDir::current().unwrap();
+/// let stack = with_dir::stack();
+/// assert_eq!(stack.len(), 2);
+/// assert_eq!(stack[0].label.as_deref(), Some("build"));
+/// assert_eq!(inner.depth(), 2);
+/// ```
+pub fn stack() -> Vec<ActiveGuard> {
+    let thread = thread::current().id();
+    REGISTRY
+        .lock()
+        .guards
+        .iter()
+        .filter(|(_, g)| g.thread == thread)
+        .map(|(_, g)| g.clone())
+        .collect()
+}
+
+impl WithDir<'_> {
+    /// How deeply this instance is nested, 1 for the outermost live
+    /// `WithDir` on the thread that created it, 2 for one inside that and
+    /// so on. See [stack](crate::stack) for the whole stack.
+    pub fn depth(&self) -> usize {
+        let registry = REGISTRY.lock();
+        let Some(pos) = registry.guards.iter().position(|(i, _)| *i == self.id) else {
+            return 0;
+        };
+        let thread = registry.guards[pos].1.thread;
+        registry.guards[..=pos]
+            .iter()
+            .filter(|(_, g)| g.thread == thread)
+            .count()
+    }
+
+    /// Attach a label to this instance, shown in [active](crate::active)
+    pub fn label(&mut self, label: impl Into<String>) {
+        let mut registry = REGISTRY.lock();
+        if let Some((_, g)) = registry.guards.iter_mut().find(|(i, _)| *i == self.id) {
+            g.label = Some(label.into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_active() {
+        let mut wd = WithDir::temp().unwrap();
+        wd.label("outer");
+        let inner = WithDir::current().unwrap();
+
+        // only this thread can hold guards now
+        let guards = active();
+        assert_eq!(guards.len(), 2);
+        assert_eq!(guards[0].path, wd.path());
+        assert_eq!(guards[0].label.as_deref(), Some("outer"));
+        assert_eq!(guards[0].thread, thread::current().id());
+        assert_eq!(guards[1].label, None);
+
+        drop(inner);
+        assert_eq!(active().len(), 1);
+    }
+
+    #[test]
+    fn test_stack() {
+        let mut wd = WithDir::temp().unwrap();
+        wd.label("outer");
+        assert_eq!(wd.depth(), 1);
+        let inner = WithDir::current().unwrap();
+        assert_eq!(inner.depth(), 2);
+
+        let stack = stack();
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack[0].label.as_deref(), Some("outer"));
+        assert_eq!(stack[1].path, inner.path());
+        // other threads have stacks of their own
+        assert!(thread::spawn(super::stack).join().unwrap().is_empty());
+        drop(inner);
+        assert_eq!(super::stack().len(), 1);
+    }
+}
@@ -0,0 +1,102 @@
+//! Retrying directory changes that fail for transient reasons, see [Retry].
+use std::{io::ErrorKind, thread::sleep, time::Duration};
+
+/// How to retry changing directory when it fails, for example because
+/// antivirus or indexing services on Windows briefly hold the directory.
+/// Set with [Options::retry](crate::Options::retry); it applies both to
+/// entering and to restoring the original directory. Errors that won't go
+/// away by waiting, like the directory not existing, are not retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Retry {
+    /// Number of retries after the first attempt, 0 to never retry
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for each one after
+    pub initial_delay: Duration,
+    /// Upper limit for the delay
+    pub max_delay: Duration,
+}
+
+impl Default for Retry {
+    /// No retries
+    fn default() -> Self {
+        Retry {
+            attempts: 0,
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Retry {
+    /// Retry up to `attempts` times, with the default delays
+    pub fn attempts(attempts: u32) -> Retry {
+        Retry {
+            attempts,
+            ..Retry::default()
+        }
+    }
+
+    pub(crate) fn run<T>(
+        &self,
+        mut f: impl FnMut() -> Result<T, std::io::Error>,
+    ) -> Result<T, std::io::Error> {
+        let mut delay = self.initial_delay;
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(e) if attempt < self.attempts && is_transient(&e) => {
+                    sleep(delay);
+                    delay = (delay * 2).min(self.max_delay);
+                    attempt += 1;
+                }
+                ret => return ret,
+            }
+        }
+    }
+}
+
+fn is_transient(e: &std::io::Error) -> bool {
+    !matches!(
+        e.kind(),
+        ErrorKind::NotFound | ErrorKind::NotADirectory | ErrorKind::InvalidInput
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry() {
+        let retry = Retry {
+            attempts: 3,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+        };
+        let mut calls = 0;
+        let r = retry.run(|| {
+            calls += 1;
+            match calls {
+                3 => Ok(calls),
+                _ => Err(std::io::Error::from(ErrorKind::PermissionDenied)),
+            }
+        });
+        assert_eq!(r.unwrap(), 3);
+
+        calls = 0;
+        let r: Result<(), _> = retry.run(|| {
+            calls += 1;
+            Err(std::io::Error::from(ErrorKind::NotFound))
+        });
+        assert!(r.is_err());
+        assert_eq!(calls, 1);
+
+        calls = 0;
+        let r: Result<(), _> = Retry::default().run(|| {
+            calls += 1;
+            Err(std::io::Error::from(ErrorKind::PermissionDenied))
+        });
+        assert!(r.is_err());
+        assert_eq!(calls, 1);
+    }
+}
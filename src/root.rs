@@ -0,0 +1,123 @@
+//! Scoped change of the root directory on unix, see [WithRoot]. Requires
+//! the `chroot` feature.
+use std::{
+    env::current_dir,
+    fs::{canonicalize, File},
+    os::{fd::AsRawFd, unix::fs::chroot},
+    path::{Path, PathBuf},
+};
+
+use parking_lot::ReentrantMutexGuard;
+
+use crate::{backend::CwdBackend, lock_to_enter, OsBackend};
+
+/// RAII guard that [chroot](std::os::unix::fs::chroot)s into a directory
+/// and changes to its `/`. On drop the original root and current directory
+/// are restored. The root is process wide, so like
+/// [WithDir](crate::WithDir) this claims the lock for its lifetime, and
+/// `WithDir`s created inside the scope resolve paths against the new root.
+///
+/// Changing the root needs `CAP_SYS_CHROOT`, usually meaning root, and
+/// restoring it needs the same. The original root is kept open so it can
+/// be returned to, which means code inside the scope running with that
+/// capability can escape, so this is not a security boundary on its own.
+///
+/// ```no_run
+/// use with_dir::WithRoot;
+///
+/// let root = WithRoot::new("/srv/sysroot").unwrap();
+/// assert!(std::path::Path::new("/usr/bin").exists());
+/// root.leave().unwrap();
+/// ```
+pub struct WithRoot {
+    root: PathBuf,
+    old_root: File,
+    original_dir: PathBuf,
+    mutex: Option<ReentrantMutexGuard<'static, ()>>,
+}
+
+impl WithRoot {
+    /// Change the root directory to `root`, and the current directory to
+    /// the new `/`
+    pub fn new(root: impl AsRef<Path>) -> Result<WithRoot, std::io::Error> {
+        let m = lock_to_enter()?;
+        let original_dir = current_dir()?;
+        let root = canonicalize(root)?;
+        let old_root = File::open("/")?;
+        chroot(&root)?;
+        let wr = WithRoot {
+            root,
+            old_root,
+            original_dir,
+            mutex: Some(m),
+        };
+        OsBackend.set_current_dir(Path::new("/"))?;
+        Ok(wr)
+    }
+
+    /// The directory that is the root while this instance is alive, as an
+    /// absolute path outside of it
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn restore(&mut self) -> Result<(), std::io::Error> {
+        // SAFETY: old_root is an open directory for the lifetime of self
+        if unsafe { libc::fchdir(self.old_root.as_raw_fd()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        chroot(".")?;
+        OsBackend.set_current_dir(&self.original_dir)
+    }
+
+    /// Return to the original root and current directory. This is the same
+    /// as dropping the instance but will not panic.
+    pub fn leave(mut self) -> Result<(), std::io::Error> {
+        let ret = self.restore();
+        self.mutex = None;
+        ret
+    }
+}
+
+impl Drop for WithRoot {
+    /// Restores the original root and current directory.
+    ///
+    /// # Panics
+    ///
+    /// Panics if either can't be restored.
+    fn drop(&mut self) {
+        if self.mutex.is_some() {
+            self.restore().unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir, write};
+
+    use super::*;
+    use crate::WithDir;
+
+    #[test]
+    fn test_with_root_missing() {
+        let _tmp = WithDir::temp().unwrap();
+        assert!(WithRoot::new("missing").is_err());
+    }
+
+    // changes the root of the whole test process, run with `--ignored` as root
+    #[test]
+    #[ignore]
+    fn test_with_root() {
+        let tmp = WithDir::temp().unwrap();
+        create_dir("sysroot").unwrap();
+        write("sysroot/marker", "").unwrap();
+        let root = WithRoot::new("sysroot").unwrap();
+        assert_eq!(root.root(), tmp.path().join("sysroot"));
+        assert!(Path::new("/marker").exists());
+        assert_eq!(current_dir().unwrap(), Path::new("/"));
+        root.leave().unwrap();
+        assert!(tmp.is_current());
+        assert!(!Path::new("/marker").exists());
+    }
+}
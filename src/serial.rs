@@ -0,0 +1,77 @@
+//! Interop with [serial_test](https://docs.rs/serial_test), enabled with
+//! the `serial_test` feature.
+//!
+//! `#[serial(cwd)]` tests and `WithDir` use separate locks, so on their own
+//! they don't keep each other out. Annotate every test that touches the
+//! current directory with `#[serial(cwd)]`, [SERIAL_KEY] being `cwd`, so
+//! they run one at a time, and run the parts of them that change directory
+//! without a `WithDir` inside [serial] so they also wait for `WithDir`s made
+//! by unannotated tests.
+//!
+//! ```
+//! use serial_test::serial;
+//!
+//! #[serial(cwd)]
+//! fn builds_in_place() {
+//!     with_dir::serial::serial(|| {
+//!         let wd = with_dir::WithDir::temp().unwrap();
+//!         // no other #[serial(cwd)] test is running, nor any other WithDir
+//!     })
+//!     .unwrap();
+//! }
+//! # builds_in_place();
+//! ```
+use crate::lock_to_enter;
+
+/// The serial_test key tests sharing the current directory should be
+/// annotated with, as in `#[serial(cwd)]`
+pub const SERIAL_KEY: &str = "cwd";
+
+/// Run `f` while holding the lock used by `WithDir`, so no `WithDir` is
+/// alive on another thread meanwhile. Fails without running `f` if the
+/// directory state is [poisoned](crate::is_poisoned).
+pub fn serial<R>(f: impl FnOnce() -> R) -> Result<R, std::io::Error> {
+    let _lock = lock_to_enter()?;
+    Ok(f())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::WithDir;
+
+    static RUNNING: AtomicBool = AtomicBool::new(false);
+
+    fn exclusive() {
+        assert!(!RUNNING.swap(true, Ordering::SeqCst));
+        thread::sleep(Duration::from_millis(20));
+        RUNNING.store(false, Ordering::SeqCst);
+    }
+
+    #[serial_test::serial(cwd)]
+    #[test]
+    fn test_serial_attribute() {
+        serial(exclusive).unwrap();
+    }
+
+    #[serial_test::serial(cwd)]
+    #[test]
+    fn test_serial() {
+        let wd = serial(|| {
+            exclusive();
+            WithDir::temp().unwrap().path().to_owned()
+        })
+        .unwrap();
+        assert!(!wd.exists());
+        // other threads' WithDirs are kept out too
+        let handle = thread::spawn(|| WithDir::temp().map(|wd| wd.path().to_owned()));
+        serial(exclusive).unwrap();
+        assert!(!handle.join().unwrap().unwrap().exists());
+    }
+}
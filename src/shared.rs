@@ -0,0 +1,448 @@
+//! A [WithDir](crate::WithDir) that several closures or threads can own at
+//! once, see [SharedWithDir].
+use std::{
+    cell::RefCell,
+    io::ErrorKind,
+    panic::resume_unwind,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Sender},
+        Arc, Weak,
+    },
+    thread::{self, JoinHandle},
+};
+
+use parking_lot::{Condvar, Mutex, MutexGuard};
+
+use crate::{
+    chdir, current_dir, error::Context, lock_to_enter, Cwd, Operation, WithDir, DIR_MUTEX,
+};
+
+/// A cloneable handle to a directory scope, made by
+/// [share](crate::WithDir::share), that can be sent to other threads. The
+/// original directory is restored when the last clone is dropped.
+///
+/// The directory lock is held by a background thread for as long as any
+/// clone is alive, so no `WithDir` can be created meanwhile on any thread,
+/// including the one that shared it. Waiting for one there deadlocks. Use
+/// the current directory directly instead, or nest with a
+/// [VirtualDir](crate::VirtualDir) made from an absolute path, such as one
+/// joined onto [path](SharedWithDir::path), since resolving a relative one
+/// waits for the lock too.
+///
+/// ```
+/// let shared = with_dir::WithDir::temp().unwrap().share().unwrap();
+/// let workers: Vec<_> = (0..3)
+///     .map(|i| {
+///         let shared = shared.clone();
+///         std::thread::spawn(move || std::fs::write(shared.path().join(i.to_string()), "").unwrap())
+///     })
+///     .collect();
+/// for w in workers {
+///     w.join().unwrap();
+/// }
+/// assert_eq!(std::fs::read_dir(".").unwrap().count(), 3);
+/// ```
+#[derive(Clone, Debug)]
+pub struct SharedWithDir {
+    inner: Arc<Inner>,
+}
+
+/// State of [new_shared](crate::WithDir::new_shared)
+struct Readers {
+    /// The directory entered, while any handle to it is alive
+    scope: Option<(PathBuf, Weak<Inner>)>,
+    /// Threads waiting to enter a different directory, which new readers
+    /// of `scope` queue behind rather than keep it alive forever
+    queued: usize,
+}
+
+static READERS: Mutex<Readers> = Mutex::new(Readers {
+    scope: None,
+    queued: 0,
+});
+
+/// Signalled, under [READERS], whenever a shared scope ends or a thread
+/// stops being queued
+static ENDED: Condvar = Condvar::new();
+
+thread_local! {
+    /// Scopes this thread got from `new_shared`, so it can refuse to wait
+    /// for one of them to end
+    static HELD: RefCell<Vec<Weak<Inner>>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Debug)]
+struct Inner {
+    path: PathBuf,
+    original_dir: PathBuf,
+    /// Dropped to tell the keeper to restore the original directory
+    release: Option<Sender<()>>,
+    keeper: Option<JoinHandle<()>>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.release = None;
+        if let Some(keeper) = self.keeper.take() {
+            // a failed restore panics the keeper, pass that on
+            let joined = keeper.join();
+            let _readers = READERS.lock();
+            ENDED.notify_all();
+            if let Err(panic) = joined {
+                if !thread::panicking() {
+                    resume_unwind(panic);
+                }
+            }
+        }
+    }
+}
+
+impl SharedWithDir {
+    /// Hand `cwd`, for `path`, to a new background thread that holds the
+    /// lock until the last clone is dropped
+    fn spawn(path: PathBuf, cwd: Cwd) -> Result<SharedWithDir, std::io::Error> {
+        let (ready_tx, ready) = channel();
+        let (release, released) = channel::<()>();
+        let keeper_path = path.clone();
+        let keeper = thread::Builder::new()
+            .name("with_dir-shared".to_owned())
+            .spawn(move || match keep(&keeper_path, cwd) {
+                Ok(wd) => {
+                    let _ = ready_tx.send(Ok(wd.original_path().to_owned()));
+                    // returns once the last handle drops `release`
+                    let _ = released.recv();
+                    drop(wd);
+                }
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                }
+            })?;
+        let original_dir = ready
+            .recv()
+            .map_err(|_| std::io::Error::other("the shared WithDir thread stopped"))??;
+        Ok(SharedWithDir {
+            inner: Arc::new(Inner {
+                path,
+                original_dir,
+                release: Some(release),
+                keeper: Some(keeper),
+            }),
+        })
+    }
+
+    /// The loop of [new_shared](crate::WithDir::new_shared), setting
+    /// `queued` once this thread is counted in [Readers::queued]
+    fn enter_shared(
+        readers: &mut MutexGuard<'_, Readers>,
+        path: &Path,
+        queued: &mut bool,
+    ) -> Result<SharedWithDir, std::io::Error> {
+        loop {
+            let current = readers
+                .scope
+                .as_ref()
+                .and_then(|(_, inner)| inner.upgrade());
+            let Some(inner) = current else {
+                if readers.queued > 0 && !*queued {
+                    // let the threads that were waiting go first
+                    ENDED.wait(readers);
+                    continue;
+                }
+                // no readers, so the lock is only held by WithDirs that will
+                // be dropped. Nobody can join until the new scope is
+                // entered, and that is all the scope needs, so waiting here
+                // keeps threads wanting the same directory from each
+                // starting one.
+                let path = {
+                    let _lock = lock_to_enter()?;
+                    std::fs::canonicalize(current_dir()?.join(path))?
+                };
+                let shared = SharedWithDir::spawn(path.clone(), Cwd::NotTemp(path.clone()))?;
+                readers.scope = Some((path, Arc::downgrade(&shared.inner)));
+                return Ok(shared);
+            };
+            // the readers are already inside, so resolve against where they
+            // came from
+            let resolved = std::fs::canonicalize(inner.original_dir.join(path));
+            let weak = Arc::downgrade(&inner);
+            let held = HELD.with(|held| held.borrow().iter().any(|h| h.ptr_eq(&weak)));
+            let same = resolved.as_deref().ok() == Some(inner.path.as_path());
+            let err = if same {
+                // joining is what lets readers keep the scope alive, so
+                // once another directory is waiting only this scope's own
+                // threads may, since they would otherwise wait for
+                // themselves
+                if readers.queued == 0 || *queued || held {
+                    return Ok(SharedWithDir { inner });
+                }
+                None
+            } else if held {
+                Some(std::io::Error::new(
+                    ErrorKind::WouldBlock,
+                    format!(
+                        "can't enter {} while this thread shares {}",
+                        path.display(),
+                        inner.path.display()
+                    ),
+                ))
+            } else {
+                resolved.err()
+            };
+            // not dropped with READERS held, its drop takes the lock
+            MutexGuard::unlocked(readers, || drop(inner));
+            if let Some(err) = err {
+                return Err(err);
+            }
+            if !same && !*queued {
+                *queued = true;
+                readers.queued += 1;
+            }
+            while weak.strong_count() > 0 {
+                ENDED.wait(readers);
+            }
+        }
+    }
+
+    /// Absolute path of the directory, see [path](crate::WithDir::path)
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    /// The directory restored when the last clone is dropped, see
+    /// [original_path](crate::WithDir::original_path)
+    pub fn original_path(&self) -> &Path {
+        &self.inner.original_dir
+    }
+}
+
+impl AsRef<Path> for SharedWithDir {
+    fn as_ref(&self) -> &Path {
+        self.path()
+    }
+}
+
+/// Enter `path` on the keeper thread, where `cwd` keeps a temporary or
+/// ephemeral directory alive
+fn keep<'a>(path: &Path, cwd: Cwd) -> Result<WithDir<'a>, std::io::Error> {
+    let m = lock_to_enter()?;
+    let original_dir = current_dir()?;
+    chdir(path).entering(Operation::SetCurrentDir, path, &original_dir)?;
+    Ok(WithDir::from_parts(m, original_dir, cwd))
+}
+
+impl WithDir<'_> {
+    /// Turn this guard into a [SharedWithDir]. Its directory, including a
+    /// [temp](crate::WithDir::temp) directory's lifetime, is handed to a
+    /// background thread that holds it until the last clone is dropped.
+    /// Fails, restoring the original directory, if another `WithDir` or
+    /// [LockGuard](crate::LockGuard) is alive on this thread, since the
+    /// background thread could then never take the lock.
+    pub fn share(mut self) -> Result<SharedWithDir, std::io::Error> {
+        let path = self.absolute_path().to_owned();
+        let cwd = std::mem::replace(&mut self.cwd, Cwd::NotTemp(path.clone()));
+        self.leave()?;
+        if DIR_MUTEX.is_owned_by_current_thread() {
+            return Err(std::io::Error::new(
+                ErrorKind::WouldBlock,
+                "can't share a WithDir while another is alive on the same thread",
+            ));
+        }
+        SharedWithDir::spawn(path, cwd)
+    }
+}
+
+impl WithDir<'_> {
+    /// Enter `path` in read mode: threads asking for the same directory
+    /// while it is entered this way share the scope and run concurrently,
+    /// and only asking for a different directory waits, until every handle
+    /// to this one has been dropped. Large test suites whose tests all run
+    /// in one fixture directory then aren't serialized. Directories are
+    /// compared after [canonicalize](std::fs::canonicalize), and a relative
+    /// `path` is resolved against the directory the current readers came
+    /// from, so every thread can pass the same one.
+    ///
+    /// Once a thread is waiting for a different directory, new readers of
+    /// the current one wait behind it instead of joining, so a steady stream
+    /// of them can't keep it out. Threads that already hold a handle to the
+    /// scope still join. A reader mustn't wait, with its handle alive, for
+    /// another thread that asks for the same directory, since that thread
+    /// may be queued.
+    ///
+    /// The readers share the process current directory, so they mustn't
+    /// change it, and like any [SharedWithDir] no `WithDir` can be created
+    /// while it is alive. Fails with
+    /// [WouldBlock](std::io::ErrorKind::WouldBlock) if a `WithDir` is
+    /// already alive on this thread, or if this thread asks for a different
+    /// directory while a handle it got from `new_shared` is alive, since it
+    /// would wait for itself.
+    ///
+    /// ```
+    /// use with_dir::WithDir;
+    ///
+    /// let readers: Vec<_> = (0..3)
+    ///     .map(|_| {
+    ///         std::thread::spawn(|| {
+    ///             let _wd = WithDir::new_shared("src").unwrap();
+    ///             assert!(std::path::Path::new("lib.rs").exists());
+    ///         })
+    ///     })
+    ///     .collect();
+    /// for r in readers {
+    ///     r.join().unwrap();
+    /// }
+    /// ```
+    pub fn new_shared(path: impl AsRef<Path>) -> Result<SharedWithDir, std::io::Error> {
+        if DIR_MUTEX.is_owned_by_current_thread() {
+            return Err(std::io::Error::new(
+                ErrorKind::WouldBlock,
+                "can't enter a shared WithDir while another is alive on the same thread",
+            ));
+        }
+        let path = path.as_ref();
+        let mut readers = READERS.lock();
+        let mut queued = false;
+        let shared = SharedWithDir::enter_shared(&mut readers, path, &mut queued);
+        if queued {
+            readers.queued -= 1;
+            ENDED.notify_all();
+        }
+        drop(readers);
+        let shared = shared?;
+        HELD.with(|held| {
+            let mut held = held.borrow_mut();
+            held.retain(|h| h.strong_count() > 0);
+            held.push(Arc::downgrade(&shared.inner));
+        });
+        Ok(shared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share() {
+        let shared = WithDir::temp().unwrap().share().unwrap();
+        let path = shared.path().to_owned();
+        assert_eq!(current_dir().unwrap(), path);
+        assert_ne!(shared.original_path(), path);
+
+        let clone = shared.clone();
+        let t = thread::spawn(move || {
+            std::fs::write("out", "").unwrap();
+            drop(clone);
+        });
+        t.join().unwrap();
+        // still held by `shared`
+        assert!(path.join("out").exists());
+        drop(shared);
+        assert!(!path.exists());
+
+        let outer = WithDir::temp().unwrap();
+        std::fs::create_dir("a").unwrap();
+        let err = WithDir::new("a").unwrap().share().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        assert!(outer.is_current());
+    }
+
+    #[test]
+    fn test_new_shared() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_owned();
+        let (entered, rx) = channel();
+        let (go, wait) = channel::<()>();
+        let first = thread::spawn(move || {
+            let shared = WithDir::new_shared(&dir).unwrap();
+            entered.send(()).unwrap();
+            let _ = wait.recv();
+            // waiting for itself would deadlock
+            let err = WithDir::new_shared(std::env::temp_dir()).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::WouldBlock);
+            drop(shared);
+        });
+        rx.recv().unwrap();
+
+        // joins the first reader's scope instead of waiting for it
+        let dir = tmp.path().to_owned();
+        let (entered, rx) = channel();
+        let second = thread::spawn(move || {
+            let shared = WithDir::new_shared(&dir).unwrap();
+            entered.send(shared.path().to_owned()).unwrap();
+        });
+        let path = rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap();
+        assert_eq!(path, std::fs::canonicalize(tmp.path()).unwrap());
+        second.join().unwrap();
+        go.send(()).unwrap();
+        first.join().unwrap();
+
+        let other = tempfile::tempdir().unwrap();
+        let shared = WithDir::new_shared(other.path()).unwrap();
+        assert_eq!(current_dir().unwrap(), shared.path());
+    }
+
+    #[test]
+    fn test_new_shared_serializes_other_dirs() {
+        let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let first = WithDir::new_shared(a.path()).unwrap();
+
+        let dir = b.path().to_owned();
+        let (entered, rx) = channel();
+        let second = thread::spawn(move || {
+            let shared = WithDir::new_shared(&dir).unwrap();
+            entered.send(current_dir().unwrap()).unwrap();
+            drop(shared);
+        });
+        // waits for every reader of `a` to be gone
+        let timeout = std::time::Duration::from_millis(200);
+        assert!(rx.recv_timeout(timeout).is_err());
+        assert_eq!(current_dir().unwrap(), first.path());
+        drop(first);
+        let cwd = rx.recv_timeout(std::time::Duration::from_secs(10)).unwrap();
+        assert_eq!(cwd, std::fs::canonicalize(b.path()).unwrap());
+        second.join().unwrap();
+    }
+
+    #[test]
+    fn test_new_shared_not_starved() {
+        let (a, b) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let dir = a.path().to_owned();
+        let churn = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                // overlapping readers, each on a new thread, so `a` is
+                // never without one
+                let mut readers = Vec::new();
+                while !stop.load(std::sync::atomic::Ordering::SeqCst) {
+                    let dir = dir.clone();
+                    readers.push(thread::spawn(move || {
+                        let shared = WithDir::new_shared(&dir).unwrap();
+                        thread::sleep(std::time::Duration::from_millis(30));
+                        drop(shared);
+                    }));
+                    thread::sleep(std::time::Duration::from_millis(10));
+                }
+                for r in readers {
+                    r.join().unwrap();
+                }
+            })
+        };
+        thread::sleep(std::time::Duration::from_millis(50));
+
+        let dir = b.path().to_owned();
+        let (entered, rx) = channel();
+        let writer = thread::spawn(move || {
+            let shared = WithDir::new_shared(&dir).unwrap();
+            entered.send(current_dir().unwrap()).unwrap();
+            drop(shared);
+        });
+        // gets in while readers of `a` are still arriving
+        let cwd = rx.recv_timeout(std::time::Duration::from_secs(10));
+        stop.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(cwd.unwrap(), std::fs::canonicalize(b.path()).unwrap());
+        writer.join().unwrap();
+        churn.join().unwrap();
+    }
+}
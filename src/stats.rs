@@ -0,0 +1,29 @@
+//! Names of the metrics recorded through the
+//! [metrics](https://docs.rs/metrics) crate when the `metrics` feature is
+//! enabled. Install a recorder to collect them.
+use std::time::Instant;
+
+/// Counter of `WithDir`s created
+pub const GUARDS_CREATED: &str = "with_dir.guards_created";
+/// Counter of failures to restore the original directory
+pub const RESTORE_FAILURES: &str = "with_dir.restore_failures";
+/// Histogram of seconds spent waiting for the directory lock
+pub const LOCK_WAIT_SECONDS: &str = "with_dir.lock_wait_seconds";
+/// Histogram of seconds each `WithDir` was alive for
+pub const GUARD_HOLD_SECONDS: &str = "with_dir.guard_hold_seconds";
+
+pub(crate) fn guard_created() {
+    metrics::counter!(GUARDS_CREATED).increment(1);
+}
+
+pub(crate) fn restore_failed() {
+    metrics::counter!(RESTORE_FAILURES).increment(1);
+}
+
+pub(crate) fn lock_waited(start: Instant) {
+    metrics::histogram!(LOCK_WAIT_SECONDS).record(start.elapsed().as_secs_f64());
+}
+
+pub(crate) fn guard_released(created: Instant) {
+    metrics::histogram!(GUARD_HOLD_SECONDS).record(created.elapsed().as_secs_f64());
+}
@@ -0,0 +1,144 @@
+//! Checked entry into directories that come from untrusted input, see
+//! [WithDir::new_strict](crate::WithDir::new_strict).
+use std::{
+    fs::{symlink_metadata, Metadata},
+    io::ErrorKind,
+    path::Path,
+};
+
+use crate::{chdir, current_dir, error::Context, lock_to_enter, Cwd, Operation, WithDir};
+
+/// Checks made by [new_strict_with](crate::WithDir::new_strict_with) on top
+/// of refusing symlinks and anything that isn't a directory.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StrictOptions {
+    /// On unix, require the directory to be owned by the effective user or
+    /// root
+    pub check_owner: bool,
+    /// On unix, refuse directories anyone can write to, unless the sticky
+    /// bit is set as on `/tmp`
+    pub reject_world_writable: bool,
+}
+
+fn refuse(path: &Path, kind: ErrorKind, why: &str) -> std::io::Error {
+    std::io::Error::new(
+        kind,
+        format!("refusing to enter {}: {}", path.display(), why),
+    )
+}
+
+fn check(path: &Path, meta: &Metadata, options: &StrictOptions) -> Result<(), std::io::Error> {
+    if meta.file_type().is_symlink() {
+        return Err(refuse(path, ErrorKind::InvalidInput, "it is a symlink"));
+    }
+    if !meta.is_dir() {
+        return Err(refuse(path, ErrorKind::NotADirectory, "not a directory"));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        // SAFETY: geteuid can't fail and has no preconditions
+        let euid = unsafe { libc::geteuid() };
+        if options.check_owner && meta.uid() != euid && meta.uid() != 0 {
+            return Err(refuse(
+                path,
+                ErrorKind::PermissionDenied,
+                "owned by another user",
+            ));
+        }
+        if options.reject_world_writable && meta.mode() & 0o002 != 0 && meta.mode() & 0o1000 == 0 {
+            return Err(refuse(path, ErrorKind::PermissionDenied, "world writable"));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = options;
+    Ok(())
+}
+
+/// Whether `a` and `b` are the same directory. Only checked on unix.
+fn same_dir(a: &Metadata, b: &Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        a.dev() == b.dev() && a.ino() == b.ino()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (a, b);
+        true
+    }
+}
+
+impl<'a> WithDir<'a> {
+    /// Like [new](crate::WithDir::new), but returns an error instead of
+    /// entering `path` if it is a symlink or not a directory. Only the
+    /// final component is checked, symlinks in its parents are followed.
+    /// On unix the directory entered is checked to be the one that was
+    /// inspected, so it can't be swapped for a symlink in between.
+    pub fn new_strict(path: impl AsRef<Path>) -> Result<WithDir<'a>, std::io::Error> {
+        WithDir::new_strict_with(path, &StrictOptions::default())
+    }
+
+    /// [new_strict](crate::WithDir::new_strict) with the extra checks in
+    /// `options`
+    pub fn new_strict_with(
+        path: impl AsRef<Path>,
+        options: &StrictOptions,
+    ) -> Result<WithDir<'a>, std::io::Error> {
+        let path = path.as_ref();
+        let m = lock_to_enter()?;
+        let original_dir = current_dir()?;
+        let before = symlink_metadata(path)?;
+        check(path, &before, options)?;
+        chdir(path).entering(Operation::SetCurrentDir, path, &original_dir)?;
+        let after = symlink_metadata(".")?;
+        if !same_dir(&before, &after) {
+            chdir(&original_dir)?;
+            return Err(refuse(
+                path,
+                ErrorKind::InvalidInput,
+                "it was replaced while being entered",
+            ));
+        }
+        Ok(WithDir::from_parts(
+            m,
+            original_dir,
+            Cwd::NotTemp(path.to_owned()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir, write};
+
+    use super::*;
+
+    #[test]
+    fn test_new_strict() {
+        let _tmp = WithDir::temp().unwrap();
+        create_dir("real").unwrap();
+        write("file", "").unwrap();
+
+        assert!(WithDir::new_strict("real").unwrap().is_current());
+        let err = WithDir::new_strict("file").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+        assert!(WithDir::new_strict("missing").is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::os::unix::fs::symlink("real", "link").unwrap();
+            let err = WithDir::new_strict("link").unwrap_err();
+            assert!(err.to_string().contains("symlink"));
+
+            let options = StrictOptions {
+                check_owner: true,
+                reject_world_writable: true,
+            };
+            assert!(WithDir::new_strict_with("real", &options).is_ok());
+            std::fs::set_permissions("real", std::fs::Permissions::from_mode(0o777)).unwrap();
+            assert!(WithDir::new_strict_with("real", &options).is_err());
+        }
+    }
+}
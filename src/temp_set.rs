@@ -0,0 +1,143 @@
+//! Groups of temporary directories that are cleaned up together. See
+//! [TempSet](crate::TempSet).
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+use tempfile::TempDir;
+
+use crate::{
+    chdir, current_dir, current_test_name, error::Context, lock_to_enter, temp_prefix, temp_root,
+    Cwd, Operation, WithDir,
+};
+
+/// A set of temporary directories that are only removed once the set and
+/// every `WithDir` entered with [enter](crate::TempSet::enter) have been
+/// dropped, or [cleanup_all](crate::TempSet::cleanup_all) is called. Useful
+/// for tests that start several cooperating processes which each need a
+/// directory of their own, but may read each other's.
+///
+/// ```
+/// use with_dir::TempSet;
+///
+/// let set = TempSet::new(2).unwrap();
+/// let first = set.enter(0).unwrap();
+/// std::fs::write("ready", "").unwrap();
+/// assert!(set.path(0).join("ready").exists());
+/// ```
+pub struct TempSet {
+    dirs: Arc<Mutex<Vec<TempDir>>>,
+    paths: Vec<PathBuf>,
+}
+
+impl TempSet {
+    /// Create `n` temporary directories
+    pub fn new(n: usize) -> Result<TempSet, std::io::Error> {
+        let prefix = temp_prefix(current_test_name().as_deref());
+        let dirs = (0..n)
+            .map(|_| {
+                tempfile::Builder::new()
+                    .prefix(&prefix)
+                    .tempdir_in(temp_root())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let paths: Vec<PathBuf> = dirs.iter().map(|d| d.path().to_owned()).collect();
+        #[cfg(feature = "log")]
+        for p in &paths {
+            log::debug!("created temporary directory {}", p.display());
+        }
+        Ok(TempSet {
+            dirs: Arc::new(Mutex::new(dirs)),
+            paths,
+        })
+    }
+
+    /// Number of directories in the set
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// True if the set has no directories
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    /// Path of the `i`th directory
+    ///
+    /// # Panics
+    ///
+    /// Panics if `i` is out of bounds
+    pub fn path(&self, i: usize) -> &Path {
+        &self.paths[i]
+    }
+
+    /// Paths of all the directories
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Change the current working directory to the `i`th directory. The
+    /// returned `WithDir` keeps the whole set alive.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `i` is out of bounds
+    pub fn enter<'a>(&self, i: usize) -> Result<WithDir<'a>, std::io::Error> {
+        let m = lock_to_enter()?;
+        let original_dir = current_dir()?;
+        chdir(&self.paths[i]).entering(Operation::SetCurrentDir, &self.paths[i], &original_dir)?;
+        Ok(WithDir::from_parts(
+            m,
+            original_dir,
+            Cwd::Shared {
+                path: self.paths[i].clone(),
+                _set: self.dirs.clone(),
+            },
+        ))
+    }
+
+    /// Remove all the directories now, even if there are still `WithDir`s
+    /// in them. Returns the first error encountered, after trying to
+    /// remove every directory.
+    pub fn cleanup_all(&self) -> Result<(), std::io::Error> {
+        let dirs = std::mem::take(&mut *self.dirs.lock());
+        let mut result = Ok(());
+        for dir in dirs {
+            let closed = dir.close();
+            if result.is_ok() {
+                result = closed;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_temp_set() {
+        let set = TempSet::new(3).unwrap();
+        assert_eq!(set.len(), 3);
+        let paths = set.paths().to_vec();
+
+        let wd = set.enter(1).unwrap();
+        assert_eq!(wd.path(), paths[1]);
+        drop(set);
+        // the guard keeps every directory in the set alive
+        assert!(paths.iter().all(|p| p.exists()));
+        drop(wd);
+        assert!(paths.iter().all(|p| !p.exists()));
+
+        let set = TempSet::new(2).unwrap();
+        let paths = set.paths().to_vec();
+        {
+            let _wd = set.enter(0).unwrap();
+            set.cleanup_all().unwrap();
+            assert!(paths.iter().all(|p| !p.exists()));
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Moving a directory scope to another thread, see
+//! [WithDir::detach](crate::WithDir::detach).
+use std::{path::Path, sync::Arc};
+
+use crate::{
+    env, error::Context, lock_to_enter, registry, Clobber, Cwd, CwdBackend, Operation, OutOfOrder,
+    Retry, Snapshot, WithDir,
+};
+
+/// A directory scope between threads, made by
+/// [detach](crate::WithDir::detach). Unlike `WithDir` it is `Send`, since
+/// it holds no lock and the current directory has been restored. It still
+/// owns a [temp](crate::WithDir::temp) or
+/// [ephemeral](crate::WithDir::create_ephemeral) directory, which is
+/// deleted if this is dropped without being [attached](Detached::attach).
+pub struct Detached {
+    cwd: Cwd,
+    snapshot: Option<Snapshot>,
+    label: Option<String>,
+    keep_on_panic: bool,
+    clobber: Clobber,
+    out_of_order: OutOfOrder,
+    retry: Retry,
+    sync_pwd: bool,
+    export_tmpdir: bool,
+    backend: Arc<dyn CwdBackend>,
+}
+
+impl std::fmt::Debug for Detached {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Detached")
+            .field("path", &self.path())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Detached {
+    /// Absolute path of the directory
+    pub fn path(&self) -> &Path {
+        match &self.cwd {
+            Cwd::NotTemp(p) => p,
+            Cwd::Temp(t) => t.path(),
+            Cwd::Ephemeral(e) => &e.path,
+            Cwd::Shared { path, .. } => path,
+        }
+    }
+
+    /// Enter the directory again on the current thread, taking the lock
+    /// and restoring whatever is current now when the returned `WithDir` is
+    /// dropped. Fails if the directory has gone in the meantime, in which
+    /// case it is dropped.
+    pub fn attach<'a>(self) -> Result<WithDir<'a>, std::io::Error> {
+        let m = lock_to_enter()?;
+        let original_dir = self.backend.current_dir().reading_cwd()?;
+        let path = self.path().to_owned();
+        self.retry
+            .run(|| self.backend.set_current_dir(&path))
+            .entering(Operation::SetCurrentDir, &path, &original_dir)?;
+        let mut wd = WithDir::from_parts(m, original_dir, self.cwd);
+        wd.snapshot = self.snapshot;
+        wd.keep_on_panic = self.keep_on_panic;
+        wd.clobber = self.clobber;
+        wd.out_of_order = self.out_of_order;
+        wd.retry = self.retry;
+        wd.backend = self.backend;
+        if let Some(label) = self.label {
+            wd.label(label);
+        }
+        if self.sync_pwd {
+            wd.sync_pwd();
+        }
+        if self.export_tmpdir {
+            wd.export_tmpdir();
+        }
+        Ok(wd)
+    }
+}
+
+impl WithDir<'_> {
+    /// Restore the original directory and release the lock, keeping the
+    /// scope itself, its directory and settings, in a [Detached] that can
+    /// be sent to another thread and [attached](Detached::attach) there.
+    /// Use it for fixtures made on a setup thread and used by a worker.
+    ///
+    /// ```
+    /// use with_dir::WithDir;
+    ///
+    /// let fixture = WithDir::temp().unwrap();
+    /// std::fs::write("input.txt", "data").unwrap();
+    /// let fixture = fixture.detach().unwrap();
+    ///
+    /// std::thread::spawn(move || {
+    ///     let _wd = fixture.attach().unwrap();
+    ///     assert_eq!(std::fs::read_to_string("input.txt").unwrap(), "data");
+    /// })
+    /// .join()
+    /// .unwrap();
+    /// ```
+    pub fn detach(mut self) -> Result<Detached, std::io::Error> {
+        let path = self.absolute_path().to_owned();
+        let detached = Detached {
+            cwd: std::mem::replace(&mut self.cwd, Cwd::NotTemp(path.clone())),
+            snapshot: self.snapshot.take(),
+            label: registry::guard(self.id).and_then(|g| g.label),
+            keep_on_panic: self.keep_on_panic,
+            clobber: self.clobber,
+            out_of_order: self.out_of_order,
+            retry: self.retry,
+            sync_pwd: self.has_process_var("PWD"),
+            export_tmpdir: self.has_process_var(env::TMPDIR_VARS[0]),
+            backend: self.backend.clone(),
+        };
+        self.leave()?;
+        Ok(detached)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::active;
+
+    #[test]
+    fn test_detach_label() {
+        let tmp = WithDir::temp().unwrap();
+        let mut outer = WithDir::new(".").unwrap();
+        outer.label("outer");
+        // same directory and not registered, so only the id tells the two apart
+        // SAFETY: `outer` holds the lock on this thread
+        let inner = unsafe { WithDir::new_unchecked(tmp.path()) }.unwrap();
+        let detached = inner.detach().unwrap();
+        assert_eq!(detached.label, None);
+        drop(outer);
+    }
+
+    #[test]
+    fn test_detach() {
+        let mut wd = WithDir::temp().unwrap();
+        wd.label("fixture");
+        std::fs::write("f", "").unwrap();
+        let path = wd.path().to_owned();
+        let detached = wd.detach().unwrap();
+        assert_eq!(detached.path(), path);
+        assert!(path.exists());
+
+        thread::spawn(move || {
+            let wd = detached.attach().unwrap();
+            assert!(wd.is_current());
+            assert!(Path::new("f").exists());
+            assert!(wd.diff().is_ok());
+            let guards = active();
+            let guard = guards.iter().find(|g| g.path == path).unwrap();
+            assert_eq!(guard.label.as_deref(), Some("fixture"));
+            drop(wd);
+            assert!(!path.exists());
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,79 @@
+//! Threads with their own current directory on Linux, see
+//! [WithDir::spawn_unshared](crate::WithDir::spawn_unshared).
+use std::{
+    env::current_dir,
+    path::Path,
+    thread::{self, JoinHandle},
+};
+
+use crate::{backend::CwdBackend, lock_to_enter, OsBackend, WithDir};
+
+impl WithDir<'_> {
+    /// Spawn a thread that stops sharing filesystem attributes with the
+    /// rest of the process (`unshare(CLONE_FS)`), changes to `path` and
+    /// runs `f`. The directory change is only seen by that thread, so the
+    /// lock isn't held while it runs and other threads, including ones
+    /// holding a `WithDir`, are unaffected. A relative `path` is resolved
+    /// against the current directory at the time of the call, read under
+    /// the lock so another thread's `WithDir` can't move it meanwhile. The
+    /// thread's result is an error if resolving `path`, the unshare or the
+    /// change of directory fails.
+    /// Threads spawned from `f` inherit the directory but share it with
+    /// `f`'s thread only. Linux only, requires the `linux-unshare` feature.
+    ///
+    /// ```
+    /// use with_dir::WithDir;
+    ///
+    /// let before = std::env::current_dir().unwrap();
+    /// let inside = WithDir::spawn_unshared("src", || std::env::current_dir().unwrap())
+    ///     .join()
+    ///     .unwrap()
+    ///     .unwrap();
+    /// assert_eq!(inside, before.join("src"));
+    /// assert_eq!(std::env::current_dir().unwrap(), before);
+    /// ```
+    pub fn spawn_unshared<T, F>(
+        path: impl AsRef<Path>,
+        f: F,
+    ) -> JoinHandle<Result<T, std::io::Error>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let path = path.as_ref();
+        let path = match path.is_absolute() {
+            true => Ok(path.to_owned()),
+            false => lock_to_enter().and_then(|_lock| current_dir().map(|cwd| cwd.join(path))),
+        };
+        thread::spawn(move || {
+            let path = path?;
+            // SAFETY: unshare only affects the calling thread and CLONE_FS
+            // has no preconditions
+            if unsafe { libc::unshare(libc::CLONE_FS) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            OsBackend.set_current_dir(&path)?;
+            Ok(f())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env::current_dir, fs::create_dir};
+
+    use super::*;
+
+    #[test]
+    fn test_spawn_unshared() {
+        let tmp = WithDir::temp().unwrap();
+        create_dir("a").unwrap();
+        let inside = WithDir::spawn_unshared("a", || current_dir().unwrap());
+        assert_eq!(inside.join().unwrap().unwrap(), tmp.path().join("a"));
+        assert!(tmp.is_current());
+
+        // runs while this thread holds the lock
+        let failed = WithDir::spawn_unshared("missing", || ());
+        assert!(failed.join().unwrap().is_err());
+    }
+}
@@ -0,0 +1,24 @@
+//! Experimental APIs, enabled with the `unstable` feature.
+//!
+//! Anything in here may change or be removed in any release, including patch
+//! releases, and is not covered by this crate's semver guarantees. Larger
+//! subsystems land here first so they can be tried out and given feedback
+//! on before being stabilised and moved to the crate root. The ones here
+//! now are also exported from the root for existing users, importing them
+//! from here marks code that should expect changes.
+//!
+//! - [VirtualDir] and [isolated], IO in a directory without changing the
+//!   current directory
+//! - [SharedWithDir], from [WithDir::new_shared](crate::WithDir::new_shared),
+//!   scopes that threads asking for the same directory share
+//! - `MemBackend`, an in-memory [CwdBackend](crate::CwdBackend), with the
+//!   `memfs` feature
+//! - `WithDir::spawn_unshared`, a thread with its own current directory on
+//!   Linux, with the `linux-unshare` feature
+//!
+//! ```toml
+//! with_dir = { version = "0.1", features = ["unstable"] }
+//! ```
+#[cfg(feature = "memfs")]
+pub use crate::MemBackend;
+pub use crate::{isolated, SharedWithDir, VirtualDir, VirtualOpenOptions};
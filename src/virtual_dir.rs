@@ -0,0 +1,298 @@
+//! IO relative to a directory without changing the current directory, see
+//! [VirtualDir].
+use std::{
+    env::current_dir,
+    fs::{self, File},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use cap_std::{ambient_authority, fs::Dir};
+use tempfile::TempDir;
+
+use crate::{current_test_name, fs::long_path, lock_dir, temp_prefix, temp_root, WithDir};
+
+/// Options for [VirtualDir::open_with], with the same methods as
+/// [std::fs::OpenOptions]
+pub use cap_std::fs::OpenOptions as VirtualOpenOptions;
+
+/// A directory that relative paths are resolved against, with the same
+/// constructors as [WithDir], that never changes the process wide current
+/// directory. It takes no lock, beyond briefly to resolve a relative path
+/// on creation, so any number can be used at once from any thread. Use it
+/// instead of `WithDir` when code only needs to do IO in a directory,
+/// rather than actually needing the current directory changed.
+///
+/// The directory is held open, and relative paths given to its methods are
+/// resolved against that handle, `openat` style, so if the directory is
+/// renamed, or its path replaced by a symlink, IO still goes to the
+/// directory this was made for. Relative paths can't lead out of it with
+/// `..`. An absolute path is used as it is.
+///
+/// ```
+/// use with_dir::VirtualDir;
+///
+/// let dir = VirtualDir::temp().unwrap();
+/// dir.create_dir_all("a/b").unwrap();
+/// dir.write("a/b/c.txt", "hi").unwrap();
+/// assert_eq!(dir.read_to_string("a/b/c.txt").unwrap(), "hi");
+/// assert_eq!(dir.list("a").unwrap(), ["b"]);
+/// ```
+#[derive(Debug)]
+pub struct VirtualDir {
+    path: PathBuf,
+    // closed before `temp_dir` is deleted
+    dir: Dir,
+    temp_dir: Option<TempDir>,
+}
+
+impl VirtualDir {
+    /// `path` made absolute, against a current directory read while no
+    /// `WithDir` on another thread can change it
+    fn absolute(path: &Path) -> Result<PathBuf, std::io::Error> {
+        if path.is_absolute() {
+            return Ok(path.to_owned());
+        }
+        let _lock = lock_dir();
+        Ok(current_dir()?.join(path))
+    }
+
+    fn at(path: PathBuf, temp_dir: Option<TempDir>) -> Result<VirtualDir, std::io::Error> {
+        if !path.is_dir() {
+            return Err(std::io::Error::new(
+                ErrorKind::NotADirectory,
+                format!("{} is not a directory", path.display()),
+            ));
+        }
+        Ok(VirtualDir {
+            dir: Dir::open_ambient_dir(&path, ambient_authority())?,
+            path,
+            temp_dir,
+        })
+    }
+
+    /// Run `op` on `rel` through the handle, or for an absolute `rel`
+    /// through its parent directory
+    fn resolve<T>(
+        &self,
+        rel: &Path,
+        op: impl FnOnce(&Dir, &Path) -> Result<T, std::io::Error>,
+    ) -> Result<T, std::io::Error> {
+        if rel.is_absolute() {
+            let (Some(parent), Some(name)) = (rel.parent(), rel.file_name()) else {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} has no parent directory", rel.display()),
+                ));
+            };
+            let parent = Dir::open_ambient_dir(parent, ambient_authority())?;
+            return op(&parent, Path::new(name));
+        }
+        match rel.as_os_str().is_empty() {
+            true => op(&self.dir, Path::new(".")),
+            false => op(&self.dir, rel),
+        }
+    }
+
+    /// Use the existing directory `path`. A relative path is resolved
+    /// against the current directory now, later changes of directory don't
+    /// affect it.
+    pub fn new(path: impl AsRef<Path>) -> Result<VirtualDir, std::io::Error> {
+        VirtualDir::at(VirtualDir::absolute(path.as_ref())?, None)
+    }
+
+    /// A new temporary directory, deleted when this is dropped, named as
+    /// for [WithDir::temp](crate::WithDir::temp)
+    pub fn temp() -> Result<VirtualDir, std::io::Error> {
+        let temp_dir = tempfile::Builder::new()
+            .prefix(&temp_prefix(current_test_name().as_deref()))
+            .tempdir_in(temp_root())?;
+        VirtualDir::at(temp_dir.path().to_owned(), Some(temp_dir))
+    }
+
+    /// Make the directory `path` and use it, it persists after this is
+    /// dropped
+    pub fn create(path: impl AsRef<Path>) -> Result<VirtualDir, std::io::Error> {
+        let path = VirtualDir::absolute(path.as_ref())?;
+        fs::create_dir(long_path(&path))?;
+        VirtualDir::at(path, None)
+    }
+
+    /// Same as [create](VirtualDir::create), also making missing parents
+    pub fn create_all(path: impl AsRef<Path>) -> Result<VirtualDir, std::io::Error> {
+        let path = VirtualDir::absolute(path.as_ref())?;
+        fs::create_dir_all(long_path(&path))?;
+        VirtualDir::at(path, None)
+    }
+
+    /// Absolute path the directory had when this was made. Unlike the IO
+    /// methods it doesn't follow the directory if it moves.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// `rel` joined onto [path](VirtualDir::path), for APIs that only take
+    /// paths
+    pub fn join(&self, rel: impl AsRef<Path>) -> PathBuf {
+        self.path.join(rel)
+    }
+
+    /// Whether `rel` exists
+    pub fn exists(&self, rel: impl AsRef<Path>) -> bool {
+        self.resolve(rel.as_ref(), |d, r| d.try_exists(r))
+            .unwrap_or(false)
+    }
+
+    /// [fs::read] of `rel`
+    pub fn read(&self, rel: impl AsRef<Path>) -> Result<Vec<u8>, std::io::Error> {
+        self.resolve(rel.as_ref(), |d, r| d.read(r))
+    }
+
+    /// [fs::read_to_string] of `rel`
+    pub fn read_to_string(&self, rel: impl AsRef<Path>) -> Result<String, std::io::Error> {
+        self.resolve(rel.as_ref(), |d, r| d.read_to_string(r))
+    }
+
+    /// [fs::write] to `rel`
+    pub fn write(
+        &self,
+        rel: impl AsRef<Path>,
+        contents: impl AsRef<[u8]>,
+    ) -> Result<(), std::io::Error> {
+        self.resolve(rel.as_ref(), |d, r| d.write(r, contents))
+    }
+
+    /// Open `rel` for reading
+    pub fn open(&self, rel: impl AsRef<Path>) -> Result<File, std::io::Error> {
+        self.resolve(rel.as_ref(), |d, r| d.open(r).map(|f| f.into_std()))
+    }
+
+    /// Open `rel` with `options`
+    pub fn open_with(
+        &self,
+        rel: impl AsRef<Path>,
+        options: &VirtualOpenOptions,
+    ) -> Result<File, std::io::Error> {
+        self.resolve(rel.as_ref(), |d, r| {
+            d.open_with(r, options).map(|f| f.into_std())
+        })
+    }
+
+    /// [fs::create_dir] of `rel`
+    pub fn create_dir(&self, rel: impl AsRef<Path>) -> Result<(), std::io::Error> {
+        self.resolve(rel.as_ref(), |d, r| d.create_dir(r))
+    }
+
+    /// [fs::create_dir_all] of `rel`
+    pub fn create_dir_all(&self, rel: impl AsRef<Path>) -> Result<(), std::io::Error> {
+        self.resolve(rel.as_ref(), |d, r| d.create_dir_all(r))
+    }
+
+    /// [fs::remove_file] of `rel`
+    pub fn remove_file(&self, rel: impl AsRef<Path>) -> Result<(), std::io::Error> {
+        self.resolve(rel.as_ref(), |d, r| d.remove_file(r))
+    }
+
+    /// [fs::remove_dir_all] of `rel`
+    pub fn remove_dir_all(&self, rel: impl AsRef<Path>) -> Result<(), std::io::Error> {
+        self.resolve(rel.as_ref(), |d, r| d.remove_dir_all(r))
+    }
+
+    /// Sorted names of the entries in the directory `rel`, use `""` for
+    /// this directory
+    pub fn list(&self, rel: impl AsRef<Path>) -> Result<Vec<String>, std::io::Error> {
+        let mut names = self
+            .resolve(rel.as_ref(), |d, r| d.read_dir(r))?
+            .map(|e| e.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>, _>>()?;
+        names.sort();
+        Ok(names)
+    }
+
+    /// Actually change to this directory, for code that needs the current
+    /// directory set, see [WithDir::new](crate::WithDir::new). The handle
+    /// is entered where the platform allows it, see
+    /// [from_fd](crate::WithDir::from_fd).
+    pub fn enter<'a>(&self) -> Result<WithDir<'a>, std::io::Error> {
+        #[cfg(unix)]
+        return WithDir::from_fd(&self.dir);
+        #[cfg(windows)]
+        return WithDir::from_handle(&self.dir);
+        #[cfg(not(any(unix, windows)))]
+        WithDir::new(&self.path)
+    }
+
+    /// Delete a [temp](VirtualDir::temp) directory now, reporting any
+    /// error instead of ignoring it as dropping does. Does nothing for
+    /// other directories.
+    pub fn close(self) -> Result<(), std::io::Error> {
+        let VirtualDir { dir, temp_dir, .. } = self;
+        drop(dir);
+        match temp_dir {
+            Some(t) => t.close(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl AsRef<Path> for VirtualDir {
+    fn as_ref(&self) -> &Path {
+        self.path()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn test_virtual_dir() {
+        let dir = VirtualDir::temp().unwrap();
+        dir.write("a", "x").unwrap();
+        assert!(dir.exists("a"));
+        assert_eq!(dir.read("a").unwrap(), b"x");
+        dir.open_with("a", VirtualOpenOptions::new().append(true))
+            .unwrap()
+            .write_all(b"y")
+            .unwrap();
+        assert_eq!(dir.read_to_string("a").unwrap(), "xy");
+        dir.create_dir("d").unwrap();
+        assert_eq!(dir.list("").unwrap(), ["a", "d"]);
+        dir.remove_file("a").unwrap();
+        dir.remove_dir_all("d").unwrap();
+        assert!(dir.list("").unwrap().is_empty());
+
+        let sub = VirtualDir::create_all(dir.join("x/y")).unwrap();
+        assert_eq!(sub.path(), dir.join("x/y"));
+        assert!(VirtualDir::create(dir.join("x/y")).is_err());
+        assert!(VirtualDir::new(dir.join("missing")).is_err());
+        assert!(sub.enter().unwrap().is_current());
+
+        let path = dir.path().to_owned();
+        dir.close().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_virtual_dir_renamed() {
+        let parent = tempfile::tempdir().unwrap();
+        let before = parent.path().join("before");
+        let after = parent.path().join("after");
+        let dir = VirtualDir::create(&before).unwrap();
+        fs::rename(&before, &after).unwrap();
+        // a new directory at the old path isn't written to
+        fs::create_dir(&before).unwrap();
+
+        dir.create_dir_all("d").unwrap();
+        dir.write("d/a", "x").unwrap();
+        assert_eq!(dir.read_to_string("d/a").unwrap(), "x");
+        assert_eq!(dir.list("").unwrap(), ["d"]);
+        assert_eq!(fs::read_to_string(after.join("d/a")).unwrap(), "x");
+        assert!(fs::read_dir(&before).unwrap().next().is_none());
+        dir.remove_file("d/a").unwrap();
+        assert!(!after.join("d/a").exists());
+        assert!(dir.read("../after/d").is_err());
+    }
+}
@@ -0,0 +1,262 @@
+//! Recursive iteration over the contents of a [WithDir](crate::WithDir). See
+//! [WithDir::walk](crate::WithDir::walk).
+use std::{
+    fs::{canonicalize, metadata, read_dir, symlink_metadata, FileType},
+    path::{Path, PathBuf},
+    vec::IntoIter,
+};
+
+use crate::WithDir;
+
+/// An entry yielded by [Walk].
+#[derive(Clone, Debug)]
+pub struct WalkEntry {
+    path: PathBuf,
+    depth: usize,
+    file_type: FileType,
+}
+
+impl WalkEntry {
+    /// Path of the entry relative to the walked directory
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of components in [path](crate::walk::WalkEntry::path),
+    /// entries directly inside the walked directory have depth 1.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Type of the entry. When following symlinks this is the type of
+    /// the target.
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    /// Take the relative path
+    pub fn into_path(self) -> PathBuf {
+        self.path
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Only {
+    Files,
+    Dirs,
+}
+
+/// Depth first iterator over everything beneath a directory, with the
+/// entries of each directory visited in name order. Created with
+/// [WithDir::walk](crate::WithDir::walk).
+pub struct Walk {
+    root: PathBuf,
+    stack: Vec<(usize, IntoIter<PathBuf>)>,
+    started: bool,
+    min_depth: usize,
+    max_depth: usize,
+    follow_links: bool,
+    only: Option<Only>,
+    // canonical paths of the directories on `stack`, when following links
+    ancestors: Vec<PathBuf>,
+}
+
+fn read_sorted(root: &Path, rel: &Path) -> Result<IntoIter<PathBuf>, std::io::Error> {
+    let mut children = read_dir(root.join(rel))?
+        .map(|e| e.map(|e| rel.join(e.file_name())))
+        .collect::<Result<Vec<_>, _>>()?;
+    children.sort();
+    Ok(children.into_iter())
+}
+
+impl Walk {
+    pub(crate) fn new(root: PathBuf) -> Walk {
+        Walk {
+            root,
+            stack: Vec::new(),
+            started: false,
+            min_depth: 1,
+            max_depth: usize::MAX,
+            follow_links: false,
+            only: None,
+            ancestors: Vec::new(),
+        }
+    }
+
+    /// Skip entries shallower than `depth`
+    pub fn min_depth(mut self, depth: usize) -> Self {
+        self.min_depth = depth;
+        self
+    }
+
+    /// Don't descend below `depth`, a `depth` of 1 lists the directory
+    /// without recursing.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Descend into symlinked directories. Links that point back to one of
+    /// their own parents are yielded but not descended into.
+    pub fn follow_links(mut self, follow: bool) -> Self {
+        self.follow_links = follow;
+        self
+    }
+
+    /// Only yield files
+    pub fn files_only(mut self) -> Self {
+        self.only = Some(Only::Files);
+        self
+    }
+
+    /// Only yield directories
+    pub fn dirs_only(mut self) -> Self {
+        self.only = Some(Only::Dirs);
+        self
+    }
+
+    fn descend(&mut self, rel: &Path, depth: usize) -> Result<(), std::io::Error> {
+        if self.follow_links {
+            let canonical = canonicalize(self.root.join(rel))?;
+            if self.ancestors.contains(&canonical) {
+                return Ok(());
+            }
+            self.ancestors.push(canonical);
+        }
+        self.stack.push((depth + 1, read_sorted(&self.root, rel)?));
+        Ok(())
+    }
+}
+
+impl Iterator for Walk {
+    type Item = Result<WalkEntry, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            if let Err(e) = self.descend(Path::new(""), 0) {
+                return Some(Err(e));
+            }
+        }
+        loop {
+            let (depth, next) = match self.stack.last_mut() {
+                Some((depth, children)) => (*depth, children.next()),
+                None => return None,
+            };
+            let Some(rel) = next else {
+                self.stack.pop();
+                self.ancestors.pop();
+                continue;
+            };
+            let full = self.root.join(&rel);
+            let meta = match self.follow_links {
+                true => metadata(&full),
+                false => symlink_metadata(&full),
+            };
+            let file_type = match meta {
+                Ok(m) => m.file_type(),
+                Err(e) => return Some(Err(e)),
+            };
+            if file_type.is_dir() && depth < self.max_depth {
+                if let Err(e) = self.descend(&rel, depth) {
+                    return Some(Err(e));
+                }
+            }
+            let wanted = match self.only {
+                Some(Only::Files) => file_type.is_file(),
+                Some(Only::Dirs) => file_type.is_dir(),
+                None => true,
+            };
+            if wanted && depth >= self.min_depth {
+                return Some(Ok(WalkEntry {
+                    path: rel,
+                    depth,
+                    file_type,
+                }));
+            }
+        }
+    }
+}
+
+impl WithDir<'_> {
+    /// Iterate over everything beneath this directory, yielding paths
+    /// relative to it. Symlinks are not followed unless
+    /// [follow_links](crate::walk::Walk::follow_links) is set.
+    ///
+    /// ```
+    /// use with_dir::WithDir;
+    ///
+    /// let wd = WithDir::temp().unwrap();
+    /// std::fs::create_dir_all("a/b").unwrap();
+    /// std::fs::write("a/b/c.txt", "").unwrap();
+    ///
+    /// let files: Vec<_> = wd.walk().files_only().map(|e| e.unwrap().into_path()).collect();
+    /// assert_eq!(files, [std::path::Path::new("a/b/c.txt")]);
+    /// ```
+    pub fn walk(&self) -> Walk {
+        Walk::new(self.absolute_path().to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+
+    use super::*;
+
+    fn paths(walk: Walk) -> Vec<PathBuf> {
+        walk.map(|e| e.unwrap().into_path()).collect()
+    }
+
+    #[test]
+    fn test_walk() {
+        let wd = WithDir::temp().unwrap();
+        create_dir_all("b/c").unwrap();
+        write("a", "").unwrap();
+        write("b/c/d", "").unwrap();
+
+        assert_eq!(
+            paths(wd.walk()),
+            [
+                Path::new("a"),
+                Path::new("b"),
+                Path::new("b/c"),
+                Path::new("b/c/d")
+            ]
+        );
+        assert_eq!(
+            paths(wd.walk().max_depth(1)),
+            [Path::new("a"), Path::new("b")]
+        );
+        assert_eq!(
+            paths(wd.walk().min_depth(2)),
+            [Path::new("b/c"), Path::new("b/c/d")]
+        );
+        assert_eq!(
+            paths(wd.walk().files_only()),
+            [Path::new("a"), Path::new("b/c/d")]
+        );
+        assert_eq!(
+            paths(wd.walk().dirs_only()),
+            [Path::new("b"), Path::new("b/c")]
+        );
+        assert_eq!(wd.walk().last().unwrap().unwrap().depth(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_walk_symlinks() {
+        let wd = WithDir::temp().unwrap();
+        create_dir_all("real").unwrap();
+        write("real/f", "").unwrap();
+        std::os::unix::fs::symlink("real", "link").unwrap();
+        // points back at the directory containing it
+        std::os::unix::fs::symlink(".", "real/up").unwrap();
+
+        assert_eq!(paths(wd.walk().files_only()), [Path::new("real/f")]);
+        assert_eq!(
+            paths(wd.walk().files_only().follow_links(true)),
+            [Path::new("link/f"), Path::new("real/f")]
+        );
+    }
+}
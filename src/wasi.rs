@@ -0,0 +1,149 @@
+//! The default [CwdBackend] on WASI, which emulates the current directory,
+//! see [WasiBackend].
+use std::{
+    io::ErrorKind,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+use tempfile::TempDir;
+
+use crate::{backend::Metadata, CwdBackend};
+
+/// The [CwdBackend] used by default when compiled for WASI
+/// (`wasm32-wasip1` and `wasm32-wasip2`), where there may be no real
+/// current directory, only directories preopened by the host. It keeps the
+/// current directory itself, resolving relative paths against it, and opens
+/// everything by absolute path, which WASI maps onto the preopens. The real
+/// current directory is changed as well where the platform supports it, so
+/// relative paths in code inside a `WithDir` work on runtimes that emulate
+/// one.
+///
+/// The starting directory is the real current directory if it can be read,
+/// otherwise `$PWD`, otherwise `/`. Only available on WASI.
+#[derive(Debug)]
+pub struct WasiBackend {
+    cwd: Mutex<PathBuf>,
+}
+
+impl Default for WasiBackend {
+    fn default() -> Self {
+        WasiBackend::new()
+    }
+}
+
+impl WasiBackend {
+    /// A backend starting from the current directory, as described above
+    pub fn new() -> WasiBackend {
+        let cwd = std::env::current_dir()
+            .ok()
+            .or_else(|| std::env::var_os("PWD").map(PathBuf::from))
+            .filter(|p| p.is_absolute())
+            .unwrap_or_else(|| PathBuf::from("/"));
+        WasiBackend {
+            cwd: Mutex::new(cwd),
+        }
+    }
+
+    /// The backend every guard uses unless another is installed
+    #[cfg(target_os = "wasi")]
+    pub(crate) fn shared() -> Arc<WasiBackend> {
+        use std::sync::OnceLock;
+        static SHARED: OnceLock<Arc<WasiBackend>> = OnceLock::new();
+        SHARED.get_or_init(|| Arc::new(WasiBackend::new())).clone()
+    }
+
+    /// `path` made absolute against the emulated current directory, with
+    /// `.` and `..` removed
+    fn resolve(&self, path: &Path) -> PathBuf {
+        let mut out = self.cwd.lock().clone();
+        for c in path.components() {
+            match c {
+                Component::Prefix(_) | Component::RootDir => out = PathBuf::from("/"),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    out.pop();
+                }
+                Component::Normal(n) => out.push(n),
+            }
+        }
+        out
+    }
+}
+
+impl CwdBackend for WasiBackend {
+    fn current_dir(&self) -> Result<PathBuf, std::io::Error> {
+        Ok(self.cwd.lock().clone())
+    }
+
+    fn set_current_dir(&self, path: &Path) -> Result<(), std::io::Error> {
+        let path = self.resolve(path);
+        if !std::fs::metadata(&path)?.is_dir() {
+            return Err(std::io::Error::new(
+                ErrorKind::NotADirectory,
+                format!("{} is not a directory", path.display()),
+            ));
+        }
+        match std::env::set_current_dir(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::Unsupported => {}
+            Err(e) => return Err(e),
+        }
+        *self.cwd.lock() = path;
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &Path, recursive: bool) -> Result<(), std::io::Error> {
+        std::fs::DirBuilder::new()
+            .recursive(recursive)
+            .create(self.resolve(path))
+    }
+
+    fn temp_dir(&self, prefix: &str) -> Result<TempDir, std::io::Error> {
+        let root = std::env::var_os("TMPDIR").unwrap_or_else(|| "/tmp".into());
+        tempfile::Builder::new()
+            .prefix(prefix)
+            .tempdir_in(self.resolve(Path::new(&root)))
+    }
+
+    fn metadata(&self, path: &Path) -> Result<Metadata, std::io::Error> {
+        std::fs::metadata(self.resolve(path)).map(Metadata::from)
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, std::io::Error> {
+        std::fs::canonicalize(self.resolve(path))
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<(), std::io::Error> {
+        std::fs::remove_dir_all(self.resolve(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WithDir;
+
+    #[test]
+    fn test_wasi_backend() {
+        let tmp = WithDir::temp().unwrap();
+        std::fs::create_dir("a").unwrap();
+        let wasi = Arc::new(WasiBackend::new());
+        assert_eq!(wasi.current_dir().unwrap(), tmp.path());
+
+        let wd = WithDir::options()
+            .backend(wasi.clone())
+            .create(true)
+            .enter("a/../b")
+            .unwrap();
+        assert_eq!(wasi.current_dir().unwrap(), tmp.path().join("b"));
+        assert!(wd.is_current());
+        drop(wd);
+        assert_eq!(wasi.current_dir().unwrap(), tmp.path());
+
+        std::fs::write("f", "").unwrap();
+        let err = wasi.set_current_dir(Path::new("f")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+    }
+}
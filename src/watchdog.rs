@@ -0,0 +1,129 @@
+//! Background check that the current directory matches the innermost live
+//! [WithDir](crate::WithDir). See [Watchdog].
+use std::{
+    env::current_dir,
+    fs::canonicalize,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+
+use crate::registry;
+
+/// Current directory if it differs from `expected`
+fn mismatch(expected: &Path) -> Option<PathBuf> {
+    let actual = current_dir().ok()?;
+    let expected = canonicalize(expected).ok()?;
+    match canonicalize(&actual) {
+        Ok(a) if a == expected => None,
+        _ => Some(actual),
+    }
+}
+
+/// Samples [current_dir](std::env::current_dir) on a background thread
+/// while any `WithDir` is alive, and prints a timestamped line to stderr
+/// whenever it doesn't match the innermost one. A mismatch is only
+/// reported once it has been seen on two samples in a row with no
+/// `WithDir` created or dropped in between, so the moment between a
+/// directory change and the guard being recorded isn't reported.
+///
+/// Sampling stops when the `Watchdog` is dropped. Requires the `watchdog`
+/// feature.
+///
+/// ```no_run
+/// let _watchdog = with_dir::Watchdog::start(std::time::Duration::from_millis(10));
+/// ```
+pub struct Watchdog {
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+    reports: Arc<Mutex<Vec<String>>>,
+}
+
+impl Watchdog {
+    /// Start sampling every `interval`
+    pub fn start(interval: Duration) -> Watchdog {
+        let (stop, rx) = channel();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let thread_reports = reports.clone();
+        let thread = thread::spawn(move || {
+            let mut pending = None;
+            let mut reported = None;
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                let (Some(expected), version) = registry::top() else {
+                    pending = None;
+                    continue;
+                };
+                let Some(actual) = mismatch(&expected) else {
+                    pending = None;
+                    continue;
+                };
+                let seen = Some((version, actual.clone()));
+                if pending != seen {
+                    pending = seen;
+                    continue;
+                }
+                if reported == pending {
+                    continue;
+                }
+                reported = pending.clone();
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let msg = format!(
+                    "[{}.{:03}] current directory is {} but the innermost WithDir is in {}",
+                    now.as_secs(),
+                    now.subsec_millis(),
+                    actual.display(),
+                    expected.display()
+                );
+                eprintln!("with_dir watchdog: {}", msg);
+                thread_reports.lock().push(msg);
+            }
+        });
+        Watchdog {
+            stop,
+            thread: Some(thread),
+            reports,
+        }
+    }
+
+    /// Everything reported so far
+    pub fn reports(&self) -> Vec<String> {
+        self.reports.lock().clone()
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env::set_current_dir, fs::create_dir};
+
+    use super::*;
+    use crate::{Clobber, WithDir};
+
+    #[test]
+    fn test_watchdog() {
+        let watchdog = Watchdog::start(Duration::from_millis(5));
+        let mut wd = WithDir::temp().unwrap();
+        wd.on_clobber(Clobber::Ignore);
+        create_dir("inner").unwrap();
+        set_current_dir("inner").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let inner = wd.path().join("inner").display().to_string();
+        assert!(watchdog.reports().iter().any(|r| r.contains(&inner)));
+    }
+}
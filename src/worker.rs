@@ -0,0 +1,189 @@
+//! A thread that makes every directory change for the threads using it,
+//! see [CwdWorker].
+use std::{
+    panic::{catch_unwind, resume_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use crate::WithDir;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Time spent by the jobs a [CwdWorker] has run, see
+/// [stats](CwdWorker::stats)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WorkerStats {
+    /// Jobs finished, including ones that failed or panicked
+    pub jobs: u64,
+    /// Total time jobs spent queued before the worker got to them
+    pub queued: Duration,
+    /// Total time jobs spent in their directory
+    pub held: Duration,
+    /// Longest time a single job spent in its directory
+    pub max_held: Duration,
+}
+
+/// A dedicated thread that owns the process current directory. Other
+/// threads [submit](CwdWorker::submit) closures to run in a directory and
+/// the worker runs them one at a time, each inside a `WithDir`. Instead of
+/// threads contending for the directory lock there is an explicit queue,
+/// and [stats](CwdWorker::stats) shows how long jobs wait and hold the
+/// directory.
+///
+/// The worker stops once the `CwdWorker` is dropped and every queued job
+/// has run.
+///
+/// ```
+/// use with_dir::CwdWorker;
+///
+/// let worker = CwdWorker::spawn();
+/// let manifest = worker
+///     .run(".", |_| std::fs::read_to_string("Cargo.toml"))
+///     .unwrap()
+///     .unwrap();
+/// assert!(manifest.contains("[package]"));
+/// assert_eq!(worker.stats().jobs, 1);
+/// ```
+pub struct CwdWorker {
+    jobs: Option<Sender<Job>>,
+    thread: Option<JoinHandle<()>>,
+    stats: Arc<Mutex<WorkerStats>>,
+}
+
+/// A job given to [CwdWorker::submit], to [wait](Pending::wait) for
+pub struct Pending<R> {
+    result: Receiver<thread::Result<Result<R, std::io::Error>>>,
+}
+
+impl<R> Pending<R> {
+    /// Block until the job has run and return what it returned, or the
+    /// error entering its directory. A panic in the job is resumed here.
+    pub fn wait(self) -> Result<R, std::io::Error> {
+        match self.result.recv() {
+            Ok(Ok(r)) => r,
+            Ok(Err(panic)) => resume_unwind(panic),
+            Err(_) => Err(std::io::Error::other("the cwd worker stopped")),
+        }
+    }
+}
+
+impl CwdWorker {
+    /// Start the worker thread
+    pub fn spawn() -> CwdWorker {
+        let (jobs, rx) = channel::<Job>();
+        let thread = thread::Builder::new()
+            .name("with_dir-cwd-worker".to_owned())
+            .spawn(move || {
+                for job in rx {
+                    job();
+                }
+            })
+            .expect("failed to spawn the cwd worker thread");
+        CwdWorker {
+            jobs: Some(jobs),
+            thread: Some(thread),
+            stats: Arc::default(),
+        }
+    }
+
+    /// Queue `f` to run on the worker inside a `WithDir` for `path`, which
+    /// if relative is resolved against the directory current when the job
+    /// runs. Returns straight away.
+    pub fn submit<R, F>(&self, path: impl AsRef<Path>, f: F) -> Pending<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&WithDir) -> R + Send + 'static,
+    {
+        let path: PathBuf = path.as_ref().to_owned();
+        let stats = self.stats.clone();
+        let submitted = Instant::now();
+        let (tx, result) = channel();
+        let job: Job = Box::new(move || {
+            let started = Instant::now();
+            let ret = catch_unwind(AssertUnwindSafe(|| {
+                let wd = WithDir::new(&path)?;
+                let r = f(&wd);
+                wd.leave()?;
+                Ok(r)
+            }));
+            let held = started.elapsed();
+            let mut stats = stats.lock();
+            stats.jobs += 1;
+            stats.queued += started - submitted;
+            stats.held += held;
+            stats.max_held = stats.max_held.max(held);
+            drop(stats);
+            // the caller may have stopped waiting
+            let _ = tx.send(ret);
+        });
+        if let Some(jobs) = &self.jobs {
+            // only fails if the worker panicked, which `wait` reports
+            let _ = jobs.send(job);
+        }
+        Pending { result }
+    }
+
+    /// Same as [submit](CwdWorker::submit), waiting for the result
+    pub fn run<R, F>(&self, path: impl AsRef<Path>, f: F) -> Result<R, std::io::Error>
+    where
+        R: Send + 'static,
+        F: FnOnce(&WithDir) -> R + Send + 'static,
+    {
+        self.submit(path, f).wait()
+    }
+
+    /// Time spent by the jobs that have finished so far
+    pub fn stats(&self) -> WorkerStats {
+        *self.stats.lock()
+    }
+}
+
+impl Drop for CwdWorker {
+    fn drop(&mut self) {
+        self.jobs = None;
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker() {
+        let tmp = tempfile::tempdir().unwrap();
+        let worker = CwdWorker::spawn();
+        let pending: Vec<_> = (0..4)
+            .map(|i| {
+                worker.submit(tmp.path(), move |wd| {
+                    std::fs::write(i.to_string(), "").unwrap();
+                    wd.path().join(i.to_string())
+                })
+            })
+            .collect();
+        for p in pending {
+            assert!(p.wait().unwrap().exists());
+        }
+        assert!(worker.run(tmp.path().join("missing"), |_| ()).is_err());
+        let panicked = catch_unwind(AssertUnwindSafe(|| {
+            worker.run(tmp.path(), |_| panic!("job failed"))
+        }));
+        assert!(panicked.is_err());
+        // the worker survives a panicking job
+        worker.run(tmp.path(), |_| ()).unwrap();
+
+        let stats = worker.stats();
+        assert_eq!(stats.jobs, 7);
+        assert!(stats.max_held <= stats.held);
+    }
+}
@@ -0,0 +1,112 @@
+//! Attribute macros for [with_dir](https://docs.rs/with_dir). Use them
+//! through `with_dir` with the `macros` feature rather than depending on
+//! this crate directly.
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input, Ident, ItemFn, LitStr, Token,
+};
+
+/// Run a test in a fresh temporary working directory. The directory is
+/// removed when the test passes and kept, with its path printed, when it
+/// panics. Equivalent to `#[test]` plus, at the top of the body:
+///
+/// ```ignore
+/// let mut wd = with_dir::WithDir::temp().unwrap();
+/// wd.keep_on_panic();
+/// ```
+#[proc_macro_attribute]
+pub fn test(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return syn::Error::new(
+            proc_macro2::TokenStream::from(attr)
+                .into_iter()
+                .next()
+                .unwrap()
+                .span(),
+            "#[with_dir::test] takes no arguments",
+        )
+        .to_compile_error()
+        .into();
+    }
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = parse_macro_input!(item as ItemFn);
+    quote! {
+        #[::core::prelude::v1::test]
+        #(#attrs)*
+        #vis #sig {
+            let mut __with_dir_guard = ::with_dir::WithDir::temp()
+                .expect("failed to create temporary working directory");
+            __with_dir_guard.keep_on_panic();
+            #block
+        }
+    }
+    .into()
+}
+
+struct WithDirArgs {
+    path: LitStr,
+    create: bool,
+}
+
+impl Parse for WithDirArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let path = input.parse()?;
+        let mut create = false;
+        if input.parse::<Option<Token![,]>>()?.is_some() && !input.is_empty() {
+            let flag: Ident = input.parse()?;
+            if flag != "create" {
+                return Err(syn::Error::new(flag.span(), "expected `create`"));
+            }
+            create = true;
+        }
+        if !input.is_empty() {
+            return Err(input.error("unexpected tokens"));
+        }
+        Ok(WithDirArgs { path, create })
+    }
+}
+
+/// Run the body of a function with the current working directory set to the
+/// given path, holding `with_dir`'s lock for the duration of the call. With
+/// `create` the directory and its parents are made if they are missing.
+///
+/// ```ignore
+/// #[with_dir::with_dir("target/generated", create)]
+/// fn generate() {
+///     std::fs::write("out.rs", "").unwrap();
+/// }
+/// ```
+///
+/// # Panics
+///
+/// The function panics if the directory can't be entered or created.
+#[proc_macro_attribute]
+pub fn with_dir(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let WithDirArgs { path, create } = parse_macro_input!(attr as WithDirArgs);
+    let ItemFn {
+        attrs,
+        vis,
+        sig,
+        block,
+    } = parse_macro_input!(item as ItemFn);
+    let constructor = if create {
+        quote!(create_all)
+    } else {
+        quote!(new)
+    };
+    quote! {
+        #(#attrs)*
+        #vis #sig {
+            let __with_dir_guard = ::with_dir::WithDir::#constructor(#path)
+                .unwrap_or_else(|e| panic!("failed to enter {}: {}", #path, e));
+            #block
+        }
+    }
+    .into()
+}